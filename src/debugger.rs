@@ -0,0 +1,695 @@
+use crate::{Gameboy, CpuRegister, Watchpoint, WatchAccess, WatchHit};
+use std::io::{self, BufRead, Write};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+//instructions executed between checks of the interrupt flag, about a frame
+const INTERRUPT_POLL: u32 = 20000;
+
+const HELP: &str = "\
+Numbers are decimal, or hexadecimal with a $ or 0x prefix. Addresses can also be symbols from the .sym file
+  break <addr> [if <reg> <op> <value>]   stop at addr (b), optionally only when the condition holds
+  break if <reg> <op> <value>            stop at any address where the condition holds
+                                         reg is a, f, b, c, d, e, h, l, af, bc, de, hl, sp or pc
+                                         op is ==, !=, <, <=, > or >=
+  delete [n]                             remove breakpoint n, or all of them (d)
+  watch [read|write|access] <addr>[-<end>] [== <value>]
+                                         stop when addr (or the range) is written, read or both (w),
+                                         optionally only when the byte is value. Writes by default
+  unwatch [n]                            remove watchpoint n, or all of them (uw)
+  breakpoints                            list the breakpoints and watchpoints (bp)
+  step [n]                               execute n instructions, 1 by default (s)
+  next                                   step over calls and rsts (n)
+  finish                                 run until the current function returns (f)
+  continue                               run until a breakpoint or watchpoint is hit or ctrl-c is pressed (c)
+  regs                                   show registers, flags and interrupts (r)
+  x <addr> [len]                         hexdump len bytes, 64 by default
+  dis [addr] [count]                     disassemble count instructions, 10 around PC by default
+  help                                   show this (h)
+  quit                                   exit (q)
+An empty line repeats the last command";
+
+#[derive(Copy, Clone, PartialEq)]
+enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+}
+
+struct Condition {
+    register: CpuRegister,
+    comparison: Comparison,
+    value: u16,
+}
+
+impl Condition {
+    fn holds(&self, gameboy: &Gameboy) -> bool {
+        let register = gameboy.register(self.register);
+
+        match self.comparison {
+            Comparison::Equal => register == self.value,
+            Comparison::NotEqual => register != self.value,
+            Comparison::Less => register < self.value,
+            Comparison::LessEqual => register <= self.value,
+            Comparison::Greater => register > self.value,
+            Comparison::GreaterEqual => register >= self.value,
+        }
+    }
+}
+
+struct Breakpoint {
+    number: usize,
+    addr: Option<u16>,
+    bank: Option<u16>,                      //ROM bank that has to be mapped, for breakpoints on symbols in 0x4000-0x7FFF
+    condition: Option<Condition>,
+    //breakpoints without an address only stop when their condition becomes true, not on every instruction after
+    held: bool,
+    text: String,
+}
+
+impl Breakpoint {
+    fn hit(&mut self, gameboy: &Gameboy) -> bool {
+        if let Some(addr) = self.addr {
+            if gameboy.pc() != addr { return false; }
+        }
+        if let Some(bank) = self.bank {
+            if gameboy.rom_bank() != bank { return false; }
+        }
+
+        match &self.condition {
+            Some(condition) if self.addr.is_none() => {
+                let held = self.held;
+                self.held = condition.holds(gameboy);
+                self.held && !held
+            },
+            Some(condition) => condition.holds(gameboy),
+            None => true
+        }
+    }
+}
+
+//why execution stopped
+enum Stop {
+    Done,
+    Breakpoint(usize),
+    Watchpoint(Vec<WatchHit>),
+    Interrupted,
+}
+
+//Interactive debugger reading commands from stdin, see HELP for the commands
+#[derive(Default)]
+pub struct Debugger {
+    breakpoints: Vec<Breakpoint>,
+    next_number: usize,
+    last_command: String,
+    interrupt: Arc<AtomicBool>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Debugger::default()
+    }
+
+    //setting the flag (from a ctrl-c handler for example) stops continue, next and finish
+    pub fn interrupt_flag(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    //read and execute commands until quit or end of input
+    pub fn run(&mut self, gameboy: &mut Gameboy) {
+        println!("Rusty GB debugger, type help for the commands");
+        println!("{}", self.current_instruction(gameboy));
+
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+
+        loop {
+            print!("(gb) ");
+            let _ = io::stdout().flush();
+
+            let line = match lines.next() {
+                Some(Ok(line)) => line,
+                _ => break
+            };
+
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => line.to_string()
+            };
+
+            if line == "q" || line == "quit" { break; }
+
+            let output = self.execute(gameboy, &line);
+            if !output.is_empty() {
+                println!("{}", output);
+            }
+
+            self.last_command = line;
+        }
+    }
+
+    //execute a single command line and return what it printed
+    pub fn execute(&mut self, gameboy: &mut Gameboy, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let args = words.get(1..).unwrap_or(&[]);
+
+        //a ctrl-c while waiting for the command doesn't stop it
+        self.interrupt.store(false, Ordering::Relaxed);
+
+        match self.dispatch(gameboy, words.first().copied().unwrap_or(""), args) {
+            Ok(output) => output,
+            Err(er) => er
+        }
+    }
+
+    fn dispatch(&mut self, gameboy: &mut Gameboy, command: &str, args: &[&str]) -> Result<String, String> {
+        match command {
+            "b" | "break" => self.add_breakpoint(gameboy, args),
+            "d" | "delete" => self.delete_breakpoint(args),
+            "w" | "watch" => add_watchpoint(gameboy, args),
+            "uw" | "unwatch" => delete_watchpoint(gameboy, args),
+            "bp" | "breakpoints" => Ok(self.list_breakpoints(gameboy)),
+            "s" | "step" => {
+                let count = match args.first() {
+                    Some(count) => parse_number(count)?,
+                    None => 1
+                };
+                let stop = self.step(gameboy, count);
+                Ok(self.report(gameboy, stop))
+            },
+            "n" | "next" => {
+                let stop = self.next(gameboy);
+                Ok(self.report(gameboy, stop))
+            },
+            "f" | "finish" => {
+                let stop = self.finish(gameboy);
+                Ok(self.report(gameboy, stop))
+            },
+            "c" | "continue" => {
+                let stop = self.continue_running(gameboy);
+                Ok(self.report(gameboy, stop))
+            },
+            "r" | "regs" => Ok(gameboy.cpu_status().trim_end().to_string()),
+            "x" => self.hexdump(gameboy, args),
+            "dis" => self.disassembly(gameboy, args),
+            "h" | "help" => Ok(HELP.to_string()),
+            "" => Ok(String::new()),
+            command => Err(format!("Unknown command \"{}\", type help for the commands", command))
+        }
+    }
+
+    fn add_breakpoint(&mut self, gameboy: &Gameboy, args: &[&str]) -> Result<String, String> {
+        let (location, rest) = match args.first() {
+            Some(&"if") => (None, args),
+            Some(addr) => (Some(parse_location(gameboy, addr)?), &args[1..]),
+            None => return Err("Usage: break <addr> [if <reg> <op> <value>]".to_string())
+        };
+        let addr = location.map(|(addr, _)| addr);
+        let bank = location.and_then(|(_, bank)| bank);
+
+        let condition = match rest {
+            [] => None,
+            ["if", register, comparison, value] => Some(Condition {
+                register: CpuRegister::from_name(register).ok_or(format!("Unknown register \"{}\"", register))?,
+                comparison: parse_comparison(comparison)?,
+                value: parse_sized(value)?,
+            }),
+            _ => return Err("Conditions look like \"if a == $10\"".to_string())
+        };
+
+        let mut text = match (args.first(), location) {
+            (Some(name), Some((addr, Some(bank)))) => format!("{} ({:02X}:{:04X})", name, bank, addr),
+            (Some(name), Some((addr, None))) if gameboy.symbols().address(name).is_some() => format!("{} (${:04X})", name, addr),
+            (_, Some((addr, _))) => format!("${:04X}", addr),
+            (_, None) => "any address".to_string()
+        };
+        if condition.is_some() {
+            text = format!("{} {}", text, rest.join(" "));
+        }
+
+        self.next_number += 1;
+        let reply = format!("Breakpoint {} at {}", self.next_number, text);
+
+        self.breakpoints.push(Breakpoint { number: self.next_number, addr, bank, condition, held: false, text });
+
+        Ok(reply)
+    }
+
+    fn delete_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        match args.first() {
+            Some(number) => {
+                let number = parse_number(number)? as usize;
+
+                match self.breakpoints.iter().position(|breakpoint| breakpoint.number == number) {
+                    Some(i) => {
+                        self.breakpoints.remove(i);
+                        Ok(format!("Deleted breakpoint {}", number))
+                    },
+                    None => Err(format!("No breakpoint {}", number))
+                }
+            },
+            None => {
+                self.breakpoints.clear();
+                Ok("Deleted all breakpoints".to_string())
+            }
+        }
+    }
+
+    fn list_breakpoints(&self, gameboy: &Gameboy) -> String {
+        let mut lines: Vec<String> = self.breakpoints.iter()
+            .map(|breakpoint| format!("{}: {}", breakpoint.number, breakpoint.text))
+            .collect();
+
+        if lines.is_empty() {
+            lines.push("No breakpoints".to_string());
+        }
+
+        let watchpoints: Vec<String> = gameboy.watchpoints()
+            .map(|(id, watchpoint)| format!("{}: {}", id, watchpoint))
+            .collect();
+
+        if !watchpoints.is_empty() {
+            lines.push("Watchpoints:".to_string());
+            lines.extend(watchpoints);
+        }
+
+        lines.join("\n")
+    }
+
+    //every breakpoint is checked so the conditional ones see each instruction
+    fn breakpoint_hit(&mut self, gameboy: &Gameboy) -> Option<usize> {
+        let mut hit = None;
+
+        for (i, breakpoint) in self.breakpoints.iter_mut().enumerate() {
+            if breakpoint.hit(gameboy) && hit.is_none() {
+                hit = Some(i);
+            }
+        }
+
+        hit
+    }
+
+    //execute count instructions, stopping early at breakpoints and watchpoints
+    fn step(&mut self, gameboy: &mut Gameboy, count: u32) -> Stop {
+        for i in 0..count {
+            if i > 0 {
+                if let Some(hit) = self.breakpoint_hit(gameboy) { return Stop::Breakpoint(hit); }
+            }
+
+            if let Some(stop) = execute_instruction(gameboy) { return stop; }
+        }
+
+        Stop::Done
+    }
+
+    //like step, but calls and rsts run until they return
+    fn next(&mut self, gameboy: &mut Gameboy) -> Stop {
+        let pc = gameboy.pc();
+        let opcode = gameboy.peek(pc);
+
+        let is_call = matches!(opcode, 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC) || opcode & 0xC7 == 0xC7;
+        if !is_call {
+            return self.step(gameboy, 1);
+        }
+
+        let (_, length) = gameboy.disassemble(pc);
+        let return_addr = pc.wrapping_add(length);
+        let sp = gameboy.register(CpuRegister::SP);
+
+        if let Some(stop) = execute_instruction(gameboy) { return stop; }
+
+        let mut count = 0;
+
+        loop {
+            if gameboy.pc() == return_addr && gameboy.register(CpuRegister::SP) >= sp { return Stop::Done; }
+            if let Some(hit) = self.breakpoint_hit(gameboy) { return Stop::Breakpoint(hit); }
+
+            if let Some(stop) = execute_instruction(gameboy) { return stop; }
+            if self.interrupted(&mut count) { return Stop::Interrupted; }
+        }
+    }
+
+    //run until a return pops the frame the current function was called with
+    fn finish(&mut self, gameboy: &mut Gameboy) -> Stop {
+        let sp = gameboy.register(CpuRegister::SP);
+        let mut first = true;
+        let mut count = 0;
+
+        loop {
+            if !first {
+                if let Some(hit) = self.breakpoint_hit(gameboy) { return Stop::Breakpoint(hit); }
+            }
+            first = false;
+
+            let opcode = gameboy.peek(gameboy.pc());
+            let is_return = matches!(opcode, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9);
+
+            if let Some(stop) = execute_instruction(gameboy) { return stop; }
+
+            if is_return && gameboy.register(CpuRegister::SP) > sp { return Stop::Done; }
+            if self.interrupted(&mut count) { return Stop::Interrupted; }
+        }
+    }
+
+    fn continue_running(&mut self, gameboy: &mut Gameboy) -> Stop {
+        if let Some(stop) = execute_instruction(gameboy) { return stop; }
+
+        let mut count = 0;
+
+        loop {
+            if let Some(hit) = self.breakpoint_hit(gameboy) { return Stop::Breakpoint(hit); }
+
+            if let Some(stop) = execute_instruction(gameboy) { return stop; }
+            if self.interrupted(&mut count) { return Stop::Interrupted; }
+        }
+    }
+
+    //the flag is only looked at every INTERRUPT_POLL instructions, count keeps track of them
+    fn interrupted(&self, count: &mut u32) -> bool {
+        *count += 1;
+        if *count < INTERRUPT_POLL { return false; }
+
+        *count = 0;
+        self.interrupt.swap(false, Ordering::Relaxed)
+    }
+
+    fn report(&self, gameboy: &mut Gameboy, stop: Stop) -> String {
+        let instruction = self.current_instruction(gameboy);
+
+        match stop {
+            Stop::Done => instruction,
+            Stop::Interrupted => format!("Interrupted\n{}", instruction),
+            Stop::Breakpoint(i) => format!("Breakpoint {} ({})\n{}", self.breakpoints[i].number, self.breakpoints[i].text, instruction),
+            Stop::Watchpoint(hits) => {
+                let hits: Vec<String> = hits.iter().map(|hit| hit.to_string()).collect();
+                format!("{}\n{}", hits.join("\n"), instruction)
+            }
+        }
+    }
+
+    fn current_instruction(&self, gameboy: &mut Gameboy) -> String {
+        let pc = gameboy.pc();
+        let (text, _) = gameboy.disassemble(pc);
+
+        match gameboy.symbol(pc) {
+            Some(symbol) => format!("${:04X} {}: {}", pc, symbol, text),
+            None => format!("${:04X}: {}", pc, text)
+        }
+    }
+
+    fn hexdump(&self, gameboy: &mut Gameboy, args: &[&str]) -> Result<String, String> {
+        let addr = match args.first() {
+            Some(addr) => parse_address(gameboy, addr)?,
+            None => return Err("Usage: x <addr> [len]".to_string())
+        };
+        let length = match args.get(1) {
+            Some(length) => parse_number(length)?,
+            None => 64
+        };
+
+        let mut lines = vec![];
+
+        for row in (0..length).step_by(16) {
+            let start = addr.wrapping_add(row as u16);
+            let count = (length - row).min(16);
+
+            let bytes: Vec<u8> = (0..count).map(|i| gameboy.peek(start.wrapping_add(i as u16))).collect();
+
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let ascii: String = bytes.iter().map(|&byte| if (0x20..0x7F).contains(&byte) { byte as char } else { '.' }).collect();
+
+            lines.push(format!("${:04X}: {:<47}  {}", start, hex.join(" "), ascii));
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    fn disassembly(&self, gameboy: &mut Gameboy, args: &[&str]) -> Result<String, String> {
+        let pc = gameboy.pc();
+
+        let start = match args.first() {
+            Some(addr) => parse_address(gameboy, addr)?,
+            None => instruction_before(gameboy, pc, 4)
+        };
+        let count = match args.get(1) {
+            Some(count) => parse_number(count)?,
+            None => 10
+        };
+
+        let mut lines = vec![];
+        let mut addr = start;
+
+        for _ in 0..count {
+            let (text, length) = gameboy.disassemble(addr);
+            let marker = if addr == pc { "=>" } else { "  " };
+
+            if let Some(name) = gameboy.symbols().name(gameboy.rom_bank(), addr) {
+                lines.push(format!("{}:", name));
+            }
+
+            lines.push(format!("{} ${:04X}: {}", marker, addr, text));
+            addr = addr.wrapping_add(length);
+        }
+
+        Ok(lines.join("\n"))
+    }
+}
+
+//step once, a watchpoint stop if the instruction triggered any
+fn execute_instruction(gameboy: &mut Gameboy) -> Option<Stop> {
+    gameboy.step_instruction();
+
+    let hits = gameboy.take_watch_hits();
+    if hits.is_empty() { None } else { Some(Stop::Watchpoint(hits)) }
+}
+
+fn add_watchpoint(gameboy: &mut Gameboy, args: &[&str]) -> Result<String, String> {
+    const USAGE: &str = "Usage: watch [read|write|access] <addr>[-<end>] [== <value>]";
+
+    let (access, rest) = match args.first() {
+        Some(&"read") => (WatchAccess::Read, &args[1..]),
+        Some(&"write") => (WatchAccess::Write, &args[1..]),
+        Some(&"access") => (WatchAccess::Any, &args[1..]),
+        _ => (WatchAccess::Write, args)
+    };
+
+    let (range, value) = match rest {
+        [range] => (range, None),
+        [range, "==", value] => (range, Some(parse_sized(value)?)),
+        _ => return Err(USAGE.to_string())
+    };
+
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_address(gameboy, start)?, parse_address(gameboy, end)?),
+        None => {
+            let addr = parse_address(gameboy, range)?;
+            (addr, addr)
+        }
+    };
+
+    if end < start {
+        return Err(format!("Range {} ends before it starts", range));
+    }
+
+    let mut watchpoint = Watchpoint::new(access, start ..= end);
+    if let Some(value) = value {
+        watchpoint = watchpoint.value(value);
+    }
+
+    let text = watchpoint.to_string();
+    let id = gameboy.add_watchpoint(watchpoint);
+
+    Ok(format!("Watchpoint {} on {}", id, text))
+}
+
+fn delete_watchpoint(gameboy: &mut Gameboy, args: &[&str]) -> Result<String, String> {
+    match args.first() {
+        Some(number) => {
+            let id = parse_number(number)? as usize;
+
+            if gameboy.remove_watchpoint(id) {
+                Ok(format!("Deleted watchpoint {}", id))
+            } else {
+                Err(format!("No watchpoint {}", id))
+            }
+        },
+        None => {
+            gameboy.clear_watchpoints();
+            Ok("Deleted all watchpoints".to_string())
+        }
+    }
+}
+
+//instructions have different lengths so going back is a guess: the furthest address up to `count`
+//instructions before addr that decodes into a sequence landing exactly on it
+fn instruction_before(gameboy: &mut Gameboy, addr: u16, count: u16) -> u16 {
+    for distance in (1..=count * 3).rev() {
+        if distance > addr { continue; }
+
+        let start = addr.wrapping_sub(distance);
+        let mut current = start;
+        let mut instructions = 0;
+
+        while current != addr && instructions < count && addr.wrapping_sub(current) <= distance {
+            current = current.wrapping_add(gameboy.disassemble(current).1);
+            instructions += 1;
+        }
+
+        if current == addr {
+            return start;
+        }
+    }
+
+    addr
+}
+
+//a number or a symbol, with the ROM bank the symbol is in when it's in the switchable area
+fn parse_location(gameboy: &Gameboy, text: &str) -> Result<(u16, Option<u16>), String> {
+    if let Some((bank, addr)) = gameboy.symbols().address(text) {
+        let bank = if (0x4000 ..= 0x7FFF).contains(&addr) { Some(bank) } else { None };
+        return Ok((addr, bank));
+    }
+
+    match parse_number(text) {
+        Ok(_) => Ok((parse_sized(text)?, None)),
+        Err(_) => Err(format!("\"{}\" is neither a number nor a symbol", text))
+    }
+}
+
+fn parse_address(gameboy: &Gameboy, text: &str) -> Result<u16, String> {
+    parse_location(gameboy, text).map(|(addr, _)| addr)
+}
+
+fn parse_number(text: &str) -> Result<u32, String> {
+    let parsed = if let Some(hex) = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")) {
+        u32::from_str_radix(hex, 16)
+    } else {
+        text.parse()
+    };
+
+    parsed.map_err(|_| format!("Invalid number \"{}\"", text))
+}
+
+//a number that has to fit in a register, an address or a byte instead of being truncated
+fn parse_sized<T: TryFrom<u32>>(text: &str) -> Result<T, String> {
+    T::try_from(parse_number(text)?).map_err(|_| format!("Number \"{}\" is too large", text))
+}
+
+fn parse_comparison(text: &str) -> Result<Comparison, String> {
+    match text {
+        "==" => Ok(Comparison::Equal),
+        "!=" => Ok(Comparison::NotEqual),
+        "<" => Ok(Comparison::Less),
+        "<=" => Ok(Comparison::LessEqual),
+        ">" => Ok(Comparison::Greater),
+        ">=" => Ok(Comparison::GreaterEqual),
+        _ => Err(format!("Unknown comparison \"{}\"", text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //Counts loops in A and calls a function adding 2 to B on each, then an RST to a RET.
+    //Runs from WRAM with the boot ROM unmapped
+    fn looping() -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x38] = 0xC9;
+        rom[0x14D] = rom[0x134..0x14D].iter().fold(0u8, |x, byte| x.wrapping_sub(*byte).wrapping_sub(1));
+        let mut gameboy = Gameboy::builder(rom).build().unwrap();
+        gameboy.poke(0xFF50, 1);
+
+        //C000: LD A,0; loop: INC A; CALL $C010; RST $38; JR loop
+        //C010: INC B; INC B; RET
+        let code: [(u16, &[u8]); 2] = [(0xC000, &[0x3E, 0x00, 0x3C, 0xCD, 0x10, 0xC0, 0xFF, 0x18, 0xF9]), (0xC010, &[0x04, 0x04, 0xC9])];
+        for (start, bytes) in code.iter() {
+            for (offset, byte) in bytes.iter().enumerate() {
+                gameboy.poke(start + offset as u16, *byte);
+            }
+        }
+        gameboy.set_register(CpuRegister::B, 0);
+        gameboy.set_register(CpuRegister::SP, 0xDFFE);
+        gameboy.set_register(CpuRegister::PC, 0xC000);
+        gameboy
+    }
+
+    #[test]
+    fn conditional_breakpoints() {
+        let mut gameboy = looping();
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.execute(&mut gameboy, "break $C002 if a == 3"), "Breakpoint 1 at $C002 if a == 3");
+        assert!(debugger.execute(&mut gameboy, "continue").starts_with("Breakpoint 1 ($C002 if a == 3)\n$C002"));
+        //three loops done
+        assert_eq!(gameboy.register(CpuRegister::A), 3);
+        assert_eq!(gameboy.register(CpuRegister::B), 6);
+    }
+
+    #[test]
+    fn breakpoints_without_an_address_stop_when_the_condition_becomes_true() {
+        let mut gameboy = looping();
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.execute(&mut gameboy, "b if b >= 4"), "Breakpoint 1 at any address if b >= 4");
+        assert!(debugger.execute(&mut gameboy, "c").starts_with("Breakpoint 1 (any address if b >= 4)\n$C012"));
+        assert_eq!(gameboy.register(CpuRegister::A), 2);
+
+        //it stays true until B wraps around, 128 loops later
+        debugger.execute(&mut gameboy, "c");
+        assert_eq!(gameboy.register(CpuRegister::B), 4);
+        assert_eq!(gameboy.register(CpuRegister::A), 130);
+    }
+
+    #[test]
+    fn next_steps_over_calls_and_rsts() {
+        let mut gameboy = looping();
+        let mut debugger = Debugger::new();
+
+        debugger.execute(&mut gameboy, "s 2");
+        assert_eq!(gameboy.pc(), 0xC003);
+
+        assert!(debugger.execute(&mut gameboy, "next").starts_with("$C006"));
+        assert_eq!(gameboy.register(CpuRegister::B), 2);
+        assert!(debugger.execute(&mut gameboy, "n").starts_with("$C007"));
+        assert_eq!(gameboy.register(CpuRegister::SP), 0xDFFE);
+
+        //a breakpoint inside the call still stops it
+        debugger.execute(&mut gameboy, "s 2");
+        debugger.execute(&mut gameboy, "b $C011");
+        assert!(debugger.execute(&mut gameboy, "n").starts_with("Breakpoint 1 ($C011)\n$C011"));
+    }
+
+    #[test]
+    fn finish_runs_until_the_function_returns() {
+        let mut gameboy = looping();
+        let mut debugger = Debugger::new();
+
+        debugger.execute(&mut gameboy, "s 3");
+        assert_eq!(gameboy.pc(), 0xC010);
+
+        assert!(debugger.execute(&mut gameboy, "finish").starts_with("$C006"));
+        assert_eq!(gameboy.register(CpuRegister::B), 2);
+        assert_eq!(gameboy.register(CpuRegister::SP), 0xDFFE);
+    }
+
+    #[test]
+    fn numbers_have_to_fit() {
+        assert_eq!(parse_sized::<u8>("$FF"), Ok(0xFF));
+        assert_eq!(parse_sized::<u8>("256"), Err("Number \"256\" is too large".to_string()));
+        assert_eq!(parse_sized::<u16>("0xFFFF"), Ok(0xFFFF));
+        assert!(parse_sized::<u16>("$10000").is_err());
+        assert!(parse_sized::<u16>("-1").is_err());
+
+        let mut gameboy = looping();
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.execute(&mut gameboy, "b $10000"), "Number \"$10000\" is too large");
+        assert_eq!(debugger.execute(&mut gameboy, "b if hl == 65536"), "Number \"65536\" is too large");
+        assert_eq!(debugger.execute(&mut gameboy, "watch $C000 == $100"), "Number \"$100\" is too large");
+        assert_eq!(debugger.execute(&mut gameboy, "bp"), "No breakpoints");
+    }
+}
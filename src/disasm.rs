@@ -0,0 +1,115 @@
+use crate::emulator::disassembler::{disassemble, jump_target, with_label};
+use crate::symbols::Symbols;
+use std::collections::HashSet;
+use std::fmt::{Formatter, Display};
+use std::ops::RangeInclusive;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+
+//reasons a ROM can't be disassembled as asked
+#[derive(Debug, PartialEq)]
+pub enum DisasmError {
+    BankOutOfRange { bank: usize, banks: usize },
+    RangeOutsideBank { bank: usize, start: u16, end: u16 },
+}
+
+impl Display for DisasmError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisasmError::BankOutOfRange { bank, banks } => write!(f, "ROM bank {} doesn't exist, the ROM has {} banks", bank, banks),
+            DisasmError::RangeOutsideBank { bank, start, end } => {
+                let window = bank_window(*bank);
+                write!(f, "${:04X}-${:04X} is outside of ROM bank {} (${:04X}-${:04X})", start, end, bank, window.start(), window.end())
+            }
+        }
+    }
+}
+
+impl std::error::Error for DisasmError {}
+
+//addresses a bank is seen at by the CPU, bank 0 is fixed and the others are switched into 0x4000
+pub fn bank_window(bank: usize) -> RangeInclusive<u16> {
+    if bank == 0 { 0x0000 ..= 0x3FFF } else { 0x4000 ..= 0x7FFF }
+}
+
+//number of 16KB banks in a ROM
+pub fn rom_banks(rom: &[u8]) -> usize {
+    rom.len().div_ceil(ROM_BANK_SIZE)
+}
+
+//disassemble ROM bank `bank`, all of it or only the addresses in range
+pub fn disassemble_rom(rom: &[u8], bank: usize, range: Option<RangeInclusive<u16>>, symbols: &Symbols) -> Result<String, DisasmError> {
+    let banks = rom_banks(rom);
+    if bank >= banks {
+        return Err(DisasmError::BankOutOfRange { bank, banks });
+    }
+
+    let window = bank_window(bank);
+    let range = range.unwrap_or_else(|| window.clone());
+
+    if !window.contains(range.start()) || !window.contains(range.end()) || range.start() > range.end() {
+        return Err(DisasmError::RangeOutsideBank { bank, start: *range.start(), end: *range.end() });
+    }
+
+    let read = |addr: u16| {
+        let offset = match addr {
+            0x0000 ..= 0x3FFF => addr as usize,
+            _ => bank * ROM_BANK_SIZE + (addr as usize - 0x4000)
+        };
+        rom.get(offset).copied().unwrap_or(0xFF)
+    };
+
+    Ok(disassemble_range(read, range, bank as u16, symbols))
+}
+
+//Disassemble whatever `read` returns for the addresses in range, e.g. |addr| gameboy.peek(addr).
+//bank is the ROM bank at 0x4000-0x7FFF, it picks the symbols there and is shown before every address.
+//Jump and call targets that are instructions of the range get a label, the symbol name if there is one
+pub fn disassemble_range<F: FnMut(u16) -> u8>(mut read: F, range: RangeInclusive<u16>, bank: u16, symbols: &Symbols) -> String {
+    let (start, end) = (*range.start() as u32, *range.end() as u32);
+    let mut fetch = |addr: u16| [read(addr), read(addr.wrapping_add(1)), read(addr.wrapping_add(2))];
+
+    //first pass finds the instructions and where they jump
+    let mut instructions = vec![];
+    let mut targets = HashSet::new();
+    let mut starts = HashSet::new();
+
+    let mut addr = start;
+    while addr <= end {
+        let bytes = fetch(addr as u16);
+        let (_, length) = disassemble(bytes, addr as u16);
+
+        if let Some(target) = jump_target(bytes, addr as u16) {
+            targets.insert(target);
+        }
+
+        starts.insert(addr as u16);
+        instructions.push((addr as u16, bytes, length));
+        addr += length as u32;
+    }
+
+    let label = |addr: u16| -> Option<String> {
+        match symbols.name(bank, addr) {
+            Some(name) => Some(name.to_string()),
+            None if targets.contains(&addr) && starts.contains(&addr) => Some(format!("L_{:04X}", addr)),
+            None => None
+        }
+    };
+
+    let mut lines = vec![];
+
+    for (addr, bytes, length) in instructions {
+        if let Some(name) = label(addr) {
+            lines.push(format!("{}:", name));
+        }
+
+        let (text, _) = disassemble(bytes, addr);
+        let text = with_label(text, bytes, addr, label);
+
+        let shown_bank = if (0x4000 ..= 0x7FFF).contains(&addr) { bank } else { 0 };
+        let hex: Vec<String> = bytes[..length as usize].iter().map(|byte| format!("{:02X}", byte)).collect();
+        lines.push(format!("    {:02X}:{:04X}  {:<8}  {}", shown_bank, addr, hex.join(" "), text));
+    }
+
+    lines.join("\n")
+}
@@ -0,0 +1,489 @@
+#![allow(non_snake_case)]
+
+mod square;
+mod wave;
+mod noise;
+use square::Square;
+use wave::Wave;
+use noise::Noise;
+use super::io_constants::{*};
+use super::cpu::registers::Response;
+use super::bit_utils::BitUtils;
+use super::state::{StateWriter, StateReader, StateError};
+
+const CLOCK: u32 = 4194304;
+//the frame sequencer runs at 512Hz
+const SEQUENCER_CYCLES: u16 = 8192;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+//Length counter, clocked at 256Hz by the frame sequencer. Disables the channel when it reaches 0
+pub struct Length {
+    pub enabled: bool,
+    counter: u16,
+    max: u16,
+}
+
+impl Length {
+    pub fn new(max: u16) -> Self {
+        Length { enabled: false, counter: 0, max }
+    }
+
+    pub fn load(&mut self, value: u8) {
+        self.counter = self.max - value as u16;
+    }
+
+    pub fn trigger(&mut self) {
+        if self.counter == 0 {
+            self.counter = self.max;
+        }
+    }
+
+    //returns false if the channel should be disabled
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter != 0;
+        }
+        true
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u16(self.counter);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.bool()?;
+        self.counter = state.u16()?;
+        if self.counter > self.max { return Err(StateError::Corrupt) }
+        Ok(())
+    }
+}
+
+//Volume envelope, clocked at 64Hz by the frame sequencer
+#[derive(Default)]
+pub struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+    pub volume: u8,
+}
+
+impl Envelope {
+    pub fn write(&mut self, byte: u8) {
+        self.initial = byte >> 4;
+        self.increase = byte.test_bit(3);
+        self.period = byte & 0x07;
+    }
+
+    pub fn read(&self) -> u8 {
+        self.initial << 4 | (self.increase as u8) << 3 | self.period //>
+    }
+
+    //the upper 5 bits of NRx2 power the channel DAC
+    pub fn dac_on(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    pub fn trigger(&mut self) {
+        self.timer = self.period;
+        self.volume = self.initial;
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 { return; }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period;
+
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.read());
+        state.u8(self.timer);
+        state.u8(self.volume);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.write(state.u8()?);
+        self.timer = state.u8()?;
+        self.volume = state.u8()? & 0x0F;
+        Ok(())
+    }
+}
+
+//averages the output between two samples and removes the DC offset, like the capacitor on the real output
+#[derive(Default)]
+struct Output {
+    left_acc: i32,
+    right_acc: i32,
+    capacitor: [f32; 2],
+    samples: Vec<i16>,
+}
+
+impl Output {
+    fn add(&mut self, (left, right): (i32, i32)) {
+        self.left_acc += left;
+        self.right_acc += right;
+    }
+
+    fn make_sample(&mut self, cycles: i32, charge: f32, max_len: usize) {
+        //4 channels * 15 * volume 8 is the loudest it can get
+        const MAX: f32 = 480.0;
+
+        let cycles = cycles.max(1) as f32;
+        let averaged = [self.left_acc as f32 / cycles, self.right_acc as f32 / cycles];
+
+        self.left_acc = 0;
+        self.right_acc = 0;
+
+        for (side, input) in averaged.iter().enumerate() {
+            let output = input - self.capacitor[side];
+            self.capacitor[side] = input - output * charge;
+
+            //don't grow forever if nobody drains the samples
+            if self.samples.len() >= max_len { continue; }
+
+            let scaled = output / MAX * i16::MAX as f32;
+            self.samples.push(scaled as i16);
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.i32(self.left_acc);
+        state.i32(self.right_acc);
+        state.f32(self.capacitor[0]);
+        state.f32(self.capacitor[1]);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.left_acc = state.i32()?;
+        self.right_acc = state.i32()?;
+        self.capacitor = [state.f32()?, state.f32()?];
+        Ok(())
+    }
+}
+
+pub struct APU {
+    power: bool,
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    NR50: u8,               //0xFF24     (R/W)
+    NR51: u8,               //0xFF25     (R/W)
+
+    sequencer_cycles: u16,
+    sequencer_step: u8,
+
+    sample_rate: u32,
+    sample_clock: u32,
+    //cycles since the last sample
+    acc_cycles: i32,
+    charge: f32,
+
+    mixed: Output,
+    //each channel on its own, only recorded when requested
+    channels: Option<[Output; 4]>,
+}
+
+impl Default for APU {
+    fn default() -> Self {
+        let mut apu = APU {
+            power: false,
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::default(),
+            noise: Noise::default(),
+            NR50: 0,
+            NR51: 0,
+            sequencer_cycles: 0,
+            sequencer_step: 0,
+            sample_rate: 0,
+            sample_clock: 0,
+            acc_cycles: 0,
+            charge: 0.0,
+            mixed: Output::default(),
+            channels: None,
+        };
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        apu
+    }
+}
+
+impl APU {
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+        self.sample_clock = 0;
+        self.charge = 0.999958f32.powf(CLOCK as f32 / rate as f32);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    //interleaved stereo samples (left, right) made since the last call
+    pub fn drain_samples(&mut self) -> Vec<i16> {
+        std::mem::take(&mut self.mixed.samples)
+    }
+
+    pub fn record_channels(&mut self, record: bool) {
+        self.channels = match record {
+            true => Some(Default::default()),
+            false => None
+        };
+    }
+
+    //same as drain_samples but for each channel separately, empty if they aren't being recorded
+    pub fn drain_channel_samples(&mut self) -> [Vec<i16>; 4] {
+        let mut drained: [Vec<i16>; 4] = Default::default();
+
+        if let Some(channels) = &mut self.channels {
+            for (i, channel) in channels.iter_mut().enumerate() {
+                drained[i] = std::mem::take(&mut channel.samples);
+            }
+        }
+
+        drained
+    }
+
+    pub fn step(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            if self.power {
+                self.square1.step();
+                self.square2.step();
+                self.wave.step();
+                self.noise.step();
+
+                self.sequencer_cycles += 1;
+                if self.sequencer_cycles == SEQUENCER_CYCLES {
+                    self.sequencer_cycles = 0;
+                    self.clock_sequencer();
+                }
+            }
+
+            let mixed = self.mix();
+
+            for (left, right) in mixed.iter() {
+                self.mixed.add((*left, *right));
+            }
+
+            if let Some(channels) = &mut self.channels {
+                for (channel, output) in channels.iter_mut().zip(mixed.iter()) {
+                    channel.add(*output);
+                }
+            }
+
+            self.acc_cycles += 1;
+
+            self.sample_clock += self.sample_rate;
+            if self.sample_clock >= CLOCK {
+                self.sample_clock -= CLOCK;
+                self.make_sample();
+            }
+        }
+    }
+
+    fn clock_sequencer(&mut self) {
+        //length counters on even steps, sweep on 2 and 6, envelopes on 7
+        if self.sequencer_step & 1 == 0 {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+
+        if self.sequencer_step == 2 || self.sequencer_step == 6 {
+            self.square1.clock_sweep();
+        }
+
+        if self.sequencer_step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+
+        self.sequencer_step = (self.sequencer_step + 1) % 8;
+    }
+
+    //DAC output of each channel in the -15..=15 range, 0 if its DAC is off
+    fn channel_outputs(&self) -> [i32; 4] {
+        let dac = |output: Option<u8>| match output {
+            Some(x) => x as i32 * 2 - 15,
+            None => 0
+        };
+
+        [
+            dac(self.square1.output()),
+            dac(self.square2.output()),
+            dac(self.wave.output()),
+            dac(self.noise.output()),
+        ]
+    }
+
+    //route each channel to the terminals selected by NR51 and scale by the NR50 master volume
+    fn mix(&self) -> [(i32, i32); 4] {
+        let outputs = self.channel_outputs();
+
+        let left_volume = ((self.NR50 >> 4) & 0x07) as i32 + 1;
+        let right_volume = (self.NR50 & 0x07) as i32 + 1;
+
+        let mut mixed = [(0, 0); 4];
+
+        for (i, output) in outputs.iter().enumerate() {
+            if self.NR51.test_bit(i as u8 + 4) { mixed[i].0 = output * left_volume; }
+            if self.NR51.test_bit(i as u8) { mixed[i].1 = output * right_volume; }
+        }
+
+        mixed
+    }
+
+    fn make_sample(&mut self) {
+        //one second is plenty
+        let max_len = self.sample_rate as usize * 2;
+
+        self.mixed.make_sample(self.acc_cycles, self.charge, max_len);
+
+        if let Some(channels) = &mut self.channels {
+            for channel in channels.iter_mut() {
+                channel.make_sample(self.acc_cycles, self.charge, max_len);
+            }
+        }
+
+        self.acc_cycles = 0;
+    }
+
+    pub fn write_byte(&mut self, addr: u16, byte: u8) -> Response {
+        //wave ram can always be written, everything else is read only while the APU is off
+        if !self.power && addr != NR52 && !(WAVE_RAM ..= WAVE_RAM_END).contains(&addr) {
+            return Response::None;
+        }
+
+        match addr {
+            NR10 ..= NR14 => self.square1.write((addr - NR10) as u8, byte),
+            NR21 ..= NR24 => self.square2.write((addr - NR21 + 1) as u8, byte),
+            NR30 ..= NR34 => self.wave.write((addr - NR30) as u8, byte),
+            NR41 ..= NR44 => self.noise.write((addr - NR41 + 1) as u8, byte),
+            NR50 => self.NR50 = byte,
+            NR51 => self.NR51 = byte,
+            NR52 => {
+                let power = byte.test_bit(7);
+
+                if !power && self.power {
+                    self.power_off();
+                } else if power && !self.power {
+                    self.sequencer_cycles = 0;
+                    self.sequencer_step = 0;
+                }
+
+                self.power = power;
+            },
+            WAVE_RAM ..= WAVE_RAM_END => self.wave.ram[(addr - WAVE_RAM) as usize] = byte,
+            _ => {}
+        }
+
+        Response::None
+    }
+
+    pub fn read_byte(&self, addr: u16) -> Response {
+        let byte = match addr {
+            NR10 ..= NR14 => self.square1.read((addr - NR10) as u8),
+            NR21 ..= NR24 => self.square2.read((addr - NR21 + 1) as u8),
+            NR30 ..= NR34 => self.wave.read((addr - NR30) as u8),
+            NR41 ..= NR44 => self.noise.read((addr - NR41 + 1) as u8),
+            NR50 => self.NR50,
+            NR51 => self.NR51,
+            NR52 => {
+                let mut status = 0x70;
+                if self.power { status.set_bit(7); }
+                if self.square1.enabled { status.set_bit(0); }
+                if self.square2.enabled { status.set_bit(1); }
+                if self.wave.enabled { status.set_bit(2); }
+                if self.noise.enabled { status.set_bit(3); }
+                status
+            },
+            WAVE_RAM ..= WAVE_RAM_END => self.wave.ram[(addr - WAVE_RAM) as usize],
+            _ => 0xFF
+        };
+
+        Response::Byte(byte)
+    }
+
+    //the buffered samples aren't saved, only what's needed to keep making the same ones
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.power);
+        self.square1.save_state(state);
+        self.square2.save_state(state);
+        self.wave.save_state(state);
+        self.noise.save_state(state);
+        state.u8(self.NR50);
+        state.u8(self.NR51);
+        state.u16(self.sequencer_cycles);
+        state.u8(self.sequencer_step);
+
+        state.u32(self.sample_rate);
+        state.u32(self.sample_clock);
+        state.i32(self.acc_cycles);
+        self.mixed.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.power = state.bool()?;
+        self.square1.load_state(state)?;
+        self.square2.load_state(state)?;
+        self.wave.load_state(state)?;
+        self.noise.load_state(state)?;
+        self.NR50 = state.u8()?;
+        self.NR51 = state.u8()?;
+        self.sequencer_cycles = state.u16()? % SEQUENCER_CYCLES;
+        self.sequencer_step = state.u8()? & 0x07;
+
+        let rate = state.u32()?;
+        let clock = state.u32()?;
+        if rate == 0 || clock >= CLOCK { return Err(StateError::Corrupt) }
+
+        self.set_sample_rate(rate);
+        self.sample_clock = clock;
+        self.acc_cycles = state.i32()?;
+        self.mixed.load_state(state)?;
+        Ok(())
+    }
+
+    //keep the output settings and samples of the APU being replaced.
+    //The loaded sampling phase is only kept if the state was made with the same sample rate
+    pub fn take_output(&mut self, old: &mut APU) {
+        if self.sample_rate != old.sample_rate {
+            self.set_sample_rate(old.sample_rate);
+            self.acc_cycles = 0;
+            self.mixed = Output::default();
+        }
+
+        self.mixed.samples = std::mem::take(&mut old.mixed.samples);
+        self.channels = old.channels.take();
+    }
+
+    //turning the APU off clears every register except wave ram
+    fn power_off(&mut self) {
+        let ram = self.wave.ram;
+
+        self.square1 = Square::new(true);
+        self.square2 = Square::new(false);
+        self.wave = Wave::default();
+        self.noise = Noise::default();
+        self.wave.ram = ram;
+
+        self.NR50 = 0;
+        self.NR51 = 0;
+    }
+}
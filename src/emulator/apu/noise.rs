@@ -0,0 +1,128 @@
+use super::{Length, Envelope};
+use super::super::bit_utils::BitUtils;
+use super::super::state::{StateWriter, StateReader, StateError};
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+//Noise channel (4), output comes from a 15 bit linear feedback shift register
+pub struct Noise {
+    pub enabled: bool,
+    shift: u8,
+    short_mode: bool,
+    divisor: u8,
+    timer: u32,
+    lfsr: u16,
+    pub length: Length,
+    pub envelope: Envelope,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise {
+            enabled: false,
+            shift: 0,
+            short_mode: false,
+            divisor: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+        }
+    }
+}
+
+impl Noise {
+    //register is the x in NR4x
+    pub fn write(&mut self, register: u8, byte: u8) {
+        match register {
+            1 => self.length.load(byte & 0x3F),
+            2 => {
+                self.envelope.write(byte);
+                if !self.envelope.dac_on() { self.enabled = false; }
+            },
+            3 => {
+                self.shift = byte >> 4;
+                self.short_mode = byte.test_bit(3);
+                self.divisor = byte & 0x07;
+            },
+            4 => {
+                self.length.enabled = byte.test_bit(6);
+                if byte.test_bit(7) { self.trigger(); }
+            },
+            _ => {}
+        }
+    }
+
+    //unreadable bits read as 1
+    pub fn read(&self, register: u8) -> u8 {
+        match register {
+            2 => self.envelope.read(),
+            3 => self.shift << 4 | (self.short_mode as u8) << 3 | self.divisor, //>
+            4 => 0xBF | (self.length.enabled as u8) << 6, //>
+            _ => 0xFF
+        }
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[self.divisor as usize] << self.shift //>
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_on();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+        self.lfsr = 0x7FFF;
+    }
+
+    pub fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.timer <= 1 {
+            self.timer = self.period();
+
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | bit << 14; //>
+
+            //7 bit mode also puts the result in bit 6
+            if self.short_mode {
+                self.lfsr = (self.lfsr & !0x40) | bit << 6; //>
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u8(self.read(3));
+        state.u32(self.timer);
+        state.u16(self.lfsr);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.bool()?;
+        self.write(3, state.u8()?);
+        self.timer = state.u32()?;
+        self.lfsr = state.u16()? & 0x7FFF;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        Ok(())
+    }
+
+    //digital output (0-15), None if the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_on() { return None; }
+        if !self.enabled { return Some(0); }
+
+        let high = !self.lfsr.test_bit(0);
+
+        Some(high as u8 * self.envelope.volume)
+    }
+}
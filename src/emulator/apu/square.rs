@@ -0,0 +1,203 @@
+use super::{Length, Envelope};
+use super::super::bit_utils::BitUtils;
+use super::super::state::{StateWriter, StateReader, StateError};
+
+//waveforms for 12.5%, 25%, 50% and 75% duty cycles
+const DUTY: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+//Square wave channel (1 and 2), only channel 1 has a frequency sweep unit
+pub struct Square {
+    pub enabled: bool,
+    has_sweep: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_timer: u8,
+    sweep_on: bool,
+    shadow: u16,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    timer: u16,
+    pub length: Length,
+    pub envelope: Envelope,
+}
+
+impl Square {
+    pub fn new(has_sweep: bool) -> Self {
+        Square {
+            enabled: false,
+            has_sweep,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_timer: 0,
+            sweep_on: false,
+            shadow: 0,
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 0,
+            length: Length::new(64),
+            envelope: Envelope::default(),
+        }
+    }
+
+    //register is the x in NRnx
+    pub fn write(&mut self, register: u8, byte: u8) {
+        match register {
+            0 => {
+                self.sweep_period = (byte >> 4) & 0x07;
+                self.sweep_negate = byte.test_bit(3);
+                self.sweep_shift = byte & 0x07;
+            },
+            1 => {
+                self.duty = byte >> 6;
+                self.length.load(byte & 0x3F);
+            },
+            2 => {
+                self.envelope.write(byte);
+                if !self.envelope.dac_on() { self.enabled = false; }
+            },
+            3 => {
+                self.frequency = (self.frequency & 0x700) | byte as u16;
+            },
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((byte & 0x07) as u16) << 8; //>
+                self.length.enabled = byte.test_bit(6);
+                if byte.test_bit(7) { self.trigger(); }
+            },
+            _ => {}
+        }
+    }
+
+    //unreadable bits read as 1
+    pub fn read(&self, register: u8) -> u8 {
+        match register {
+            0 => 0x80 | self.sweep_period << 4 | (self.sweep_negate as u8) << 3 | self.sweep_shift, //>
+            1 => self.duty << 6 | 0x3F, //>
+            2 => self.envelope.read(),
+            4 => 0xBF | (self.length.enabled as u8) << 6, //>
+            _ => 0xFF
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_on();
+        self.length.trigger();
+        self.timer = self.period();
+        self.envelope.trigger();
+
+        if self.has_sweep {
+            self.shadow = self.frequency;
+            self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+            self.sweep_on = self.sweep_period != 0 || self.sweep_shift != 0;
+
+            if self.sweep_shift != 0 {
+                self.calculate_sweep();
+            }
+        }
+    }
+
+    //new frequency from the shadow register, disables the channel if it overflows
+    fn calculate_sweep(&mut self) -> u16 {
+        let delta = self.shadow >> self.sweep_shift;
+
+        let frequency = if self.sweep_negate {
+            self.shadow.wrapping_sub(delta)
+        } else {
+            self.shadow + delta
+        };
+
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+
+        frequency
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if !self.has_sweep { return; }
+
+        if self.sweep_timer > 0 {
+            self.sweep_timer -= 1;
+        }
+
+        if self.sweep_timer == 0 {
+            self.sweep_timer = if self.sweep_period == 0 { 8 } else { self.sweep_period };
+
+            if self.sweep_on && self.sweep_period != 0 {
+                let frequency = self.calculate_sweep();
+
+                if frequency <= 2047 && self.sweep_shift != 0 {
+                    self.frequency = frequency;
+                    self.shadow = frequency;
+                    //the new frequency is checked for overflow again, but not used
+                    self.calculate_sweep();
+                }
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.timer <= 1 {
+            self.timer = self.period();
+            self.duty_step = (self.duty_step + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.u8(self.sweep_period);
+        state.bool(self.sweep_negate);
+        state.u8(self.sweep_shift);
+        state.u8(self.sweep_timer);
+        state.bool(self.sweep_on);
+        state.u16(self.shadow);
+        state.u8(self.duty);
+        state.u8(self.duty_step);
+        state.u16(self.frequency);
+        state.u16(self.timer);
+        self.length.save_state(state);
+        self.envelope.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.bool()?;
+        self.sweep_period = state.u8()? & 0x07;
+        self.sweep_negate = state.bool()?;
+        self.sweep_shift = state.u8()? & 0x07;
+        self.sweep_timer = state.u8()?;
+        self.sweep_on = state.bool()?;
+        self.shadow = state.u16()?;
+        self.duty = state.u8()? & 0x03;
+        self.duty_step = state.u8()? & 0x07;
+        self.frequency = state.u16()? & 0x7FF;
+        self.timer = state.u16()?;
+        self.length.load_state(state)?;
+        self.envelope.load_state(state)?;
+        Ok(())
+    }
+
+    //digital output (0-15), None if the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.envelope.dac_on() { return None; }
+        if !self.enabled { return Some(0); }
+
+        let high = DUTY[self.duty as usize].test_bit(7 - self.duty_step);
+
+        Some(high as u8 * self.envelope.volume)
+    }
+}
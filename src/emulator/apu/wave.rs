@@ -0,0 +1,134 @@
+use super::Length;
+use super::super::bit_utils::BitUtils;
+use super::super::state::{StateWriter, StateReader, StateError};
+
+//Wave channel (3), plays the 32 4-bit samples stored in wave ram
+pub struct Wave {
+    pub enabled: bool,
+    dac: bool,
+    volume: u8,
+    frequency: u16,
+    timer: u16,
+    position: u8,
+    sample: u8,
+    pub length: Length,
+    pub ram: [u8; 16],
+}
+
+impl Default for Wave {
+    fn default() -> Self {
+        Wave {
+            enabled: false,
+            dac: false,
+            volume: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            length: Length::new(256),
+            ram: [0; 16],
+        }
+    }
+}
+
+impl Wave {
+    //register is the x in NR3x
+    pub fn write(&mut self, register: u8, byte: u8) {
+        match register {
+            0 => {
+                self.dac = byte.test_bit(7);
+                if !self.dac { self.enabled = false; }
+            },
+            1 => self.length.load(byte),
+            2 => self.volume = (byte >> 5) & 0x03,
+            3 => {
+                self.frequency = (self.frequency & 0x700) | byte as u16;
+            },
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((byte & 0x07) as u16) << 8; //>
+                self.length.enabled = byte.test_bit(6);
+                if byte.test_bit(7) { self.trigger(); }
+            },
+            _ => {}
+        }
+    }
+
+    //unreadable bits read as 1
+    pub fn read(&self, register: u8) -> u8 {
+        match register {
+            0 => 0x7F | (self.dac as u8) << 7, //>
+            2 => 0x9F | self.volume << 5, //>
+            4 => 0xBF | (self.length.enabled as u8) << 6, //>
+            _ => 0xFF
+        }
+    }
+
+    fn period(&self) -> u16 {
+        (2048 - self.frequency) * 2
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac;
+        self.length.trigger();
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    pub fn clock_length(&mut self) {
+        if !self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn step(&mut self) {
+        if self.timer <= 1 {
+            self.timer = self.period();
+            self.position = (self.position + 1) & 0x1F;
+
+            //high nibble is played first
+            let byte = self.ram[(self.position / 2) as usize];
+            self.sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.enabled);
+        state.bool(self.dac);
+        state.u8(self.volume);
+        state.u16(self.frequency);
+        state.u16(self.timer);
+        state.u8(self.position);
+        state.u8(self.sample);
+        self.length.save_state(state);
+        state.bytes(&self.ram);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = state.bool()?;
+        self.dac = state.bool()?;
+        self.volume = state.u8()? & 0x03;
+        self.frequency = state.u16()? & 0x7FF;
+        self.timer = state.u16()?;
+        self.position = state.u8()? & 0x1F;
+        self.sample = state.u8()? & 0x0F;
+        self.length.load_state(state)?;
+        state.bytes(&mut self.ram)?;
+        Ok(())
+    }
+
+    //digital output (0-15), None if the DAC is off
+    pub fn output(&self) -> Option<u8> {
+        if !self.dac { return None; }
+        if !self.enabled { return Some(0); }
+
+        //volume code 0 mutes, 1 is 100%, 2 is 50% and 3 is 25%
+        let shift = match self.volume {
+            0 => 4,
+            code => code - 1
+        };
+
+        Some(self.sample >> shift)
+    }
+}
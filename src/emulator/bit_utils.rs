@@ -9,11 +9,11 @@ macro_rules! impl_BitUtils {
         $(impl BitUtils for $t {
             fn set_bit(&mut self, bit: u8){
                 let x = 1 << bit; //>
-                *self = *self | x;
+                *self |= x;
             }
             fn reset_bit(&mut self, bit: u8){
                 let x = 1 << bit; //>
-                *self = *self & !x;
+                *self &= !x;
             }
             fn test_bit(&self, bit: u8) -> bool {
                 let x = 1 << bit; //>
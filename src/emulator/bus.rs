@@ -1,23 +1,37 @@
 use super::io_constants::{*};
 
 use super::gpu::{*};
+use super::apu::{*};
 use super::memory::{*};
 use super::timer::{*};
 use super::cartridge::{*};
 use super::joypad::{*};
+use super::serial::Serial;
 use super::cpu::registers::Response;
 use super::cpu::registers::Value;
+use super::state::{StateWriter, StateReader, StateError};
+use super::watchpoint::{Watchpoints, WatchAccess};
 pub use super::interrupt::{*};
 
+//160 bytes, one per M-cycle
+const DMA_CYCLES: u16 = 160 * 4;
+
 #[derive(Default)]
 pub struct Bus {
     memory: Memory,
     pub gpu: GPU,
+    pub apu: APU,
     cartrigbe: Cartridge,
     pub interrupts: InterruptHandler,
     timer: Timer,
     pub joypad: Joypad,
-    pub halt_cpu: bool
+    pub serial: Serial,
+    pub halt_cpu: bool,
+    pub watchpoints: Watchpoints,
+    pub screen: Vec<u32>,                   //last complete frame
+    ticked: u8,                             //cycles the rest of the system already ran during this instruction
+    pub lenient: bool,                      //the CPU can use VRAM and OAM whatever the PPU is doing
+    dma_cycles: u16,                        //left until the OAM DMA ends, OAM is locked until then
     //everything with memory mapped I/O registers goes in here
 }
 
@@ -30,13 +44,64 @@ pub enum Module {
     Interrupt,
     Unusable,  
     Timer,
+    APU,
+    Serial,
 }
 
 impl Bus {
+    //CPU write: it takes an M-cycle, so the rest of the system runs for 4 cycles right after it
     pub fn write_byte(&mut self, addr: u16, byte: u8) -> Response {
+        let response = match self.locked(addr) {
+            true => {
+                if !self.watchpoints.is_empty() {
+                    let old = self.peek(addr).value();
+                    self.watchpoints.check_blocked(WatchAccess::Write, addr, old, byte);
+                }
+                Response::None
+            },
+            false => self.write_untimed(addr, byte)
+        };
+        self.tick();
+        response
+    }
+
+    //CPU read, also an M-cycle
+    pub fn read_byte(&mut self, addr: u16) -> Response {
+        let response = match self.locked(addr) {
+            true => {
+                if !self.watchpoints.is_empty() {
+                    self.watchpoints.check_blocked(WatchAccess::Read, addr, 0xFF, 0xFF);
+                }
+                Response::Byte(0xFF)
+            },
+            false => self.read_untimed(addr)
+        };
+        self.tick();
+        response
+    }
+
+    //the CPU can't reach VRAM while the PPU draws (mode 3), nor OAM while it scans or draws (modes 2 and 3) or a
+    //DMA fills it. Reads get 0xFF and writes are dropped, watchpoints still see them as blocked hits
+    fn locked(&self, addr: u16) -> bool {
+        if self.lenient { return false; }
+
+        match addr {
+            0x8000 ..= 0x9FFF => self.gpu.vram_locked(),
+            0xFE00 ..= 0xFE9F => self.gpu.oam_locked() || self.dma_cycles > 0,
+            _ => false
+        }
+    }
+
+    //write without time passing, for DMA and the debugger
+    pub fn write_untimed(&mut self, addr: u16, byte: u8) -> Response {
 
         let into = Bus::classify(addr);
 
+        if !self.watchpoints.is_empty() && into != Module::Unusable {
+            let old = self.peek(addr).value();
+            self.watchpoints.check(WatchAccess::Write, addr, old, byte);
+        }
+
         match into {
             Module::Cartrigbe => { self.cartrigbe.write_byte(addr, byte); },
 
@@ -49,10 +114,10 @@ impl Bus {
             Module::IO => {
                 match addr {
                     LCDC => self.gpu.write_lcdc(byte),
-                    STAT => self.gpu.write_stat(byte),
+                    STAT => self.gpu.write_stat(byte, &mut self.interrupts),
                     SCY => self.gpu.scroll_y = byte,
                     SCX => self.gpu.scroll_x = byte,
-                    LY => self.gpu.lcd_y = byte,
+                    LY => {},   //read only
                     LYC => self.gpu.lycompare = byte,
                     OAM_DMA => {
                         self.gpu.OAM_DMA = byte;
@@ -70,13 +135,29 @@ impl Bus {
                     _ => {}
                 }
             },
-            Module::Unusable => { panic!("Unusable was written") },
+            //nothing is there, writes are ignored
+            Module::Unusable => {},
             Module::Timer => { self.timer.write_byte(addr, byte); }
+            Module::APU => { self.apu.write_byte(addr, byte); }
+            Module::Serial => { self.serial.write_byte(addr, byte); }
         }
 
         Response::None
     }
-    pub fn read_byte(&mut self, addr: u16) -> Response {
+    pub fn read_untimed(&mut self, addr: u16) -> Response {
+        let response = self.peek(addr);
+
+        if !self.watchpoints.is_empty() {
+            if let Response::Byte(byte) = response {
+                self.watchpoints.check(WatchAccess::Read, addr, byte, byte);
+            }
+        }
+
+        response
+    }
+
+    //read without triggering watchpoints, for the debugger and the old value of watched writes
+    pub fn peek(&mut self, addr: u16) -> Response {
 
         let from = Bus::classify(addr);
 
@@ -105,25 +186,19 @@ impl Bus {
                     _ => { Response::Byte(0xFF) }
                 }
             },
-            Module::Unusable => { panic!("Unusable was read") },
+            Module::Unusable => Response::Byte(0xFF),
             Module::Timer => { self.timer.read_byte(addr) },
+            Module::APU => { self.apu.read_byte(addr) },
+            Module::Serial => { self.serial.read_byte(addr) },
         }
 
 
     }
 
+    //low byte first, each one on its own like the CPU does it. The two may land in different modules
     pub fn write_short(&mut self, addr: u16, short: u16) {
-        let b1 = Bus::classify(addr);
-        let b2 = Bus::classify(addr+1);
-
-        if b1 == b2 {
-
-            self.write_byte(addr, short as u8);
-            self.write_byte(addr + 1, (short >> 8) as u8 );
-
-        } else {
-            panic!("Tried to write short along different modules")
-        }
+        self.write_byte(addr, short as u8);
+        self.write_byte(addr.wrapping_add(1), (short >> 8) as u8 );
     }
 
 
@@ -136,14 +211,91 @@ impl Bus {
             0xFE00 ..= 0xFE9F => Module::GPU,     
             0xFEA0 ..= 0xFEFF => Module::Unusable,
             TMA | TIMA | DIV | TAC => Module::Timer,
+            SB | SC => Module::Serial,
+            NR10 ..= WAVE_RAM_END => Module::APU,
             0xFF00 ..= 0xFF7F => Module::IO, 
             0xFF80 ..= 0xFFFE => Module::Memory,   
             0xFFFF            => Module::Interrupt,
         }
     }
 
-    pub fn insert_cartrigbe(&mut self, file_name: String) {
-        self.cartrigbe.insert(file_name);
+    pub fn insert_cartrigbe(&mut self, cartrigbe: Cartridge) {
+        self.cartrigbe = cartrigbe;
+    }
+
+    pub fn rumble(&self) -> bool {
+        self.cartrigbe.rumble()
+    }
+
+    pub fn rom_bank(&self) -> u16 {
+        self.cartrigbe.rom_bank()
+    }
+
+    pub fn rom_checksum(&self) -> u32 {
+        self.cartrigbe.checksum()
+    }
+
+    pub fn rom_title(&self) -> &str {
+        self.cartrigbe.title()
+    }
+
+    pub fn battery_ram(&self) -> Option<Vec<u8>> {
+        self.cartrigbe.battery_ram()
+    }
+
+    pub fn load_battery_ram(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        self.cartrigbe.load_battery_ram(data)
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        self.memory.save_state(state);
+        self.gpu.save_state(state);
+        self.apu.save_state(state);
+        self.interrupts.save_state(state);
+        self.timer.save_state(state);
+        self.joypad.save_state(state);
+        self.serial.save_state(state);
+        state.bool(self.halt_cpu);
+        state.u16(self.dma_cycles);
+        self.cartrigbe.save_state(state);
+    }
+
+    //everything is loaded into a new bus first so a bad state doesn't leave the system half restored
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut bus = Bus::default();
+
+        bus.memory.load_state(state)?;
+        bus.gpu.load_state(state)?;
+        bus.apu.load_state(state)?;
+        bus.interrupts.load_state(state)?;
+        bus.timer.load_state(state)?;
+        bus.joypad.load_state(state)?;
+        bus.serial.load_state(state)?;
+        bus.halt_cpu = state.bool()?;
+        bus.dma_cycles = state.u16()?;
+
+        //the cartridge is restored in place, it's put back as it was if the state has anything after it
+        let mut backup = StateWriter::new(self.cartrigbe.checksum());
+        self.cartrigbe.save_state(&mut backup);
+        self.cartrigbe.load_state(state)?;
+
+        if let Err(er) = state.finish() {
+            let backup = backup.finish();
+            let (mut backup, _) = StateReader::new(&backup)?;
+            self.cartrigbe.load_state(&mut backup)?;
+            return Err(er);
+        }
+
+        //the cartridge ROM, the audio output, the link cable, the watchpoints and lenient access stay the same.
+        //The screen is restored by Gameboy::load_state
+        bus.cartrigbe = std::mem::take(&mut self.cartrigbe);
+        bus.serial.take_connection(&mut self.serial);
+        bus.watchpoints = std::mem::take(&mut self.watchpoints);
+        bus.lenient = self.lenient;
+        bus.apu.take_output(&mut self.apu);
+        *self = bus;
+
+        Ok(())
     }
 
     pub fn to_short(bytes: [u8; 2]) -> u16 {
@@ -158,31 +310,47 @@ impl Bus {
         self.interrupts.master = false;
     }
 
-    pub fn run_system(&mut self, cycles: u8, screen: &mut Vec<u32>) {
-        self.gpu.step(cycles, &mut self.interrupts, screen);
-        self.timer.step(cycles, &mut self.interrupts);
+    //an M-cycle of the CPU without a memory access, like the one before a push
+    pub fn tick(&mut self) {
+        self.run_system(4);
+        self.ticked += 4;
     }
-    //maybe not an optimal solution, performs the dma all at once. The rom will wait 160 cycles either way
-    fn perform_dma(&mut self) {
-        //Max transfer start is 0xF100
-        if self.gpu.OAM_DMA <= 0xF1 {
 
-            let start = (self.gpu.OAM_DMA as u16) << 8; //>
-            let end = (self.gpu.OAM_DMA as u16) << 8 | 0x9F; //>
+    //run what's left of an instruction that took `cycles`, after the M-cycles its memory accesses already ran
+    pub fn finish_instruction(&mut self, cycles: u8) {
+        debug_assert!(self.ticked <= cycles, "an instruction ticked {} cycles but says it took {}", self.ticked, cycles);
+        let remaining = cycles.saturating_sub(self.ticked);
+        self.ticked = 0;
 
-            let mut oam_start = 0xFE00;
+        self.run_system(remaining);
+    }
 
-            //will run 160 times
-            for addr in start ..= end {
-                let byte: u8 = self.read_byte(addr).value();
+    fn run_system(&mut self, cycles: u8) {
+        self.dma_cycles = self.dma_cycles.saturating_sub(cycles as u16);
+        self.gpu.step(cycles, &mut self.interrupts, &mut self.screen);
+        self.timer.step(cycles, &mut self.interrupts);
+        self.serial.step(cycles, &mut self.interrupts);
+        self.apu.step(cycles);
+        self.cartrigbe.step(cycles);
+    }
+    //maybe not an optimal solution, performs the dma all at once. The rom will wait 160 cycles either way,
+    //OAM stays locked for them
+    fn perform_dma(&mut self) {
+        let start = (self.gpu.OAM_DMA as u16) << 8; //>
 
-                self.write_byte(oam_start, byte);
-                oam_start += 1;
-            }
+        //will run 160 times
+        for offset in 0 .. 0xA0 {
+            let addr = start + offset;
+
+            //0xE000-0xFDFF is echo RAM like for the CPU, the DMA can't see OAM and IO and reads open bus there
+            let byte: u8 = match addr {
+                0xFE00 ..= 0xFFFF => 0xFF,
+                _ => self.read_untimed(addr).value(),
+            };
 
+            self.write_untimed(0xFE00 + offset, byte);
+        }
 
-        } else {
-            panic!("Wrong OAM_DMA address")
-        }     
+        self.dma_cycles = DMA_CYCLES;
     }
 }
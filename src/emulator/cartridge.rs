@@ -5,14 +5,49 @@ use mbcx::mbc0::MBC0;
 use mbcx::mbc1::MBC1;
 use mbcx::mbc2::MBC2;
 use mbcx::mbc3::MBC3;
+use mbcx::mbc5::MBC5;
 use header::Header;
-use std::fs::File;
-use std::io::Read;
 use super::cpu::registers::Response;
+use super::state::{self, StateWriter, StateReader, StateError};
+use std::fmt::{Formatter, Display};
+
+//reasons a ROM image can be refused
+#[derive(Debug, PartialEq)]
+pub enum CartridgeError {
+    Truncated(usize),                       //image is too small to hold a header
+    HeaderChecksum { expected: u8, found: u8 },
+    ColorOnly,
+    UnsupportedType(u8),
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    SizeMismatch { header: usize, data: usize },
+    NoBattery,                              //battery RAM was loaded into a cartridge without one
+    SaveSizeMismatch { expected: usize, found: usize },
+}
+
+impl Display for CartridgeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CartridgeError::Truncated(size) => write!(f, "ROM is truncated ({} bytes)", size),
+            CartridgeError::HeaderChecksum { expected, found } => write!(f, "Header checksum doesn't match (expected {:#04x}, found {:#04x})", expected, found),
+            CartridgeError::ColorOnly => write!(f, "Game is GameBoy Color only"),
+            CartridgeError::UnsupportedType(code) => write!(f, "Cartridge type not supported: {:#04x}", code),
+            CartridgeError::InvalidRomSize(code) => write!(f, "Invalid ROM size code: {:#04x}", code),
+            CartridgeError::InvalidRamSize(code) => write!(f, "Invalid RAM size code: {:#04x}", code),
+            CartridgeError::SizeMismatch { header, data } => write!(f, "Data size doesn't match header ({} bytes in header, {} bytes of data)", header, data),
+            CartridgeError::NoBattery => write!(f, "Cartridge has no battery backed RAM"),
+            CartridgeError::SaveSizeMismatch { expected, found } => write!(f, "Save data size doesn't match cartridge RAM ({} bytes expected, {} bytes found)", expected, found),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
 
 pub struct Cartridge {
     controller: Box<dyn MBC>,
     bios_control: u8,
+    checksum: u32,
+    title: String,
 }
 
 impl Default for Cartridge {
@@ -20,39 +55,29 @@ impl Default for Cartridge {
         Cartridge { 
             controller: Box::new(MBC0::default()),
             bios_control: 0,
+            checksum: 0,
+            title: String::new(),
         }
     }
 }
 
 impl Cartridge {
-    pub fn insert(&mut self, fname: String) {
-        let file = File::open(fname);
-
-        let mut data: Vec<u8> = vec![];
-
-        match file {
-            Ok(file) => {
-                for byte in file.bytes() {
-                    data.push(byte.unwrap());
-                }
-            }
-            Err(file) => {
-                println!("{}. Exiting", file);
-                std::process::exit(1);
-            }
-        }
+    pub fn from_bytes(data: &[u8]) -> Result<Cartridge, CartridgeError> {
+        let head = Header::parse(data)?;
 
-        let head = Header::parse(&data);
+        let mut controller: Box<dyn MBC> = match head.cartridge_type {
+            0 => Box::new(MBC0::default()),
+            1 ..= 3 => Box::new(MBC1::default()),
+            5 ..= 6 => Box::new(MBC2::default()),
+            0x0F ..= 0x13 => Box::new(MBC3::default()),
+            0x19 ..= 0x1E => Box::new(MBC5::default()),
+            code => return Err(CartridgeError::UnsupportedType(code))
+        };
 
-        match head.cartridge_type {
-            0 => {},
-            1 ..= 3 => { self.controller = Box::new(MBC1::default()) }
-            5 ..= 6 => { self.controller = Box::new(MBC2::default()) }
-            0x11 ..= 0x13 => { self.controller = Box::new(MBC3::default()) }
-            _ => unimplemented!("Cartridge type not suported")
-        }
+        let title = head.title.clone();
+        controller.load(data.to_vec(), head)?;
 
-        self.controller.load(data, head);
+        Ok(Cartridge { controller, bios_control: 0, checksum: state::crc32(data), title })
     }
 
     pub fn write_byte(&mut self, addr: u16, byte: u8) {
@@ -67,9 +92,51 @@ impl Cartridge {
         }
     }
 
+    pub fn rumble(&self) -> bool {
+        self.controller.rumble()
+    }
+
+    pub fn rom_bank(&self) -> u16 {
+        self.controller.rom_bank()
+    }
+
+    pub fn step(&mut self, cycles: u8) {
+        self.controller.step(cycles);
+    }
+
+    pub fn battery_ram(&self) -> Option<Vec<u8>> {
+        self.controller.battery_ram()
+    }
+
+    pub fn load_battery_ram(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        self.controller.load_battery_ram(data)
+    }
+
+    //game title from the header
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
     pub fn bios_control(&mut self, byte: u8) {
         self.bios_control = byte;
     }
+
+    //CRC-32 of the whole ROM
+    pub fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.bios_control);
+        self.controller.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let bios_control = state.u8()?;
+        self.controller.load_state(state)?;
+        self.bios_control = bios_control;
+        Ok(())
+    }
 }
 
 const BIOS: [u8; 256] = [0x31, 0xFE, 0xFF, 0x21, 0xFF, 0x9F, 0xAF, 0x32, 0xCB, 0x7C, 0x20, 0xFA, 0x0E, 0x11, 0x21, 0x26,
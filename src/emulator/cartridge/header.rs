@@ -1,5 +1,7 @@
 #![allow(non_snake_case)]
 
+use super::CartridgeError;
+
 #[derive(Default)]
 pub struct Header {
     pub title: String,
@@ -13,15 +15,16 @@ pub struct Header {
 }
 
 impl Header {
-    pub fn parse(data: &Vec<u8>) -> Self {
+    pub fn parse(data: &[u8]) -> Result<Self, CartridgeError> {
+        //the header ends at 0x014F, anything smaller can't be a cartridge
+        if data.len() < 0x0150 { return Err(CartridgeError::Truncated(data.len())) }
 
         let mut header = Header::default();
 
-        for i in 0x0134..=0x0143{
-            let c = data[i];
+        for &c in &data[0x0134..=0x0143] {
             match c {
                 0 => {},
-                _ => header.title.push(data[i] as char)
+                _ => header.title.push(c as char)
             }
         }
 
@@ -33,32 +36,29 @@ impl Header {
         header.header_checksum = data[0x014D];
         header.global_checksum = data[0x014E] as u16 | (data[0x014F] as u16) << 8; //>
 
-        Self::validate(&header, &data);
+        Self::validate(&header, data)?;
 
-        return header;
+        return Ok(header);
     }
 
-    fn validate(header: &Header, data: &Vec<u8>) {
-        if (header.GCB_flag & 0xC0 ) == 0xC0 { panic!("Game is GameBoy Color only") }
+    fn validate(header: &Header, data: &[u8]) -> Result<(), CartridgeError> {
+        if (header.GCB_flag & 0xC0 ) == 0xC0 { return Err(CartridgeError::ColorOnly) }
 
         let mut x: u8 = 0;
 
-        for i in 0x0134 ..= 0x014C {
-            x = x.wrapping_sub(data[i]).wrapping_sub(1);
+        for &byte in &data[0x0134 ..= 0x014C] {
+            x = x.wrapping_sub(byte).wrapping_sub(1);
         }
 
-        if x != header.header_checksum { panic!("Header checksum doesn't match") }
-
+        if x != header.header_checksum {
+            return Err(CartridgeError::HeaderChecksum { expected: header.header_checksum, found: x })
+        }
 
         //at this point we have a valid cartrigbe
+        Ok(())
     }
 
     pub fn has_battery(&self) -> bool {
-        match self.cartridge_type {
-            3 => true,
-            6 => true,
-            0x13 => true,
-            _ => false
-        }
+        matches!(self.cartridge_type, 3 | 6 | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E)
     }
 }
\ No newline at end of file
@@ -2,55 +2,120 @@ pub mod mbc0;
 pub mod mbc1;
 pub mod mbc2;
 pub mod mbc3;
+pub mod mbc5;
+pub mod rtc;
 use super::header::Header;
+pub use super::CartridgeError;
+pub use super::super::state::{StateWriter, StateReader, StateError};
 
 pub trait MBC {
     fn write(&mut self, addr: u16, byte: u8);
     fn read(&self, addr: u16) -> u8;
-    fn load(&mut self, data: Vec<u8>, header: Header );
+    fn load(&mut self, data: Vec<u8>, header: Header ) -> Result<(), CartridgeError>;
+    //state of the rumble motor, only some MBC5 carts have one
+    fn rumble(&self) -> bool { false }
+    //ROM bank mapped at 0x4000-0x7FFF
+    fn rom_bank(&self) -> u16 { 1 }
+    //advance anything that runs on the cartridge clock, like the MBC3 RTC
+    fn step(&mut self, _cycles: u8) {}
+    //banking registers and SRAM for save states, the ROM itself is never saved
+    fn save_state(&self, _state: &mut StateWriter) {}
+    fn load_state(&mut self, _state: &mut StateReader) -> Result<(), StateError> { Ok(()) }
+    //SRAM kept by the cartridge battery, None if there is no battery
+    fn battery_ram(&self) -> Option<Vec<u8>> { None }
+    fn load_battery_ram(&mut self, _data: &[u8]) -> Result<(), CartridgeError> { Err(CartridgeError::NoBattery) }
 }
-#[derive(PartialEq)]
+#[derive(PartialEq, Default)]
 pub enum Mode {
+    #[default]
     ROM,
     RAM
 }
 
-impl Default for Mode{
-    fn default() -> Self { Mode::ROM }
-}
-
-pub fn rom_to_size(code: u8) -> usize {
+pub fn rom_to_size(code: u8) -> Result<usize, CartridgeError> {
     match code {
-        0 => 0x8000,
-        1 => 0x10000,
-        2 => 0x20000,
-        3 => 0x40000,
-        4 => 0x80000,
-        5 => 0x100000,
-        6 => 0x200000,
-        7 => 0x400000,
-        8 => 0x800000,
-        0x52 => 0x120000,
-        0x53 => 0x140000,
-        0x54 => 0x180000,
-
-        _ => { unreachable!("Invalid ROM size code: {:x}", code) }
+        0 => Ok(0x8000),
+        1 => Ok(0x10000),
+        2 => Ok(0x20000),
+        3 => Ok(0x40000),
+        4 => Ok(0x80000),
+        5 => Ok(0x100000),
+        6 => Ok(0x200000),
+        7 => Ok(0x400000),
+        8 => Ok(0x800000),
+        0x52 => Ok(0x120000),
+        0x53 => Ok(0x140000),
+        0x54 => Ok(0x180000),
+
+        _ => Err(CartridgeError::InvalidRomSize(code))
     }
 }   
 
-pub fn ram_to_size(code: u8) -> usize {
+pub fn ram_to_size(code: u8) -> Result<usize, CartridgeError> {
     match code {
-        0 => 0,
-        1 => 0x800,
-        2 => 0x2000,
-        3 => 0x8000,
-        4 => 0x20000,
-        5 => 0x10000,
+        0 => Ok(0),
+        1 => Ok(0x800),
+        2 => Ok(0x2000),
+        3 => Ok(0x8000),
+        4 => Ok(0x20000),
+        5 => Ok(0x10000),
         
-        _ => { unreachable!("Invalid ROM size code: {:x}", code) }
+        _ => Err(CartridgeError::InvalidRamSize(code))
     }
 }   
 
+//checks the ROM size declared in the header against the actual data
+pub fn check_rom_size(header: &Header, data: &[u8]) -> Result<(), CartridgeError> {
+    let size = rom_to_size(header.rom_size)?;
+
+    if size != data.len() {
+        return Err(CartridgeError::SizeMismatch { header: size, data: data.len() })
+    }
+
+    Ok(())
+}
+
+pub fn save_sram(state: &mut StateWriter, sram: &[u8]) {
+    state.u32(sram.len() as u32);
+    state.bytes(sram);
+}
+
+//the saved SRAM has to be the same size as the cartridge's
+pub fn load_sram(state: &mut StateReader, size: usize) -> Result<Vec<u8>, StateError> {
+    if state.u32()? as usize != size { return Err(StateError::Corrupt) }
+
+    let mut sram = vec![0; size];
+    state.bytes(&mut sram)?;
+
+    Ok(sram)
+}
+
+pub fn battery_ram(header: &Header, sram: &[u8]) -> Option<Vec<u8>> {
+    if header.has_battery() { Some(sram.to_vec()) } else { None }
+}
+
+//battery RAM coming from a save file has to be the same size as the cartridge's SRAM
+pub fn load_battery_ram(header: &Header, sram: &mut [u8], data: &[u8]) -> Result<(), CartridgeError> {
+    if !header.has_battery() { return Err(CartridgeError::NoBattery) }
+
+    if data.len() != sram.len() {
+        return Err(CartridgeError::SaveSizeMismatch { expected: sram.len(), found: data.len() })
+    }
+
+    sram.copy_from_slice(data);
+    Ok(())
+}
+
+//banks past the end of the ROM mirror the existing ones, like on a cartridge whose upper bank lines aren't connected
+pub fn mirror_rom_bank(data: &[u8], bank: usize) -> usize {
+    bank % (data.len() / 0x4000)
+}
+
+//offset in the ROM of addr (0x4000-0x7FFF) while `bank` is mapped there
+pub fn banked_offset(data: &[u8], bank: usize, addr: u16) -> usize {
+    mirror_rom_bank(data, bank) * 0x4000 + (addr - 0x4000) as usize
+}
+
 pub fn fix_rom_bank(rom_bank: u8) -> u8 {
     match rom_bank {
         0 | 0x20 | 0x40 | 0x60 => rom_bank + 1,
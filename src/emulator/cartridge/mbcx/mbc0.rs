@@ -11,8 +11,9 @@ impl MBC for MBC0 {
         if addr > 0x7FFF { return 0xFF; }
         self.data[addr as usize]
     }
-    fn load(&mut self, data: Vec<u8>, _: Header ) {
-        if data.len() != 0x8000 { panic!("Wrong data size for MBC0") }
+    fn load(&mut self, data: Vec<u8>, header: Header ) -> Result<(), CartridgeError> {
+        check_rom_size(&header, &data)?;
         self.data = data;
+        Ok(())
     }
 }
\ No newline at end of file
@@ -1,8 +1,5 @@
 use super::super::mbcx::{*};
 use super::super::header::Header;
-use std::fs::File;
-use std::io::Read;
-use std::io::Write;
 
 #[derive(Default)]
 pub struct MBC1 {
@@ -19,17 +16,13 @@ impl MBC for MBC1 {
     fn write(&mut self, addr: u16, mut byte: u8) {
         match addr {
             0 ..= 0x1FFF => {
-                if (byte & 0x0A) == 0x0A {
-                    self.ram_on = true;
-                } else {
-                    self.ram_on = false;
-                }
+                self.ram_on = (byte & 0x0A) == 0x0A;
             },
             0x2000 ..= 0x3FFF => {
                 //only use 5 bits of byte
-                byte = byte & 0x1F;
+                byte &= 0x1F;
                 //clears all bits of rom_bank except bits 5 and 6
-                self.rom_bank = self.rom_bank & 0xE0;
+                self.rom_bank &= 0xE0;
 
                 self.rom_bank |= byte;
 
@@ -38,11 +31,11 @@ impl MBC for MBC1 {
             0x4000 ..= 0x5FFF => {
                 //only enable banking ram if ram size is 32kb or higher
                 if self.mode == Mode::RAM && self.header.ram_size > 2 {
-                    self.ram_bank = byte;
+                    self.ram_bank = byte & 0x03;
                 } else {
                     //only enable rom banking if rom size is 1mb or higher
                     if self.header.rom_size > 4 {
-                        self.rom_bank = self.rom_bank & !0xE0;
+                        self.rom_bank &= !0xE0;
                         byte = (byte & 0x3) << 5; //>
                         self.rom_bank |= byte;
                         self.rom_bank = fix_rom_bank(self.rom_bank);
@@ -58,16 +51,18 @@ impl MBC for MBC1 {
             },            
             //SRAM
             0xA000 ..= 0xBFFF => {
-                if !self.ram_on { return (); }
+                if !self.ram_on { return; }
 
-                let offset;
-                if self.mode == Mode::RAM {
-                    offset = (0x2000 * self.ram_bank as usize) + (addr - 0xA000) as usize;
+                let offset = if self.mode == Mode::RAM {
+                    (0x2000 * self.ram_bank as usize) + (addr - 0xA000) as usize
                 } else {
-                    offset = (addr - 0xA000) as usize;
-                }
+                    (addr - 0xA000) as usize
+                };
 
-                self.sram[offset] = byte;
+                //nothing to write to without RAM
+                if let Some(cell) = self.sram.get_mut(offset) {
+                    *cell = byte;
+                }
             },
             _ => {}
         }
@@ -80,12 +75,11 @@ impl MBC for MBC1 {
 
             0x4000 ..= 0x7FFF => {
                 
-                let adjusted;
-                if self.mode == Mode::ROM {
-                    adjusted = ((addr - 0x4000) as usize) + (0x4000 * self.rom_bank as usize)
+                let adjusted = if self.mode == Mode::ROM {
+                    banked_offset(&self.data, self.rom_bank as usize, addr)
                 } else {
-                    adjusted = (addr - 0x4000) as usize
-                }
+                    (addr - 0x4000) as usize
+                };
                 return self.data[adjusted];
             }
 
@@ -93,98 +87,75 @@ impl MBC for MBC1 {
                 if !self.ram_on {
                     return 0xFF;
                 }
-                let offset;
-
-                if self.mode == Mode::RAM {
-                    offset = (0x2000 * self.ram_bank as usize) + (addr - 0xA000) as usize;
+                let offset = if self.mode == Mode::RAM {
+                    (0x2000 * self.ram_bank as usize) + (addr - 0xA000) as usize
                 } else {
-                    offset = (addr - 0xA000) as usize;
-                }
+                    (addr - 0xA000) as usize
+                };
 
-                self.sram[offset]
+                *self.sram.get(offset).unwrap_or(&0xFF)
             }
 
-            _ => { panic!("Wrong cartridge address") }
+            _ => 0xFF
         }   
 
     }
-    fn load(&mut self, data: Vec<u8>, header: Header ) {
+    fn load(&mut self, data: Vec<u8>, header: Header ) -> Result<(), CartridgeError> {
         self.header = header; //move header to controller
 
         match self.header.cartridge_type {
             1 => {
-                let size = rom_to_size(self.header.rom_size);
-                if self.header.ram_size != 0 { panic!("Cartridge type = 1 but ram_size != 0") }
-                if size != data.len() { panic!("Data size doesn't match header") }
+                if self.header.ram_size != 0 { return Err(CartridgeError::InvalidRamSize(self.header.ram_size)) }
+                check_rom_size(&self.header, &data)?;
             },
-            2 => {
-                let size = rom_to_size(self.header.rom_size);
-                let rsize = ram_to_size(self.header.ram_size);
-                if size != data.len() { panic!("Data size doesn't match header") }
+            2 ..= 3 => {
+                let rsize = ram_to_size(self.header.ram_size)?;
+                check_rom_size(&self.header, &data)?;
                 self.sram = vec![0; rsize];
             },
-            3 => {
-                let size = rom_to_size(self.header.rom_size);
-                if size != data.len() { panic!("Data size and doesn't match header info") }
-                self.try_load();
-            },
-            _ => panic!("Wrong type for MBC1: {:x}", self.header.cartridge_type )
+            _ => return Err(CartridgeError::UnsupportedType(self.header.cartridge_type))
         }
 
         self.rom_bank = 1;
         self.data = data; //move data to controller
+        Ok(())
     }
-}
-
-impl MBC1 {
-    fn try_load(&mut self) {
-        let rsize = ram_to_size(self.header.ram_size);
-
-        let mut name = self.header.title.clone();
-        name.push_str(".sav");
-        let file = File::open(name);
-
-
-        match file {
-            Ok(mut file) => {
-                match file.read_to_end(&mut self.sram) {
-                    Ok(file_size) => {
-                        if file_size != rsize {
-                            println!("WARNING: Loading SRAM data failed -> .sav size mismatch");
-                            self.sram = vec![0; rsize];
-                        } else {
-                            println!("SRAM data loaded")
-                        }
-                    },
-                    Err(er) => {
-                        println!("WARNING: Loading SRAM data failed -> {}", er);
-                        self.sram = vec![0; rsize];
-                    }
-                }
-            },
-            Err(_) => {
-                self.sram = vec![0; rsize];
-            }
-        }
+    fn rom_bank(&self) -> u16 {
+        if self.mode == Mode::ROM { mirror_rom_bank(&self.data, self.rom_bank as usize) as u16 } else { 0 }
     }
-}
-
-impl Drop for MBC1 {
-    fn drop(&mut self) {
-        if self.header.has_battery() {
-            let mut name = self.header.title.clone();
-            name.push_str(".sav");
-            let fp = File::create(name);
-    
-            match fp {
-                Ok(mut file) => {
-                    match file.write_all(&self.sram) {
-                        Ok(_) => println!("SRAM data saved"),
-                        Err(er) => println!("WARNING: Saving SRAM data failed -> {}", er)
-                    } 
-                },
-                Err(er) => println!("WARNING: Saving SRAM data failed -> {}", er)
-            }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.mode == Mode::RAM);
+        state.bool(self.ram_on);
+        state.u8(self.rom_bank);
+        state.u8(self.ram_bank);
+        save_sram(state, &self.sram);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mode = if state.bool()? { Mode::RAM } else { Mode::ROM };
+        let ram_on = state.bool()?;
+        let rom_bank = state.u8()?;
+        let ram_bank = state.u8()?;
+        let sram = load_sram(state, self.sram.len())?;
+
+        //only values the registers can take with this ROM and RAM size, banks past the end of the ROM are mirrored
+        let max_rom_bank = if self.header.rom_size > 4 { 0x7F } else { 0x1F };
+        let max_ram_bank = if self.header.ram_size > 2 { 3 } else { 0 };
+
+        if rom_bank == 0 || rom_bank > max_rom_bank || ram_bank > max_ram_bank {
+            return Err(StateError::Corrupt);
         }
+
+        self.mode = mode;
+        self.ram_on = ram_on;
+        self.rom_bank = rom_bank;
+        self.ram_bank = ram_bank;
+        self.sram = sram;
+        Ok(())
+    }
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        battery_ram(&self.header, &self.sram)
+    }
+    fn load_battery_ram(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        load_battery_ram(&self.header, &mut self.sram, data)
     }
 }
\ No newline at end of file
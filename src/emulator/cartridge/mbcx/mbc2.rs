@@ -1,8 +1,5 @@
 use super::super::mbcx::{*};
 use super::super::header::Header;
-use std::fs::File;
-use std::io::Read;
-use std::io::Write;
 
 #[derive(Default)]
 pub struct MBC2 {
@@ -16,22 +13,19 @@ pub struct MBC2 {
 impl MBC for MBC2 {
     fn write(&mut self, addr: u16, byte: u8) {
         match addr {
-            0 ..= 0x1FFF => {
-                //check if addr accepts ram enable/disable
-                if (addr & 0x100) == 0 {
-                    self.ram_on = (byte & 0x0A) == 0x0A;
-                }
+            //check if addr accepts ram enable/disable
+            0 ..= 0x1FFF if (addr & 0x100) == 0 => {
+                self.ram_on = (byte & 0x0A) == 0x0A;
             },
-            0x2000 ..= 0x3FFF => {
-                if (addr & 0x100) == 0x100 {
-                    self.rom_bank = byte & 0x0F;
-                    if self.rom_bank == 0 { self.rom_bank = 1};
-                }
+            0x2000 ..= 0x3FFF if (addr & 0x100) == 0x100 => {
+                self.rom_bank = byte & 0x0F;
+                if self.rom_bank == 0 { self.rom_bank = 1};
             },
-            0xA000 ..= 0xA1FF => {
-                self.sram[ (addr - 0xA000) as usize ] = byte & 0x0F;
+            //the 512 half bytes are mirrored through the whole ram area
+            0xA000 ..= 0xBFFF => {
+                self.sram[ (addr & 0x1FF) as usize ] = byte & 0x0F;
             },
-            _ => { panic!("Wrong cartridge address")}
+            _ => {}
         }
     }
     fn read(&self, addr: u16) -> u8 {
@@ -40,86 +34,56 @@ impl MBC for MBC2 {
             0 ..= 0x3FFF => self.data[addr as usize],
 
             0x4000 ..= 0x7FFF => {
-                let adjusted = ((addr - 0x4000) as usize) + (0x4000 * self.rom_bank as usize);
-                return self.data[adjusted];
+                return self.data[banked_offset(&self.data, self.rom_bank as usize, addr)];
             },
 
-            0xA000 ..= 0xA1FF => self.sram[ (addr - 0xA000) as usize] & 0x0F,
+            0xA000 ..= 0xBFFF => self.sram[ (addr & 0x1FF) as usize] & 0x0F,
 
-            _ => {panic!("Wrong cartridge address")}
+            _ => 0xFF
         }
     }
-    fn load(&mut self, data: Vec<u8>, header: Header ) {
+    fn load(&mut self, data: Vec<u8>, header: Header ) -> Result<(), CartridgeError> {
         self.header = header;
 
         match self.header.cartridge_type {
-            5 => {
-                let size = rom_to_size(self.header.rom_size);
-                if self.header.ram_size != 0 { panic!("ram_size != 0 for MBC2") }
-                if size != data.len() { panic!("Data size doesn't match header") }
+            5 ..= 6 => {
+                if self.header.ram_size != 0 { return Err(CartridgeError::InvalidRamSize(self.header.ram_size)) }
+                check_rom_size(&self.header, &data)?;
                 self.sram = vec![0; 512];
             },
-            6 => {
-                let size = rom_to_size(self.header.rom_size);
-                if self.header.ram_size != 0 { panic!("ram_size != 0 for MBC2") }
-                if size != data.len() { panic!("Data size doesn't match header") }
-                self.try_load();
-            },
-            _ => panic!("Wrong type for MBC2: {:x}", self.header.cartridge_type )
+            _ => return Err(CartridgeError::UnsupportedType(self.header.cartridge_type))
         }
 
         self.data = data;
+        Ok(())
     }
-}
-
-impl MBC2 {
-    fn try_load(&mut self) {
-        let rsize = 512;
-
-        let mut name = self.header.title.clone();
-        name.push_str(".sav");
-        let file = File::open(name);
-
-        match file {
-            Ok(mut file) => {
-                match file.read_to_end(&mut self.sram) {
-                    Ok(file_size) => {
-                        if file_size != rsize {
-                            println!("WARNING: Loading SRAM data failed -> .sav size mismatch");
-                            self.sram = vec![0; rsize];
-                        } else {
-                            println!("SRAM data loaded")
-                        }
-                    },
-                    Err(er) => {
-                        println!("WARNING: Loading SRAM data failed -> {}", er);
-                        self.sram = vec![0; rsize];
-                    }
-                }
-            },
-            Err(_) => {
-                self.sram = vec![0; rsize];
-            }
-        }
+    fn rom_bank(&self) -> u16 {
+        mirror_rom_bank(&self.data, self.rom_bank as usize) as u16
     }
-}
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.ram_on);
+        state.u8(self.rom_bank);
+        save_sram(state, &self.sram);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let ram_on = state.bool()?;
+        let rom_bank = state.u8()?;
+        let sram = load_sram(state, self.sram.len())?;
 
-impl Drop for MBC2 {
-    fn drop(&mut self) {
-        if self.header.has_battery() {
-            let mut name = self.header.title.clone();
-            name.push_str(".sav");
-            let fp = File::create(name);
-    
-            match fp {
-                Ok(mut file) => {
-                    match file.write_all(&self.sram) {
-                        Ok(_) => println!("SRAM data saved"),
-                        Err(er) => println!("WARNING: Saving SRAM data failed -> {}", er)
-                    } 
-                },
-                Err(er) => println!("WARNING: Saving SRAM data failed -> {}", er)
-            }
+        //the register has 4 bits and is never 0, banks past the end of the ROM are mirrored
+        if !(1 ..= 0x0F).contains(&rom_bank) {
+            return Err(StateError::Corrupt);
         }
+
+        self.ram_on = ram_on;
+        self.rom_bank = rom_bank;
+        self.sram = sram;
+        Ok(())
+    }
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        battery_ram(&self.header, &self.sram)
+    }
+    fn load_battery_ram(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        load_battery_ram(&self.header, &mut self.sram, data)
     }
 }
\ No newline at end of file
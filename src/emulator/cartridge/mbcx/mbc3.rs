@@ -1,8 +1,6 @@
 use super::super::mbcx::{*};
 use super::super::header::Header;
-use std::fs::File;
-use std::io::Read;
-use std::io::Write;
+use super::rtc::{RTC, TRAILER_SIZE, SHORT_TRAILER_SIZE};
 
 #[derive(Default)]
 pub struct MBC3 {
@@ -12,17 +10,14 @@ pub struct MBC3 {
     ram_bank: u8,
     sram: Vec<u8>,
     data: Vec<u8>,
+    rtc: Option<RTC>,
 }
 
 impl MBC for MBC3 {
     fn write(&mut self, addr: u16, byte: u8) {
         match addr {
             0 ..= 0x1FFF => {
-                if (byte & 0x0A) == 0x0A {
-                    self.ram_timer_on = true;
-                } else {
-                    self.ram_timer_on = false;
-                }
+                self.ram_timer_on = (byte & 0x0A) == 0x0A;
             },
             0x2000 ..= 0x3FFF => {
                 self.rom_bank = byte & 0x7F;
@@ -35,17 +30,25 @@ impl MBC for MBC3 {
                 }
             },            
             0x6000 ..= 0x7FFF => {
-                //latch data
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.latch(byte);
+                }
             },            
             //SRAM
             0xA000 ..= 0xBFFF => {
-                if !self.ram_timer_on { return (); }
+                if !self.ram_timer_on { return; }
                 match self.ram_bank {
                     0 ..= 3 => {
                         let adjusted = (0x2000 * self.ram_bank as usize) + (addr - 0xA000) as usize;
-                        self.sram[adjusted] = byte;
+                        if adjusted < self.sram.len() {
+                            self.sram[adjusted] = byte;
+                        }
+                    },
+                    0x08 ..= 0x0C => {
+                        if let Some(rtc) = &mut self.rtc {
+                            rtc.write(self.ram_bank, byte);
+                        }
                     },
-                    0x08 ..= 0x0C => {},
                     _ => {},
                 }
             },
@@ -59,99 +62,116 @@ impl MBC for MBC3 {
             },
 
             0x4000 ..= 0x7FFF => { 
-                let adjusted = ((addr - 0x4000) as usize) + (0x4000 * self.rom_bank as usize);
-                return self.data[adjusted];
+                return self.data[banked_offset(&self.data, self.rom_bank as usize, addr)];
             }
 
             0xA000 ..= 0xBFFF => {
                 if !self.ram_timer_on {
                     return 0xFF;
                 }
-                let offset = (0x2000 * self.ram_bank as usize) + (addr - 0xA000) as usize;
-                self.sram[offset]
+                match self.ram_bank {
+                    0x08 ..= 0x0C => match &self.rtc {
+                        Some(rtc) => rtc.read(self.ram_bank),
+                        None => 0xFF
+                    },
+                    _ => {
+                        let offset = (0x2000 * self.ram_bank as usize) + (addr - 0xA000) as usize;
+                        *self.sram.get(offset).unwrap_or(&0xFF)
+                    }
+                }
             }
 
-            _ => { panic!("Wrong cartridge address") }
+            _ => 0xFF
         }   
 
     }
-    fn load(&mut self, data: Vec<u8>, header: Header ) {
+    fn load(&mut self, data: Vec<u8>, header: Header ) -> Result<(), CartridgeError> {
         self.header = header; //move header to controller
 
         match self.header.cartridge_type {
+            0x0F ..= 0x10 => {
+                let rsize = ram_to_size(self.header.ram_size)?;
+                check_rom_size(&self.header, &data)?;
+                self.sram = vec![0; rsize];
+                self.rtc = Some(RTC::default());
+            },
             0x11 => {
-                let size = rom_to_size(self.header.rom_size);
-                if self.header.ram_size != 0 { panic!("Cartridge type = 1 but ram_size != 0") }
-                if size != data.len() { panic!("Data size doesn't match header") }
+                if self.header.ram_size != 0 { return Err(CartridgeError::InvalidRamSize(self.header.ram_size)) }
+                check_rom_size(&self.header, &data)?;
             },
-            0x12 => {
-                let size = rom_to_size(self.header.rom_size);
-                let rsize = ram_to_size(self.header.ram_size);
-                if size != data.len() { panic!("Data size doesn't match header") }
+            0x12 ..= 0x13 => {
+                let rsize = ram_to_size(self.header.ram_size)?;
+                check_rom_size(&self.header, &data)?;
                 self.sram = vec![0; rsize];
             },
-            0x13 => {
-                let size = rom_to_size(self.header.rom_size);
-                if size != data.len() { panic!("Data size and doesn't match header info") }
-                self.try_load();
-            },
-            _ => panic!("Wrong type for MBC1: {:x}", self.header.cartridge_type )
+            _ => return Err(CartridgeError::UnsupportedType(self.header.cartridge_type))
         }
 
         self.rom_bank = 1;
         self.data = data; //move data to controller
+        Ok(())
     }
-}
 
-impl MBC3 {
-    fn try_load(&mut self) {
-        let rsize = ram_to_size(self.header.ram_size);
+    fn step(&mut self, cycles: u8) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.step(cycles);
+        }
+    }
+    fn rom_bank(&self) -> u16 {
+        mirror_rom_bank(&self.data, self.rom_bank as usize) as u16
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.ram_timer_on);
+        state.u8(self.rom_bank);
+        state.u8(self.ram_bank);
+        save_sram(state, &self.sram);
 
-        let mut name = self.header.title.clone();
-        name.push_str(".sav");
-        let file = File::open(name);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(state);
+        }
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let ram_timer_on = state.bool()?;
+        let rom_bank = state.u8()?;
+        let ram_bank = state.u8()?;
+        let sram = load_sram(state, self.sram.len())?;
 
+        //the register has 7 bits and is never 0, banks past the end of the ROM are mirrored
+        if !(1 ..= 0x7F).contains(&rom_bank) || !matches!(ram_bank, 0 ..= 3 | 0x08 ..= 0x0C) {
+            return Err(StateError::Corrupt);
+        }
 
-        match file {
-            Ok(mut file) => {
-                match file.read_to_end(&mut self.sram) {
-                    Ok(file_size) => {
-                        if file_size != rsize {
-                            println!("WARNING: Loading SRAM data failed -> .sav size mismatch");
-                            self.sram = vec![0; rsize];
-                        } else {
-                            println!("SRAM data loaded")
-                        }
-                    },
-                    Err(er) => {
-                        println!("WARNING: Loading SRAM data failed -> {}", er);
-                        self.sram = vec![0; rsize];
-                    }
-                }
-            },
-            Err(_) => {
-                self.sram = vec![0; rsize];
-            }
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(state)?;
         }
+
+        self.ram_timer_on = ram_timer_on;
+        self.rom_bank = rom_bank;
+        self.ram_bank = ram_bank;
+        self.sram = sram;
+        Ok(())
     }
-}
+    //timer carts have the RTC state appended after the SRAM
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        let mut save = battery_ram(&self.header, &self.sram)?;
+
+        if let Some(rtc) = &self.rtc {
+            save.extend(rtc.save());
+        }
+
+        Some(save)
+    }
+    fn load_battery_ram(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        let rsize = self.sram.len();
 
-impl Drop for MBC3 {
-    fn drop(&mut self) {
-        if self.header.has_battery() {
-            let mut name = self.header.title.clone();
-            name.push_str(".sav");
-            let fp = File::create(name);
-    
-            match fp {
-                Ok(mut file) => {
-                    match file.write_all(&self.sram) {
-                        Ok(_) => println!("SRAM data saved"),
-                        Err(er) => println!("WARNING: Saving SRAM data failed -> {}", er)
-                    } 
-                },
-                Err(er) => println!("WARNING: Saving SRAM data failed -> {}", er)
+        if let Some(rtc) = &mut self.rtc {
+            if data.len() == rsize + TRAILER_SIZE || data.len() == rsize + SHORT_TRAILER_SIZE {
+                load_battery_ram(&self.header, &mut self.sram, &data[..rsize])?;
+                rtc.load(&data[rsize..]);
+                return Ok(());
             }
         }
+
+        load_battery_ram(&self.header, &mut self.sram, data)
     }
 }
\ No newline at end of file
@@ -0,0 +1,133 @@
+use super::super::mbcx::{*};
+use super::super::header::Header;
+
+#[derive(Default)]
+pub struct MBC5 {
+    header: Header,
+    ram_on: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    rumble: bool,
+    sram: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl MBC for MBC5 {
+    fn write(&mut self, addr: u16, byte: u8) {
+        match addr {
+            0 ..= 0x1FFF => {
+                self.ram_on = (byte & 0x0F) == 0x0A;
+            },
+            0x2000 ..= 0x2FFF => {
+                //lower 8 bits of the rom bank, bank 0 can be selected too
+                self.rom_bank = (self.rom_bank & 0x100) | byte as u16;
+            },
+            0x3000 ..= 0x3FFF => {
+                //9th bit of the rom bank
+                self.rom_bank = (self.rom_bank & 0xFF) | ((byte & 1) as u16) << 8; //>
+            },
+            0x4000 ..= 0x5FFF => {
+                //rumble carts use bit 3 to drive the motor
+                if self.has_rumble() {
+                    self.rumble = (byte & 0x08) == 0x08;
+                    self.ram_bank = byte & 0x07;
+                } else {
+                    self.ram_bank = byte & 0x0F;
+                }
+            },
+            //SRAM
+            0xA000 ..= 0xBFFF => {
+                if !self.ram_on || self.sram.is_empty() { return; }
+
+                let offset = self.ram_offset(addr);
+                self.sram[offset] = byte;
+            },
+            _ => {}
+        }
+    }
+    fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0 ..= 0x3FFF => {
+                return self.data[addr as usize]
+            },
+
+            0x4000 ..= 0x7FFF => {
+                return self.data[banked_offset(&self.data, self.rom_bank as usize, addr)];
+            }
+
+            0xA000 ..= 0xBFFF => {
+                if !self.ram_on || self.sram.is_empty() {
+                    return 0xFF;
+                }
+
+                self.sram[self.ram_offset(addr)]
+            }
+
+            _ => 0xFF
+        }   
+
+    }
+    fn load(&mut self, data: Vec<u8>, header: Header ) -> Result<(), CartridgeError> {
+        self.header = header; //move header to controller
+
+        match self.header.cartridge_type {
+            0x19 | 0x1C => {
+                check_rom_size(&self.header, &data)?;
+            },
+            0x1A | 0x1B | 0x1D | 0x1E => {
+                let rsize = ram_to_size(self.header.ram_size)?;
+                check_rom_size(&self.header, &data)?;
+                self.sram = vec![0; rsize];
+            },
+            _ => return Err(CartridgeError::UnsupportedType(self.header.cartridge_type))
+        }
+
+        self.rom_bank = 1;
+        self.data = data; //move data to controller
+        Ok(())
+    }
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+    fn rom_bank(&self) -> u16 {
+        mirror_rom_bank(&self.data, self.rom_bank as usize) as u16
+    }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.ram_on);
+        state.u16(self.rom_bank);
+        state.u8(self.ram_bank);
+        state.bool(self.rumble);
+        save_sram(state, &self.sram);
+    }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let ram_on = state.bool()?;
+        let rom_bank = state.u16()? & 0x1FF;
+        let ram_bank = state.u8()? & 0x0F;
+        let rumble = state.bool()?;
+        let sram = load_sram(state, self.sram.len())?;
+
+        self.ram_on = ram_on;
+        self.rom_bank = rom_bank;
+        self.ram_bank = ram_bank;
+        self.rumble = rumble;
+        self.sram = sram;
+        Ok(())
+    }
+    fn battery_ram(&self) -> Option<Vec<u8>> {
+        battery_ram(&self.header, &self.sram)
+    }
+    fn load_battery_ram(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        load_battery_ram(&self.header, &mut self.sram, data)
+    }
+}
+
+impl MBC5 {
+    fn has_rumble(&self) -> bool {
+        matches!(self.header.cartridge_type, 0x1C ..= 0x1E)
+    }
+
+    //banks past the end of the ram mirror the existing ones
+    fn ram_offset(&self, addr: u16) -> usize {
+        ((0x2000 * self.ram_bank as usize) + (addr - 0xA000) as usize) % self.sram.len()
+    }
+}
\ No newline at end of file
@@ -0,0 +1,350 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use super::{StateWriter, StateReader, StateError};
+
+const CYCLES_PER_SECOND: u32 = 4194304;
+
+//size of the RTC trailer appended to .sav files (5 live registers, 5 latched registers and a timestamp)
+pub const TRAILER_SIZE: usize = 48;
+//older emulators wrote a 32 bit timestamp
+pub const SHORT_TRAILER_SIZE: usize = 44;
+
+//register indexes, the RTC is mapped on ram banks 0x08 - 0x0C
+const SECONDS: usize = 0;
+const MINUTES: usize = 1;
+const HOURS: usize = 2;
+const DAY_LOW: usize = 3;
+const DAY_HIGH: usize = 4;
+
+//writable bits of each register
+const MASKS: [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, 0xC1];
+
+//MBC3 real time clock
+#[derive(Default)]
+pub struct RTC {
+    live: [u8; 5],
+    latched: [u8; 5],
+    cycles: u32,
+    latch_armed: bool,
+}
+
+impl RTC {
+    //writing 0 and then 1 copies the live registers into the latched ones
+    pub fn latch(&mut self, byte: u8) {
+        if self.latch_armed && byte == 1 {
+            self.latched = self.live;
+        }
+        self.latch_armed = byte == 0;
+    }
+
+    //bank is the selected ram bank (0x08 - 0x0C)
+    pub fn read(&self, bank: u8) -> u8 {
+        self.latched[(bank - 0x08) as usize]
+    }
+
+    pub fn write(&mut self, bank: u8, byte: u8) {
+        let register = (bank - 0x08) as usize;
+
+        //writing the seconds resets the internal divider
+        if register == SECONDS {
+            self.cycles = 0;
+        }
+
+        self.live[register] = byte & MASKS[register];
+    }
+
+    fn halted(&self) -> bool {
+        (self.live[DAY_HIGH] & 0x40) == 0x40
+    }
+
+    pub fn step(&mut self, cycles: u8) {
+        if self.halted() { return; }
+
+        self.cycles += cycles as u32;
+
+        if self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.tick();
+        }
+    }
+
+    //advance one second. Counters wrap at their bit width when set to an invalid value, without carrying
+    fn tick(&mut self) {
+        self.live[SECONDS] = (self.live[SECONDS] + 1) & MASKS[SECONDS];
+        if self.live[SECONDS] != 60 { return; }
+        self.live[SECONDS] = 0;
+
+        self.live[MINUTES] = (self.live[MINUTES] + 1) & MASKS[MINUTES];
+        if self.live[MINUTES] != 60 { return; }
+        self.live[MINUTES] = 0;
+
+        self.live[HOURS] = (self.live[HOURS] + 1) & MASKS[HOURS];
+        if self.live[HOURS] != 24 { return; }
+        self.live[HOURS] = 0;
+
+        let days = self.days() + 1;
+        self.set_days(days);
+    }
+
+    fn days(&self) -> u32 {
+        self.live[DAY_LOW] as u32 | ((self.live[DAY_HIGH] & 1) as u32) << 8 //>
+    }
+
+    //the day counter is 9 bits, overflowing sets the day carry flag (bit 7 of DH)
+    fn set_days(&mut self, mut days: u32) {
+        if days > 0x1FF {
+            days %= 0x200;
+            self.live[DAY_HIGH] |= 0x80;
+        }
+
+        self.live[DAY_LOW] = days as u8;
+        self.live[DAY_HIGH] = (self.live[DAY_HIGH] & 0xFE) | (days >> 8) as u8;
+    }
+
+    //advance the clock by the time the emulator was closed
+    fn advance(&mut self, mut seconds: u64) {
+        if self.halted() { return; }
+
+        //get out of any invalid value one second at a time so the rest can be done at once
+        while seconds > 0 && (self.live[SECONDS] > 59 || self.live[MINUTES] > 59 || self.live[HOURS] > 23) {
+            self.tick();
+            seconds -= 1;
+        }
+
+        let total = seconds
+            + self.live[SECONDS] as u64
+            + self.live[MINUTES] as u64 * 60
+            + self.live[HOURS] as u64 * 3600
+            + self.days() as u64 * 86400;
+
+        self.live[SECONDS] = (total % 60) as u8;
+        self.live[MINUTES] = (total / 60 % 60) as u8;
+        self.live[HOURS] = (total / 3600 % 24) as u8;
+        self.set_days((total / 86400).min(u32::MAX as u64) as u32);
+    }
+
+    //unlike the .sav trailer this keeps the sub-second counter and no timestamp, the clock resumes where it was
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.live);
+        state.bytes(&self.latched);
+        state.u32(self.cycles);
+        state.bool(self.latch_armed);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        let mut live = [0; 5];
+        let mut latched = [0; 5];
+        state.bytes(&mut live)?;
+        state.bytes(&mut latched)?;
+        let cycles = state.u32()?;
+        let latch_armed = state.bool()?;
+
+        if cycles >= CYCLES_PER_SECOND { return Err(StateError::Corrupt) }
+
+        for (i, mask) in MASKS.iter().enumerate() {
+            live[i] &= mask;
+            latched[i] &= mask;
+        }
+
+        *self = RTC { live, latched, cycles, latch_armed };
+        Ok(())
+    }
+
+    //RTC state in the format used by VBA-M, BGB and others: every register as a little endian u32 and a 64 bit unix timestamp
+    pub fn save(&self) -> Vec<u8> {
+        let mut trailer = Vec::with_capacity(TRAILER_SIZE);
+
+        for register in self.live.iter().chain(self.latched.iter()) {
+            trailer.extend_from_slice(&(*register as u32).to_le_bytes());
+        }
+
+        trailer.extend_from_slice(&now().to_le_bytes());
+
+        trailer
+    }
+
+    //restore the state from a .sav trailer, returns false if it isn't valid
+    pub fn load(&mut self, trailer: &[u8]) -> bool {
+        if trailer.len() != TRAILER_SIZE && trailer.len() != SHORT_TRAILER_SIZE { return false; }
+
+        let word = |i: usize| {
+            let mut bytes = [0; 4];
+            bytes.copy_from_slice(&trailer[i*4 .. i*4 + 4]);
+            u32::from_le_bytes(bytes)
+        };
+
+        for (i, mask) in MASKS.iter().enumerate() {
+            self.live[i] = word(i) as u8 & mask;
+            self.latched[i] = word(i + 5) as u8 & mask;
+        }
+
+        let timestamp = match trailer.len() {
+            TRAILER_SIZE => {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&trailer[40..48]);
+                u64::from_le_bytes(bytes)
+            },
+            _ => word(10) as u64
+        };
+
+        self.advance(now().saturating_sub(timestamp));
+
+        true
+    }
+}
+
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(time) => time.as_secs(),
+        Err(_) => 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //trailer with the registers as they are stored, only the low byte of every word counts
+    fn trailer(live: [u32; 5], latched: [u32; 5]) -> Vec<u8> {
+        let mut trailer = Vec::new();
+        for register in live.iter().chain(latched.iter()) {
+            trailer.extend_from_slice(&register.to_le_bytes());
+        }
+        trailer
+    }
+
+    fn at(hours: u8, minutes: u8, seconds: u8, days: u32) -> RTC {
+        let mut rtc = RTC::default();
+        rtc.live[HOURS] = hours;
+        rtc.live[MINUTES] = minutes;
+        rtc.live[SECONDS] = seconds;
+        rtc.set_days(days);
+        rtc
+    }
+
+    #[test]
+    fn loads_the_trailer_byte_for_byte() {
+        let mut data = trailer([0x105, 0x06, 0x07, 0x08, 0xFF81], [0x01, 0x02, 0x03, 0x04, 0x40]);
+        //a timestamp in the future means no time has passed
+        data.extend_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(data.len(), TRAILER_SIZE);
+        assert_eq!(data[..8], [0x05, 0x01, 0x00, 0x00, 0x06, 0x00, 0x00, 0x00]);
+
+        let mut rtc = RTC::default();
+        assert!(rtc.load(&data));
+        assert_eq!(rtc.live, [0x05, 0x06, 0x07, 0x08, 0x81]);
+        assert_eq!(rtc.latched, [0x01, 0x02, 0x03, 0x04, 0x40]);
+        assert_eq!(rtc.days(), 0x108);
+
+        //the 32 bit timestamp of older emulators
+        let mut data = trailer([0x05, 0x06, 0x07, 0x08, 0x01], [0; 5]);
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(data.len(), SHORT_TRAILER_SIZE);
+
+        let mut rtc = RTC::default();
+        assert!(rtc.load(&data));
+        assert_eq!(rtc.live, [0x05, 0x06, 0x07, 0x08, 0x01]);
+
+        assert!(!rtc.load(&data[1..]));
+        assert!(!rtc.load(&[0; TRAILER_SIZE + 1]));
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        //halted, so the seconds between save and load don't count
+        let mut rtc = at(12, 34, 56, 0x123);
+        rtc.live[DAY_HIGH] |= 0x40;
+        rtc.latch(0);
+        rtc.latch(1);
+        rtc.write(0x08, 0x11);
+
+        let data = rtc.save();
+        assert_eq!(data.len(), TRAILER_SIZE);
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&data[40..]);
+        assert!(now() - u64::from_le_bytes(timestamp) < 5);
+
+        let mut loaded = RTC::default();
+        assert!(loaded.load(&data));
+        assert_eq!(loaded.live, rtc.live);
+        assert_eq!(loaded.latched, rtc.latched);
+        assert_eq!(loaded.live[SECONDS], 0x11);
+        assert_eq!(loaded.latched[SECONDS], 56);
+    }
+
+    #[test]
+    fn latching_takes_a_0_then_a_1() {
+        let mut rtc = at(1, 2, 3, 4);
+
+        rtc.latch(1);
+        assert_eq!(rtc.read(0x08), 0);
+
+        rtc.latch(0);
+        rtc.latch(1);
+        assert_eq!([rtc.read(0x08), rtc.read(0x09), rtc.read(0x0A), rtc.read(0x0B)], [3, 2, 1, 4]);
+
+        //the latched registers stay put until the next 0 then 1
+        rtc.write(0x08, 30);
+        rtc.latch(1);
+        assert_eq!(rtc.read(0x08), 3);
+        rtc.latch(0);
+        rtc.latch(2);
+        rtc.latch(1);
+        assert_eq!(rtc.read(0x08), 3);
+        rtc.latch(0);
+        rtc.latch(1);
+        assert_eq!(rtc.read(0x08), 30);
+    }
+
+    #[test]
+    fn halted_clock_stands_still() {
+        let mut rtc = at(0, 0, 10, 0);
+        rtc.write(0x0C, 0x40);
+
+        for _ in 0..CYCLES_PER_SECOND / 4 {
+            rtc.step(4);
+        }
+        rtc.advance(1000);
+        assert_eq!(rtc.live[SECONDS], 10);
+
+        rtc.write(0x0C, 0x00);
+        for _ in 0..CYCLES_PER_SECOND / 4 {
+            rtc.step(4);
+        }
+        assert_eq!(rtc.live[SECONDS], 11);
+    }
+
+    #[test]
+    fn days_carry_into_bit_8_and_overflow() {
+        let mut rtc = at(23, 59, 59, 0xFF);
+        rtc.tick();
+        assert_eq!(rtc.live, [0, 0, 0, 0x00, 0x01]);
+        assert_eq!(rtc.days(), 0x100);
+
+        let mut rtc = at(23, 59, 59, 0x1FF);
+        rtc.tick();
+        assert_eq!(rtc.live, [0, 0, 0, 0x00, 0x80]);
+
+        //the carry flag stays set until it's written
+        rtc.tick();
+        assert_eq!(rtc.live[DAY_HIGH], 0x80);
+        rtc.write(0x0C, 0x00);
+        assert_eq!(rtc.live[DAY_HIGH], 0x00);
+    }
+
+    #[test]
+    fn advance_catches_up_on_wall_time() {
+        //1 day, 1 hour, 1 minute and 1 second
+        let mut rtc = at(0, 0, 30, 0);
+        rtc.advance(90061);
+        assert_eq!(rtc.live, [31, 1, 1, 1, 0]);
+
+        let mut rtc = at(12, 0, 0, 0x1FF);
+        rtc.advance(86400);
+        assert_eq!(rtc.live, [0, 0, 12, 0, 0x80]);
+
+        //an invalid second wraps to 0 without carrying before the rest is added
+        let mut rtc = at(0, 0, 63, 0);
+        rtc.advance(2);
+        assert_eq!(rtc.live, [1, 0, 0, 0, 0]);
+    }
+}
@@ -11,6 +11,7 @@ pub use instructions::Instruction;
 use super::bit_utils::{*};
 
 mod decoder;
+pub mod disassembler;
 use decoder::{*};
 
 #[derive(Default)]
@@ -75,6 +76,9 @@ impl CPU {
 
             bus.interrupts.master = false;
 
+            //dispatch waits two M-cycles before pushing PC, the CALL below spends the second one
+            bus.tick();
+
             match vec {
                 InterruptVector::VBlank => {
                     bus.interrupts.requests.reset_bit(0);
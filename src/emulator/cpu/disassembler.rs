@@ -0,0 +1,132 @@
+use super::CPU;
+
+//text of the instruction in `bytes` (read from addr onwards) with its operands filled in, and its length in bytes
+pub fn disassemble(bytes: [u8; 3], addr: u16) -> (String, u16) {
+    let opcode = bytes[0];
+
+    if opcode == 0xCB {
+        let instruction = CPU::decode(bytes[1], true);
+        return (instruction.disassembly.to_string(), 2);
+    }
+
+    let instruction = CPU::decode(opcode, false);
+    let length = 1 + instruction.args as u16;
+
+    if instruction.disassembly == "REMOVED" {
+        return (format!("DB ${:02X}", opcode), 1);
+    }
+
+    let byte = bytes[1];
+    let short = bytes[1] as u16 | (bytes[2] as u16) << 8; //>
+
+    let text = match instruction.disassembly.split_once(' ') {
+        Some((mnemonic, operands)) => {
+            let operands: Vec<String> = operands.split(',')
+                .map(|operand| format_operand(instruction.disassembly, operand, byte, short, addr.wrapping_add(length)))
+                .collect();
+
+            format!("{} {}", mnemonic, operands.join(","))
+        },
+        None => instruction.disassembly.to_string()
+    };
+
+    (text, length)
+}
+
+//replaces the n, nn and d placeholders of the decoder tables
+fn format_operand(template: &str, operand: &str, byte: u8, short: u16, next: u16) -> String {
+    let signed = |offset: u8| {
+        let offset = offset as i8;
+        if offset < 0 { format!("-${:02X}", offset.unsigned_abs()) } else { format!("+${:02X}", offset) }
+    };
+
+    match operand {
+        "nn" => format!("${:04X}", short),
+        "(nn)" => format!("(${:04X})", short),
+        //relative jumps show the address they go to
+        "n" if template.starts_with("JR") => format!("${:04X}", next.wrapping_add(byte as i8 as u16)),
+        "n" if template == "ADD SP,n" => signed(byte),
+        "d" => signed(byte),
+        "n" => format!("${:02X}", byte),
+        "(n)" => format!("(${:04X})", 0xFF00 | byte as u16),
+        _ => operand.to_string()
+    }
+}
+
+//where a jump, call or rst goes, None for every other instruction (and JP (HL), which can't be known)
+pub fn jump_target(bytes: [u8; 3], addr: u16) -> Option<u16> {
+    let short = bytes[1] as u16 | (bytes[2] as u16) << 8; //>
+
+    match bytes[0] {
+        0xC3 | 0xC2 | 0xCA | 0xD2 | 0xDA | 0xCD | 0xC4 | 0xCC | 0xD4 | 0xDC => Some(short),
+        0x18 | 0x20 | 0x28 | 0x30 | 0x38 => Some(addr.wrapping_add(2).wrapping_add(bytes[1] as i8 as u16)),
+        opcode if opcode & 0xC7 == 0xC7 => Some((opcode & 0x38) as u16),
+        _ => None
+    }
+}
+
+//the address an instruction reads or writes through an immediate operand, like LD A,($C000) or LDH ($FF80),A
+pub fn memory_operand(bytes: [u8; 3]) -> Option<u16> {
+    match bytes[0] {
+        0x08 | 0xEA | 0xFA => Some(bytes[1] as u16 | (bytes[2] as u16) << 8), //>
+        0xE0 | 0xF0 => Some(0xFF00 | bytes[1] as u16),
+        _ => None
+    }
+}
+
+//replace the address operand of the instruction (jump target or memory operand) with its label, if `label` knows one
+pub fn with_label<F: Fn(u16) -> Option<String>>(text: String, bytes: [u8; 3], addr: u16, label: F) -> String {
+    let operand = match jump_target(bytes, addr).or_else(|| memory_operand(bytes)) {
+        Some(operand) => operand,
+        None => return text
+    };
+
+    match label(operand) {
+        Some(name) => text.replace(&format!("${:04X}", operand), &name),
+        None => text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relative_jumps_show_their_target() {
+        assert_eq!(disassemble([0x18, 0xFE, 0x00], 0x0150), ("JR $0150".to_string(), 2));
+        assert_eq!(disassemble([0x20, 0x10, 0x00], 0x0150), ("JR NZ,$0162".to_string(), 2));
+        assert_eq!(disassemble([0x38, 0x80, 0x00], 0x0000), ("JR C,$FF82".to_string(), 2));
+    }
+
+    #[test]
+    fn high_page_operands_are_full_addresses() {
+        assert_eq!(disassemble([0xE0, 0x40, 0x00], 0x0150), ("LDH ($FF40),A".to_string(), 2));
+        assert_eq!(disassemble([0xF0, 0x44, 0x00], 0x0150), ("LDH A,($FF44)".to_string(), 2));
+        assert_eq!(memory_operand([0xE0, 0x80, 0x00]), Some(0xFF80));
+    }
+
+    #[test]
+    fn immediates_and_signed_offsets() {
+        assert_eq!(disassemble([0x3E, 0x42, 0x00], 0x0150), ("LD A,$42".to_string(), 2));
+        assert_eq!(disassemble([0xFA, 0x00, 0xC0], 0x0150), ("LD A,($C000)".to_string(), 3));
+        assert_eq!(disassemble([0xE8, 0xFE, 0x00], 0x0150), ("ADD SP,-$02".to_string(), 2));
+        assert_eq!(disassemble([0xD3, 0x00, 0x00], 0x0150), ("DB $D3".to_string(), 1));
+    }
+
+    #[test]
+    fn cb_prefixed_instructions_are_two_bytes() {
+        assert_eq!(disassemble([0xCB, 0x7C, 0x00], 0x0150), ("BIT 7,H".to_string(), 2));
+        assert_eq!(disassemble([0xCB, 0x37, 0x00], 0x0150), ("SWAP A".to_string(), 2));
+        assert_eq!(disassemble([0xCB, 0xC6, 0x00], 0x0150), ("SET 0,(HL)".to_string(), 2));
+    }
+
+    #[test]
+    fn labels_replace_the_address_operand() {
+        let label = |addr| if addr == 0x0150 { Some("Main".to_string()) } else { None };
+        let (text, _) = disassemble([0x18, 0xFE, 0x00], 0x0150);
+        assert_eq!(with_label(text, [0x18, 0xFE, 0x00], 0x0150, label), "JR Main");
+
+        let (text, _) = disassemble([0xCD, 0x00, 0x20], 0x0150);
+        assert_eq!(with_label(text.clone(), [0xCD, 0x00, 0x20], 0x0150, label), text);
+    }
+}
@@ -8,9 +8,12 @@ impl Instruction {
 //Generic functions
 
     //Stack management functions
+    //PUSH, CALL, RST and interrupts all spend an M-cycle decrementing SP before the high byte is written
     pub fn push_to_stack(registers: &mut Registers, mem: &mut Bus, short: u16){
         let sp: u16 = registers.SP(Action::Read).value();
 
+        mem.tick();
+
         mem.write_byte(sp.wrapping_sub(1), (short >> 8) as u8); 
         mem.write_byte(sp.wrapping_sub(2), short as u8);
 
@@ -34,7 +34,7 @@ impl PartialEq for Instruction {
             let b: fn([u8;2], *mut Registers, *mut Bus) = transmute(other.function as fn([u8;2], &mut Registers, &mut Bus) -> u8);
             
             //compare and return
-            return a == b;
+            return std::ptr::fn_addr_eq(a, b);
         }
     }
 }
@@ -276,7 +276,7 @@ impl Instruction {
         if jump >= 0 {
             registers.PC(Action::Increment(jump as u16));
         } else {
-            registers.PC(Action::Decrement(jump.abs() as u16)); 
+            registers.PC(Action::Decrement(jump.unsigned_abs() as u16)); 
         }
 
         return 12;
@@ -361,7 +361,7 @@ impl Instruction {
             if jump >= 0 {
                 registers.PC(Action::Increment(jump as u16));
             } else {
-                registers.PC(Action::Decrement(jump.abs() as u16)); 
+                registers.PC(Action::Decrement(jump.unsigned_abs() as u16)); 
             }
             return 12;
         }
@@ -473,7 +473,7 @@ impl Instruction {
             if jump >= 0 {
                 registers.PC(Action::Increment(jump as u16));
             } else {
-                registers.PC(Action::Decrement(jump.abs() as u16)); 
+                registers.PC(Action::Decrement(jump.unsigned_abs() as u16)); 
             }
             return 12;
         }
@@ -570,7 +570,7 @@ impl Instruction {
             if jump >= 0 {
                 registers.PC(Action::Increment(jump as u16));
             } else {
-                registers.PC(Action::Decrement(jump.abs() as u16)); 
+                registers.PC(Action::Decrement(jump.unsigned_abs() as u16)); 
             }
             return 12;
         }
@@ -659,7 +659,7 @@ impl Instruction {
             if jump >= 0 {
                 registers.PC(Action::Increment(jump as u16));
             } else {
-                registers.PC(Action::Decrement(jump.abs() as u16)); 
+                registers.PC(Action::Decrement(jump.unsigned_abs() as u16)); 
             }
             return 12;
         }
@@ -2229,6 +2229,8 @@ impl Instruction {
     //0xC0
     pub fn RET_NZ(_operands: [u8; 2], registers: &mut Registers, mem: &mut Bus) -> u8 {
         if !registers.test_flag(ZERO_FLAG) {
+            //the condition is checked in an M-cycle of its own before the return address is read
+            mem.tick();
             let pointer = Instruction::pop_from_stack(registers, mem);
             registers.PC( Action::Write(pointer) );
             return 20;
@@ -2323,6 +2325,8 @@ impl Instruction {
     //0xC8
     pub fn RET_Z(_operands: [u8; 2], registers: &mut Registers, mem: &mut Bus) -> u8 {
         if registers.test_flag(ZERO_FLAG) {
+            //the condition is checked in an M-cycle of its own before the return address is read
+            mem.tick();
             let pointer = Instruction::pop_from_stack(registers, mem);
             registers.PC( Action::Write(pointer) );
             return 20;
@@ -2409,6 +2413,8 @@ impl Instruction {
     //0xD0
     pub fn RET_NC(_operands: [u8; 2], registers: &mut Registers, mem: &mut Bus) -> u8 {
         if !registers.test_flag(CARRY_FLAG) {
+            //the condition is checked in an M-cycle of its own before the return address is read
+            mem.tick();
             let pointer = Instruction::pop_from_stack(registers, mem);
             registers.PC( Action::Write(pointer) );
             return 20;
@@ -2495,6 +2501,8 @@ impl Instruction {
     //0xD8
     pub fn RET_C(_operands: [u8; 2], registers: &mut Registers, mem: &mut Bus) -> u8 {
         if registers.test_flag(CARRY_FLAG) {
+            //the condition is checked in an M-cycle of its own before the return address is read
+            mem.tick();
             let pointer = Instruction::pop_from_stack(registers, mem);
             registers.PC( Action::Write(pointer) );
             return 20;
@@ -2709,7 +2717,7 @@ impl Instruction {
     pub fn POP_AF(_operands: [u8; 2], registers: &mut Registers, mem: &mut Bus) -> u8 {
         
         let mut popped = Instruction::pop_from_stack(registers, mem);
-        popped = popped & 0xFFF0;
+        popped &= 0xFFF0;
         registers.AF( Action::Write(popped) );
         return 12;
     }
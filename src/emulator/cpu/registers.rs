@@ -1,3 +1,5 @@
+use super::super::state::{StateWriter, StateReader, StateError};
+
 const ZERO_FLAG: u8 = 0b10000000;
 const NEGATIVE_FLAG: u8 = 0b01000000;
 const HALFCARRY_FLAG: u8 = 0b00100000;
@@ -19,6 +21,7 @@ impl Default for Register {
         Register{ all: 0x0 }
     }
 }
+#[derive(Default)]
 pub struct Registers{
     AF: Register,
     BC: Register,
@@ -50,16 +53,34 @@ impl std::fmt::Display for Registers {
 }
 
 
-impl Default for Registers {
-    fn default() -> Registers{
-        Registers{ 
-            AF: Register::default(),
-            BC: Register::default(),
-            DE: Register::default(),
-            HL: Register::default(),
-            SP: 0,
-            PC: 0x0
-        }
+//registers as named by debugging tools
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CpuRegister {
+    A, F, B, C, D, E, H, L,
+    AF, BC, DE, HL, SP, PC,
+}
+
+impl CpuRegister {
+    pub fn from_name(name: &str) -> Option<CpuRegister> {
+        let register = match name.to_ascii_uppercase().as_str() {
+            "A" => CpuRegister::A,
+            "F" => CpuRegister::F,
+            "B" => CpuRegister::B,
+            "C" => CpuRegister::C,
+            "D" => CpuRegister::D,
+            "E" => CpuRegister::E,
+            "H" => CpuRegister::H,
+            "L" => CpuRegister::L,
+            "AF" => CpuRegister::AF,
+            "BC" => CpuRegister::BC,
+            "DE" => CpuRegister::DE,
+            "HL" => CpuRegister::HL,
+            "SP" => CpuRegister::SP,
+            "PC" => CpuRegister::PC,
+            _ => return None
+        };
+
+        Some(register)
     }
 }
 
@@ -98,6 +119,80 @@ impl Value<u16> for Response {
 }
 
 impl Registers {
+    //read only access for debugging, without going through Action
+    pub fn get(&self, register: CpuRegister) -> u16 {
+        unsafe {
+            match register {
+                CpuRegister::A => self.AF.Pair.msb as u16,
+                CpuRegister::F => self.AF.Pair.lsb as u16,
+                CpuRegister::B => self.BC.Pair.msb as u16,
+                CpuRegister::C => self.BC.Pair.lsb as u16,
+                CpuRegister::D => self.DE.Pair.msb as u16,
+                CpuRegister::E => self.DE.Pair.lsb as u16,
+                CpuRegister::H => self.HL.Pair.msb as u16,
+                CpuRegister::L => self.HL.Pair.lsb as u16,
+                CpuRegister::AF => self.AF.all,
+                CpuRegister::BC => self.BC.all,
+                CpuRegister::DE => self.DE.all,
+                CpuRegister::HL => self.HL.all,
+                CpuRegister::SP => self.SP,
+                CpuRegister::PC => self.PC,
+            }
+        }
+    }
+
+    //write access for debugging tools. The low nibble of F doesn't exist and is always 0
+    pub fn set(&mut self, register: CpuRegister, value: u16) {
+        let byte = value as u8;
+
+        match register {
+            CpuRegister::A => self.AF.Pair.msb = byte,
+            CpuRegister::F => self.AF.Pair.lsb = byte & 0xF0,
+            CpuRegister::B => self.BC.Pair.msb = byte,
+            CpuRegister::C => self.BC.Pair.lsb = byte,
+            CpuRegister::D => self.DE.Pair.msb = byte,
+            CpuRegister::E => self.DE.Pair.lsb = byte,
+            CpuRegister::H => self.HL.Pair.msb = byte,
+            CpuRegister::L => self.HL.Pair.lsb = byte,
+            CpuRegister::AF => self.AF.all = value & 0xFFF0,
+            CpuRegister::BC => self.BC.all = value,
+            CpuRegister::DE => self.DE.all = value,
+            CpuRegister::HL => self.HL.all = value,
+            CpuRegister::SP => self.SP = value,
+            CpuRegister::PC => self.PC = value,
+        }
+    }
+
+    //flags as ZNHC, - when reset
+    pub fn flags(&self) -> String {
+        let f = unsafe { self.AF.Pair.lsb };
+
+        [(ZERO_FLAG, 'Z'), (NEGATIVE_FLAG, 'N'), (HALFCARRY_FLAG, 'H'), (CARRY_FLAG, 'C')].iter()
+            .map(|(mask, name)| if f & mask == *mask { *name } else { '-' })
+            .collect()
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        unsafe {
+            state.u16(self.AF.all);
+            state.u16(self.BC.all);
+            state.u16(self.DE.all);
+            state.u16(self.HL.all);
+        }
+        state.u16(self.SP);
+        state.u16(self.PC);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.AF.all = state.u16()?;
+        self.BC.all = state.u16()?;
+        self.DE.all = state.u16()?;
+        self.HL.all = state.u16()?;
+        self.SP = state.u16()?;
+        self.PC = state.u16()?;
+        Ok(())
+    }
+
     pub fn AF(&mut self, wr: Action) -> Response {
         match wr {
             Action::Write(x) => {
@@ -790,7 +790,7 @@ impl Instruction {
         let val = mem.read_byte(dHL).value();
 
         Instruction::BIT(val, 0, registers);
-        return 16;
+        return 12;
     }
 
     //0xCB 0x47
@@ -849,7 +849,7 @@ impl Instruction {
         let val = mem.read_byte(dHL).value();
 
         Instruction::BIT(val, 1, registers);
-        return 16;
+        return 12;
     }
 
     //0xCB 0x4F
@@ -908,7 +908,7 @@ impl Instruction {
         let val = mem.read_byte(dHL).value();
 
         Instruction::BIT(val, 2, registers);
-        return 16;
+        return 12;
     }
 
     //0xCB 0x57
@@ -967,7 +967,7 @@ impl Instruction {
         let val = mem.read_byte(dHL).value();
 
         Instruction::BIT(val, 3, registers);
-        return 16;
+        return 12;
     }
 
     //0xCB 0x5F
@@ -1026,7 +1026,7 @@ impl Instruction {
         let val = mem.read_byte(dHL).value();
 
         Instruction::BIT(val, 4, registers);
-        return 16;
+        return 12;
     }
 
     //0xCB 0x67
@@ -1086,7 +1086,7 @@ impl Instruction {
         let val = mem.read_byte(dHL).value();
 
         Instruction::BIT(val, 5, registers);
-        return 16;
+        return 12;
     }
 
     //0xCB 0x6F
@@ -1145,7 +1145,7 @@ impl Instruction {
         let val = mem.read_byte(dHL).value();
 
         Instruction::BIT(val, 6, registers);
-        return 16;
+        return 12;
     }
 
     //0xCB 0x77
@@ -1204,7 +1204,7 @@ impl Instruction {
         let val = mem.read_byte(dHL).value();
 
         Instruction::BIT(val, 7, registers);
-        return 16;
+        return 12;
     }
 
     //0xCB 0x7F
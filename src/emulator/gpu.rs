@@ -3,12 +3,16 @@
 use super::interrupt::{*};
 use super::bit_utils::{*};
 use super::cpu::registers::{Response};
+use super::state::{StateWriter, StateReader, StateError};
+use std::collections::VecDeque;
 
-const OAM_SEARCH: usize = 80;
-const TRANSFER_CYCLES: usize = 252;
-const HBLANK_CYCLES: usize = 456;
-const FRAME_CYCLES: usize = 456 * 146;
-const VBLANK_CYCLES: usize = FRAME_CYCLES + 456 * 10;
+//mode 2 takes the first OAM_SEARCH dots of a line, mode 3 as long as drawing takes and mode 0 the rest
+const OAM_SEARCH: u16 = 80;
+const LINE_DOTS: u16 = 456;
+const VISIBLE_LINES: u8 = 144;
+const LINES: u8 = 154;
+//the fetcher spends 2 dots on each of tile number, low byte and high byte
+const FETCH_DOTS: u8 = 6;
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum Mode {
@@ -18,16 +22,6 @@ pub enum Mode {
     Transfer = 3
 }
 
-#[derive(Copy, Clone)]
-struct Tile {
-    dirty: bool,
-    data: [u8; 16]
-}
-
-impl Default for Tile {
-    fn default() -> Self { Tile { dirty: true, data: [0; 16]} }
-}
-
 #[derive(Copy, Clone, Debug)]
 struct Sprite{
     dirty: bool,
@@ -46,16 +40,76 @@ impl Default for Sprite {
     }
 }
 
+//a pixel waiting in the sprite FIFO
+#[derive(Copy, Clone, Default)]
+struct SpritePixel {
+    color: u8,
+    palette: bool,
+    priority: bool,                         //behind background colors 1-3
+}
+
+//fetches a row of 8 background or window pixels for the FIFO
+#[derive(Copy, Clone, Default)]
+struct Fetcher {
+    dot: u8,                                //of the fetch in progress, 0-5
+    x: u8,                                  //tile column, from the start of the line or of the window
+    window: bool,
+    first: bool,                            //the first fetch of a line is thrown away
+    ready: bool,                            //a row was fetched and waits for the FIFO to empty
+    tile: u8,
+    low: u8,
+    high: u8,
+}
+
+impl Fetcher {
+    fn save_state(&self, state: &mut StateWriter) {
+        for value in [self.dot, self.x, self.tile, self.low, self.high].iter() {
+            state.u8(*value);
+        }
+        for flag in [self.window, self.first, self.ready].iter() {
+            state.bool(*flag);
+        }
+    }
+
+    fn load_state(state: &mut StateReader) -> Result<Self, StateError> {
+        Ok(Fetcher {
+            dot: state.u8()?,
+            x: state.u8()?,
+            tile: state.u8()?,
+            low: state.u8()?,
+            high: state.u8()?,
+            window: state.bool()?,
+            first: state.bool()?,
+            ready: state.bool()?,
+        })
+    }
+}
+
 pub struct GPU {
     pub mode: Mode,
-    scanline_cycles: usize,
-    frame_cycles: usize,
+    dot: u16,                               //of the current line, 0-455
+    line: u8,                               //being drawn, LY only differs from it on line 153
+    compare_ly: Option<u8>,                 //what LYC is compared to, nothing for a few dots after LY changes
+    stat_line: bool,                        //the STAT interrupt line, high while any enabled source is
 
     sprites: [Sprite; 40],
-    tile_cache: [Tile; 384],
 
     skip_frame: bool,
 
+    //the line being drawn in mode 3
+    lx: u8,                                 //pixels sent to the LCD
+    discard: u8,                            //pixels still to drop from the start of the line for SCX
+    bg_fifo: VecDeque<u8>,
+    sprite_fifo: VecDeque<SpritePixel>,
+    fetcher: Fetcher,
+    line_sprites: Vec<(usize, bool)>,       //OAM index of the sprites on this line and whether they were fetched
+    sprite_fetch: Option<(usize, u8)>,      //sprite being fetched and the dots it has left
+
+    //the window keeps its own line count, it only moves on lines where the window was drawn
+    window_line: u8,
+    window_y_hit: bool,                     //LY matched WY at the start of a line this frame
+    window_wrap: bool,                      //the window started at WX=166, it covers the whole next line
+
     pub LCDC: u8,           //0xFF40     (R/W)
     pub STAT: u8,           //0xFF41     (R/W)
     pub scroll_y: u8,       //0xFF42     (R/W)
@@ -68,10 +122,6 @@ pub struct GPU {
     pub bg_palette: u8,     //0xFF47     (R/W)
     pub ob_palette0: u8,    //0xFF48     (R/W)
     pub ob_palette1: u8,    //0xFF49     (R/W)
-    pub bgp_index: u8,      //0xFF68     (R/W) (GB Color only)
-    pub bgp_data: u8,       //0xFF69     (R/W) (GB Color only)
-    pub spt_index: u8,      //0xFF6A     (R/W) (GB Color only)   
-    pub spt_data: u8,       //0xFF6B     (R/W) (GB Color only)
     vram: [u8;0x2000],
     oam: [u8; 0xA0],
     pub display: Vec<u32>
@@ -81,11 +131,22 @@ impl Default for GPU {
     fn default() -> GPU{
         GPU {
             mode: Mode::Oam,
-            scanline_cycles: 0,
-            frame_cycles: 0,
+            dot: 0,
+            line: 0,
+            compare_ly: Some(0),
+            stat_line: false,
             skip_frame: true,
             sprites: [Sprite::default(); 40],
-            tile_cache: [Tile::default(); 384],
+            lx: 0,
+            discard: 0,
+            bg_fifo: VecDeque::with_capacity(16),
+            sprite_fifo: VecDeque::with_capacity(8),
+            fetcher: Fetcher::default(),
+            line_sprites: Vec::with_capacity(10),
+            sprite_fetch: None,
+            window_line: 0,
+            window_y_hit: false,
+            window_wrap: false,
             LCDC: 0,           //0xFF40     (R/W)
             STAT: 0x82,           //0xFF41     (R/W)
             scroll_y: 0,       //0xFF42     (R/W)
@@ -98,13 +159,9 @@ impl Default for GPU {
             bg_palette: 0,     //0xFF47     (R/W)
             ob_palette0: 0,    //0xFF48     (R/W)
             ob_palette1: 0,    //0xFF49     (R/W)
-            bgp_index: 0,      //0xFF68     (R/W) (GB Color only)
-            bgp_data: 0,       //0xFF69     (R/W) (GB Color only)
-            spt_index: 0,      //0xFF6A     (R/W) (GB Color only)   
-            spt_data: 0,       //0xFF6B     (R/W) (GB Color only)
             vram: [0; 0x2000],
             oam: [0; 0xA0],
-            display: vec![0; 160*146]
+            display: vec![0; 160*144]
         }
     }
 }
@@ -117,380 +174,328 @@ enum Region {
 impl GPU {
     pub fn step(&mut self, cycles_made: u8, interrupt_handler: &mut InterruptHandler, screen: &mut Vec<u32>){
         //check if display is enabled
-        if self.enabled() {
-            //save the current mode
-            let cur_mode = self.mode;
-
-            //sync the internal cycles
-            self.scanline_cycles += cycles_made as usize;
-            self.frame_cycles += cycles_made as usize;
-            
-            //flag for interrupt request
-            let mut interrupt_status = false;
-            
-            //if frame_cycles is bigger than 65664 it's VBLANK period
-            if self.frame_cycles >= FRAME_CYCLES {
-                //cur_mode is not equal to VBlank so change it
-                if cur_mode != Mode::VBlank {
-                    self.set_mode(Mode::VBlank);
-                    interrupt_handler.request(Interrupt::VBlank);
-                    *screen = self.display.clone();
-                    //update interrupt flag
-                    interrupt_status = self.STAT.test_bit(4);
+        if !self.enabled() { return; }
 
-                }
-                //frame_cycles are bigger than the Vblank period, reset everything
-                if self.frame_cycles > VBLANK_CYCLES {
-                    self.skip_frame = false;
-                    self.frame_cycles = 0;
-                    self.scanline_cycles = 0;
-                    self.lcd_y = 0;
-                    //compare LY to LYC
-                    self.line_compare(interrupt_handler);
+        for _ in 0..cycles_made {
+            self.step_dot(interrupt_handler, screen);
+        }
+    }
+
+    fn step_dot(&mut self, interrupt_handler: &mut InterruptHandler, screen: &mut Vec<u32>) {
+        if self.line < VISIBLE_LINES {
+            match self.dot {
+                0 => {
                     self.set_mode(Mode::Oam);
-                }
-            } else {
-                //it's not vblank so test scanline cycles
-                match self.scanline_cycles {
-                    0 ..= OAM_SEARCH  => {
-                        //OAM period
-                        if cur_mode != Mode::Oam {
-                            self.set_mode(Mode::Oam);
-                            interrupt_status = self.STAT.test_bit(5);
-                        }
-                    },
-                    OAM_SEARCH ..= TRANSFER_CYCLES => {
-                        //Transfer period
-                        if cur_mode != Mode::Transfer {
-                            self.set_mode(Mode::Transfer);
-                            if !self.skip_frame {
-                                self.draw();
-                            }
-                        }
-                    },
-                    TRANSFER_CYCLES ..= HBLANK_CYCLES => {
-                        if cur_mode != Mode::HBlank {
-                            self.set_mode(Mode::HBlank);
-                            interrupt_status = self.STAT.test_bit(3);
-                        }
-
-                    },
-
-                    _ => {}
-                }
+
+                    if self.line == self.window_y {
+                        self.window_y_hit = true;
+                    }
+                },
+                OAM_SEARCH => self.start_transfer(),
+                _ => {}
             }
 
-            if interrupt_status {
-                interrupt_handler.request(Interrupt::LCDC);
+            if self.mode == Mode::Transfer {
+                self.transfer_dot();
             }
+        }
 
-            if self.scanline_cycles > HBLANK_CYCLES {
-                self.lcd_y += 1;
-                self.scanline_cycles = 0;
-                self.line_compare(interrupt_handler);
+        self.update_compare();
+        self.update_stat_line(self.STAT, interrupt_handler);
+
+        self.dot += 1;
+
+        if self.dot == LINE_DOTS {
+            if self.line < VISIBLE_LINES && self.fetcher.window {
+                self.window_line = self.window_line.wrapping_add(1);
             }
 
+            self.dot = 0;
+            self.line += 1;
+
+            if self.line == VISIBLE_LINES {
+                self.set_mode(Mode::VBlank);
+                interrupt_handler.request(Interrupt::VBlank);
+                *screen = self.display.clone();
+            }
+
+            if self.line == LINES {
+                self.line = 0;
+                self.skip_frame = false;
+                self.window_line = 0;
+                self.window_y_hit = false;
+            }
+
+            //LY was 0 since the start of line 153 already
+            if self.line != 0 {
+                self.lcd_y = self.line;
+                self.compare_ly = None;
+            }
         }
     }
 
-    fn line_compare(&mut self, interrupt: &mut InterruptHandler){
-        if self.lycompare == self.lcd_y {
+    //LY=LYC is checked against the previous line's LY until 4 dots into the line, and against nothing in between.
+    //Line 153 shows LY=153 for 4 dots only, then LY=0 (compared from its 12th dot) for the rest of it and line 0
+    fn update_compare(&mut self) {
+        match (self.line, self.dot) {
+            (153, 4) => {
+                self.lcd_y = 0;
+                self.compare_ly = Some(153);
+            },
+            (153, 8) => self.compare_ly = None,
+            (153, 12) => self.compare_ly = Some(0),
+            (_, 4) => self.compare_ly = Some(self.lcd_y),
+            _ => {}
+        }
+
+        if self.compare_ly == Some(self.lycompare) {
             self.STAT.set_bit(2);
-            if self.STAT.test_bit(6) {
-                interrupt.request(Interrupt::LCDC)
-            }
         } else {
             self.STAT.reset_bit(2);
         }
+    }
 
+    //All STAT sources share one interrupt line, only its rising edge requests an interrupt, so a source going
+    //active while another one already is (a mode 2 right after a mode 0 for example) doesn't fire again.
+    //The mode 2 source also sees the start of line 144
+    fn update_stat_line(&mut self, stat: u8, interrupt_handler: &mut InterruptHandler) {
+        let line = (stat.test_bit(3) && self.mode == Mode::HBlank)
+            || (stat.test_bit(4) && self.mode == Mode::VBlank)
+            || (stat.test_bit(5) && (self.mode == Mode::Oam || (self.line == VISIBLE_LINES && self.dot == 0)))
+            || (stat.test_bit(6) && self.STAT.test_bit(2));
+
+        if line && !self.stat_line {
+            interrupt_handler.request(Interrupt::LCDC);
+        }
+        self.stat_line = line;
     }
 
     pub fn enabled(&self) -> bool{
         self.LCDC.test_bit(7)
     }
 
-    fn draw(&mut self){
+    pub fn vram_locked(&self) -> bool {
+        self.mode == Mode::Transfer
+    }
 
-        let mut priority = vec![false; 160];
+    pub fn oam_locked(&self) -> bool {
+        self.mode == Mode::Oam || self.mode == Mode::Transfer
+    }
 
-        if self.LCDC.test_bit(0) {
-            self.paint_background(&mut priority);
-        }
+    //mode 3 begins: the FIFOs start empty and the fetcher at the left of the background
+    fn start_transfer(&mut self) {
+        self.set_mode(Mode::Transfer);
+
+        self.lx = 0;
+        self.discard = self.scroll_x & 7;
+        self.bg_fifo.clear();
+        self.sprite_fifo.clear();
+        self.fetcher = Fetcher { first: true, ..Fetcher::default() };
+        self.sprite_fetch = None;
 
-        if self.LCDC.test_bit(5) && self.LCDC.test_bit(0) {
-            //draw window
-            self.paint_window(&mut priority)
+        if self.window_wrap && self.window_enabled() {
+            self.fetcher.window = true;
         }
+        self.window_wrap = false;
+
+        self.line_sprites.clear();
         if self.LCDC.test_bit(1) {
-            //search the visible sprites
             let visible = self.search_oam();
-            //draw sprite
-            self.paint_sprites(visible, &mut priority);
+            self.line_sprites.extend(visible.into_iter().map(|index| (index, false)));
         }
     }
 
-    fn paint_background(&mut self, priority: &mut Vec<bool>){
-        //draw bg
-
-        //get palette
-        let palette = self.bg_palette;
-
-        //tile map address base
-        let tile_map_addr = match self.LCDC.test_bit(3) {
-            true => 0x9C00,
-            false => 0x9800
-        };
-        
-        //tile data address base
-        let tile_data_addr = match self.LCDC.test_bit(4) {
-            true => 0x8000,
-            false => 0x9000
-        };
-
-        //Current line (Y axis)
-        let LY = self.lcd_y;
-
-        //Apply scroll effect, if any
-        let Y = LY.wrapping_add(self.scroll_y);
-
-        //Set buffer position (to display) as current line times 160 (screen width)
-        let buffer: u32 = LY as u32 * 160;
-        
-        //Current row in a tile
-        let row = Y / 8;
-
-        //For each pixel in a line
-        for i in 0..160 {
-            //Apply scroll effect, if any
-            let X = (i as u8).wrapping_add(self.scroll_x);
-
-            //Current column in a tile
-            let column = X / 8;
-
-
-            // Upper 5 bits select the row, 5 first the column
-            let index = ((row as u16) << 5) + column as u16; //> 
-
-            let tile_index = tile_map_addr + index;
-
-            let tile_pattern: u8 = self.vram[(tile_index - 0x8000) as usize];
-
-            let raw_address = match self.LCDC.test_bit(4) {
-                true => {
-                    //8800-97FF (unsigned)
-                    (tile_pattern as u16 * 16) + tile_data_addr - 0x8000
-                },
-                false => {
-                    //8800-97FF (signed)
-                    let adjusted = ((tile_pattern as i8) as i16) * 16;
-                    let path = (tile_data_addr as i16) + adjusted;
-                    (path as u16) - 0x8000
-                },
-            };
-
-            let id = (raw_address / 16) as usize;
-
-            if self.tile_cache[id].dirty {
-                self.update_tile(id, raw_address);
+    //one dot of mode 3: a pixel goes out unless the FIFO is empty or a sprite is being fetched, then the fetcher works
+    fn transfer_dot(&mut self) {
+        if let Some((index, dots)) = self.sprite_fetch {
+            if dots > 1 {
+                self.sprite_fetch = Some((index, dots - 1));
+            } else {
+                self.sprite_fetch = None;
+                self.fetch_sprite(index);
             }
+            return;
+        }
 
-            let cache = &self.tile_cache[ id ];
-
-            let py = ((Y % 8) * 2) as u16;
-            let px = X % 8;
-
-            let mut t1 = cache.data[py as usize];    
-            let mut t2 = cache.data[(py+1) as usize];
-
-            t1 = GPU::reverse_order(t1);
-            t2 = GPU::reverse_order(t2);
-            
-            let b1 = t2.test_bit(px);   
-            let b0 = t1.test_bit(px);
+        if self.window_starts() {
+            self.bg_fifo.clear();
+            self.fetcher = Fetcher { window: true, ..Fetcher::default() };
 
-            let pixel = (b1 as u8) << 1 | b0 as u8; //>
-            
+            //WX below 7 puts the window's left edge off screen, those pixels are dropped instead
+            self.discard = 7u8.saturating_sub(self.window_x);
+        }
 
-            let drawn = self.to_rgb(pixel, palette);
+        //WX=166 matches at the last pixel, the window then also takes the whole next line (even if it's drawn already)
+        if self.window_x == 166 && self.lx == 159 && self.window_enabled() {
+            self.window_wrap = true;
+        }
 
-            priority[i] = pixel != 0;
+        //a sprite at this position waits for the fetcher to have a row ready and the FIFO to have pixels to mix with.
+        //Sprites are fetched lowest X first, then lowest OAM index, and the first one fetched wins where they overlap
+        //A sprite at X=0 has nothing to show and doesn't wait for the fine scroll to be dropped
+        if self.LCDC.test_bit(1) {
+            let lx = self.lx as i16;
+            let pending = self.line_sprites.iter().enumerate()
+                .filter(|(_, &(index, fetched))| {
+                    let x = self.sprites[index].x;
+                    !fetched && x.max(0) == lx && (self.discard == 0 || x == -8)
+                })
+                .min_by_key(|(_, &(index, _))| (self.sprites[index].x, index))
+                .map(|(position, _)| position);
+
+            if let Some(position) = pending {
+                if !self.fetcher.ready || self.bg_fifo.is_empty() {
+                    self.fetch_dot();
+                }
 
-            self.display[(buffer + i as u32) as usize] = drawn;
+                //the sprite fetch starts on the dot the fetcher reads its high byte and takes FETCH_DOTS with it
+                if self.fetcher.ready && !self.bg_fifo.is_empty() {
+                    self.line_sprites[position].1 = true;
+                    self.sprite_fetch = Some((self.line_sprites[position].0, FETCH_DOTS - 1));
+                }
+                return;
+            }
         }
-    }
-
-    
-    fn paint_window(&mut self, priority: &mut Vec<bool>){
 
-        let palette = self.bg_palette;
+        self.output_pixel();
+        self.fetch_dot();
+    }
 
-        let WY = self.window_y;
+    fn window_enabled(&self) -> bool {
+        self.LCDC.test_bit(5) && self.LCDC.test_bit(0) && self.window_y_hit
+    }
 
-        let LY = self.lcd_y;
+    //the window starts where WX-7 is reached, or at the left edge for WX below 7
+    fn window_starts(&self) -> bool {
+        !self.fetcher.window && self.window_enabled() && self.lx + 7 == self.window_x.max(7)
+    }
 
-        //window does not appear in this row
-        if LY < WY || WY > 143 { return (); }
-        // if WX > 159 { return (); }
-        //tile map address base
-        let tile_map_addr = match self.LCDC.test_bit(6) {
-            true => 0x9C00,
-            false => 0x9800
+    fn output_pixel(&mut self) {
+        let color = match self.bg_fifo.pop_front() {
+            Some(color) => color,
+            None => return
         };
-        
-        //tile data address base
-        let tile_data_addr = match self.LCDC.test_bit(4) {
-            true => 0x8000,
-            false => 0x9000
-        };
-
 
-        let buffer: u32 = LY as u32 * 160;
+        if self.discard > 0 {
+            self.discard -= 1;
+            return;
+        }
 
-        let row = (LY - WY) / 8;
+        let sprite = self.sprite_fifo.pop_front().unwrap_or_default();
 
-        for i in 0..160 {
+        //with LCDC bit 0 off the background and window are white
+        let color = if self.LCDC.test_bit(0) { color } else { 0 };
 
-            let column = i/8;
+        let drawn = if sprite.color != 0 && self.LCDC.test_bit(1) && !(sprite.priority && color != 0) {
+            let palette = if sprite.palette { self.ob_palette1 } else { self.ob_palette0 };
+            self.to_rgb(sprite.color, palette)
+        } else {
+            self.to_rgb(color, self.bg_palette)
+        };
 
-            // Upper 5 bits select the row, 5 first the column
-            let index = ((row as u16) << 5) + column as u16; //> 
+        if !self.skip_frame {
+            self.display[self.line as usize * 160 + self.lx as usize] = drawn;
+        }
 
-            let tile_index = tile_map_addr + index;
+        self.lx += 1;
 
-            let tile_number: u8 = self.vram[(tile_index - 0x8000) as usize];
+        if self.lx == 160 {
+            self.set_mode(Mode::HBlank);
+        }
+    }
 
-            let raw_address = match self.LCDC.test_bit(4) {
-                true => {
-                    //8800-97FF (unsigned)
-                    (tile_number as u16 * 16) + tile_data_addr - 0x8000
-                },
-                false => {
-                    //8800-97FF (signed)
-                    let adjusted = ((tile_number as i8) as i16) * 16;
-                    let path = (tile_data_addr as i16) + adjusted;
-                    (path as u16) - 0x8000
+    //a dot of the background/window fetcher, the reads happen on the second dot of each step
+    fn fetch_dot(&mut self) {
+        if !self.fetcher.ready {
+            match self.fetcher.dot {
+                1 => self.fetcher.tile = self.vram[self.tile_map_address()],
+                3 => self.fetcher.low = self.vram[self.tile_data_address()],
+                5 => {
+                    self.fetcher.high = self.vram[self.tile_data_address() + 1];
+                    self.fetcher.ready = true;
                 },
-            };
-
-            let id = (raw_address / 16) as usize;
-
-            if self.tile_cache[id].dirty {
-                self.update_tile(id, raw_address);
+                _ => {}
             }
+            self.fetcher.dot += 1;
+        }
 
-            let cache = self.tile_cache[ id ];
-
-            let py = (((LY-WY) % 8) * 2) as u16;
-            let px = i % 8;
-
-            let mut t1 = cache.data[py as usize];    
-            let mut t2 = cache.data[(py+1) as usize];
-
-            t1 = GPU::reverse_order(t1);
-            t2 = GPU::reverse_order(t2);
-            
-            let b1 = t2.test_bit(px);   
-            let b0 = t1.test_bit(px);
-
-            let pixel = (b1 as u8) << 1 | b0 as u8; //>
-            
-
-            let drawn = self.to_rgb(pixel, palette);
-
-            priority[i as usize] = pixel != 0;
+        if self.fetcher.ready && self.bg_fifo.is_empty() {
+            if !self.fetcher.first {
+                for bit in (0..8).rev() {
+                    self.bg_fifo.push_back((self.fetcher.low >> bit) & 1 | ((self.fetcher.high >> bit) & 1) << 1); //>
+                }
+                self.fetcher.x = self.fetcher.x.wrapping_add(1);
+            }
 
-            self.display[(buffer + i as u32) as usize] = drawn;
+            self.fetcher.first = false;
+            self.fetcher.ready = false;
+            self.fetcher.dot = 0;
         }
     }
-    
-    fn paint_sprites(&mut self, visible: Vec<Sprite>, priority: &mut Vec<bool>){
-
-        let ly = self.lcd_y;
-        let tall = self.LCDC.test_bit(2);
-        let buffer = ly as u32 * 160;
-
-        for sprite in visible.iter().rev() {
-            let sx = sprite.x;
-            let sy = sprite.y;
-
-            let y = ly.wrapping_sub(sy as u8) % 8;
-
-            let py = match sprite.y_flip {
-                true =>  { ((y as i8 - 7) * -1) as u8 },
-                false => y
-            };
-
-            let id = match tall {
-                true => {
-                    if ly.wrapping_sub(sy as u8) < 8 {
-                        if sprite.y_flip {
-                            sprite.addr | 1
-                        } else {
-                            sprite.addr & 0xFE
-                        }
-                    } else {
-                        if sprite.y_flip {
-                            sprite.addr & 0xFE
-                        } else {
-                            sprite.addr | 1
-                        }
-                    }
-                },
-                false => sprite.addr
-            };
-            
-
-            if self.tile_cache[id as usize].dirty {
-                self.update_tile(id as usize, id*16);
-            }
 
-            let tile = self.tile_cache[id as usize];
+    //where in vram the number of the tile being fetched is
+    fn tile_map_address(&self) -> usize {
+        let (map_bit, row, column) = match self.fetcher.window {
+            true => (6, self.window_line / 8, self.fetcher.x & 31),
+            false => (3, self.line.wrapping_add(self.scroll_y) / 8, (self.scroll_x / 8).wrapping_add(self.fetcher.x) & 31),
+        };
 
-            let palette = match sprite.palette {
-                true => self.ob_palette1,
-                false => self.ob_palette0,
-            };
+        let map: usize = if self.LCDC.test_bit(map_bit) { 0x1C00 } else { 0x1800 };
+        map + row as usize * 32 + column as usize
+    }
 
-            for i in 0..8 {
-                let actual_x = ((sx + i) as i16) as u8; 
+    //where in vram the low byte of the fetched tile's current row is
+    fn tile_data_address(&self) -> usize {
+        let line = match self.fetcher.window {
+            true => self.window_line % 8,
+            false => self.line.wrapping_add(self.scroll_y) % 8
+        } as usize;
+
+        let tile = match self.LCDC.test_bit(4) {
+            //8000-8FFF (unsigned)
+            true => self.fetcher.tile as usize * 16,
+            //8800-97FF (signed)
+            false => (0x1000 + (self.fetcher.tile as i8) as isize * 16) as usize,
+        };
 
-                if actual_x >= 160 { continue; }
+        tile + line * 2
+    }
 
-                let px = match sprite.x_flip {
-                    true => ((i as i8 - 7) * -1) as u8,
-                    false => i as u8
-                };
+    //put the sprite's pixels in the sprite FIFO, where a pixel of an earlier sprite is already there it stays
+    fn fetch_sprite(&mut self, index: usize) {
+        let sprite = self.sprites[index];
+        let height: i16 = if self.LCDC.test_bit(2) { 16 } else { 8 };
 
-                let mut t1 = tile.data[(py*2) as usize];    
-                let mut t2 = tile.data[(py*2+1) as usize];
-    
-                t1 = GPU::reverse_order(t1);
-                t2 = GPU::reverse_order(t2);
-                
-                let b1 = t2.test_bit(px);   
-                let b0 = t1.test_bit(px);
+        let mut row = self.line as i16 - sprite.y;
+        if sprite.y_flip {
+            row = height - 1 - row;
+        }
 
-                let pixel = (b1 as u8) << 1 | b0 as u8; //>
+        //8x16 sprites ignore bit 0 of the tile number, the row picks the top or bottom tile
+        let tile = if height == 16 { sprite.addr & 0xFE } else { sprite.addr } as usize;
+        let address = tile * 16 + (row as usize & 15) * 2;
+        let (low, high) = (self.vram[address], self.vram[address + 1]);
 
-                if pixel == 0 { continue; }
+        //pixels left of the screen are never shown
+        let hidden = (-sprite.x).max(0) as usize;
 
-                let drawn = self.to_rgb(pixel, palette);
+        for i in hidden..8 {
+            let bit = if sprite.x_flip { i } else { 7 - i };
+            let pixel = SpritePixel {
+                color: (low >> bit) & 1 | ((high >> bit) & 1) << 1, //>
+                palette: sprite.palette,
+                priority: sprite.priority,
+            };
 
-                if sprite.priority && priority[actual_x as usize] { continue; }
-    
-                self.display[(buffer + actual_x as u32) as usize] = drawn;
+            let slot = i - hidden;
+            match self.sprite_fifo.get_mut(slot) {
+                Some(existing) if existing.color == 0 => *existing = pixel,
+                Some(_) => {},
+                None => self.sprite_fifo.push_back(pixel)
             }
-
         }
     }
 
-    fn reverse_order(mut b: u8) -> u8{
-        b = (b & 0xF0) >> 4 | (b & 0x0F) << 4; //>
-        b = (b & 0xCC) >> 2 | (b & 0x33) << 2; //> 
-        b = (b & 0xAA) >> 1 | (b & 0x55) << 1; //>
-        b
-    }
-
     fn to_rgb(&self, pixel: u8, palette: u8) -> u32{
         let colors = [
 			0xE0F8D0, // 0 White
@@ -509,16 +514,6 @@ impl GPU {
 		colors[shade as usize]
     }
 
-    fn update_tile(&mut self, id: usize, raw_addr: u16) {
-        let tile = &mut self.tile_cache[id];
-        
-        for i in (raw_addr..raw_addr+16).enumerate() {
-            tile.data[i.0] = self.vram[i.1 as usize];
-        }
-
-        tile.dirty = false;
-    }
-
     fn update_sprite(&mut self, mut index: usize){
         
         let current = &mut self.sprites[index];
@@ -541,28 +536,30 @@ impl GPU {
         current.dirty = false;
     }
 
-    fn search_oam(&mut self) -> Vec<Sprite> { 
-        let sprite_max: i16 = match self.LCDC.test_bit(2) {
-            true => 15,
-            false => 7
+    //OAM index of the sprites on the current line: the first 10 in OAM order whose rows cover LY.
+    //X isn't looked at, sprites off either side of the screen still use up a slot
+    fn search_oam(&mut self) -> Vec<usize> { 
+        let height: i16 = match self.LCDC.test_bit(2) {
+            true => 16,
+            false => 8
         };
 
-        let mut visible_sprites: Vec<Sprite> = vec![];
+        let mut visible_sprites: Vec<usize> = Vec::with_capacity(10);
 
-        for i in 0..40 {    
-
-            
+        for i in 0..40 {
             if self.sprites[i].dirty {
-                self.update_sprite(i as usize);
+                self.update_sprite(i);
             }
-            
-            let sprite = self.sprites[i];
 
-            if sprite.x + 8 >= 0 && self.lcd_y as i16 >= sprite.y as i16 && self.lcd_y as i16 <= sprite.y as i16 + sprite_max && sprite.x < 160 && visible_sprites.len() < 11 {
-                
-                visible_sprites.push(self.sprites[i]);
-            }
+            let row = self.line as i16 - self.sprites[i].y;
 
+            if (0..height).contains(&row) {
+                visible_sprites.push(i);
+
+                if visible_sprites.len() == 10 {
+                    break;
+                }
+            }
         }
         visible_sprites
     }
@@ -591,14 +588,19 @@ impl GPU {
 
     pub fn write_lcdc(&mut self, byte: u8) {
         if !byte.test_bit(7) && self.enabled() {
-            if self.mode != Mode::VBlank {
-                panic!("Turned LCD off outside of Vblank")
-            }
+            //games are supposed to only do it in VBlank (real LCDs can be damaged otherwise) but any mode works here,
+            //the line being drawn is simply left unfinished.
+            //The interrupt enables stay, and LY=LYC keeps its last value while the LCD is off
             self.lcd_y = 0;
-            self.STAT = 0x80;
+            self.line = 0;
+            self.STAT &= 0xFC;
             self.mode = Mode::HBlank;
-            self.scanline_cycles = 0;
-            self.frame_cycles = 0;
+            self.dot = 0;
+            self.compare_ly = Some(0);
+            self.stat_line = false;
+            self.window_line = 0;
+            self.window_y_hit = false;
+            self.window_wrap = false;
         }
         if byte.test_bit(7) && !self.enabled() {
             if self.lcd_y == self.lycompare { 
@@ -611,10 +613,19 @@ impl GPU {
         self.LCDC = byte
     }
 
-    pub fn write_stat(&mut self, byte: u8) {
+    pub fn write_stat(&mut self, byte: u8, interrupt_handler: &mut InterruptHandler) {
+        //on the DMG every source is enabled for a cycle while STAT is written, which can fire the interrupt
+        if self.enabled() {
+            self.update_stat_line(0xFF, interrupt_handler);
+        }
+
         //only keep bytes 3-6
-        let data = (byte & 0xF8) | 0x80;
+        let data = (byte & 0xF8) | (self.STAT & 0x07) | 0x80;
         self.STAT = data;
+
+        if self.enabled() {
+            self.update_stat_line(self.STAT, interrupt_handler);
+        }
     }
 
     pub fn read_stat(&mut self) -> u8 {
@@ -628,10 +639,6 @@ impl GPU {
         match into {
             Region::VRAM(x) => {
                 self.vram[x] = byte;
-
-                if x < 0x1800 {
-                    self.tile_cache[ (x/16) as usize].dirty = true;
-                }
             }
             Region::OAM(x) => {
                 self.sprites[x/4].dirty = true;
@@ -647,14 +654,121 @@ impl GPU {
 
         match from {
             Region::VRAM(x) => {
-                return Response::Byte( self.vram[x] as u8 );
+                return Response::Byte( self.vram[x] );
             }
             Region::OAM(x) => {
-                return Response::Byte( self.oam[x] as u8 );
+                return Response::Byte( self.oam[x] );
             }
         }
     }
 
+    //the sprite cache isn't saved, it's rebuilt from oam
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.mode as u8);
+        state.u16(self.dot);
+        state.u8(self.line);
+        state.bool(self.compare_ly.is_some());
+        state.u8(self.compare_ly.unwrap_or(0));
+        state.bool(self.stat_line);
+        state.bool(self.skip_frame);
+
+        state.u8(self.lx);
+        state.u8(self.discard);
+        state.u8(self.bg_fifo.len() as u8);
+        for color in self.bg_fifo.iter() {
+            state.u8(*color);
+        }
+        state.u8(self.sprite_fifo.len() as u8);
+        for pixel in self.sprite_fifo.iter() {
+            state.u8(pixel.color);
+            state.bool(pixel.palette);
+            state.bool(pixel.priority);
+        }
+        self.fetcher.save_state(state);
+        state.u8(self.line_sprites.len() as u8);
+        for (index, fetched) in self.line_sprites.iter() {
+            state.u8(*index as u8);
+            state.bool(*fetched);
+        }
+        let (index, dots) = self.sprite_fetch.unwrap_or((0, 0));
+        state.u8(index as u8);
+        state.u8(dots);
+
+        state.u8(self.window_line);
+        state.bool(self.window_y_hit);
+        state.bool(self.window_wrap);
+
+        for register in [self.LCDC, self.STAT, self.scroll_y, self.scroll_x, self.lcd_y, self.lycompare,
+                         self.OAM_DMA, self.window_y, self.window_x, self.bg_palette, self.ob_palette0, self.ob_palette1].iter() {
+            state.u8(*register);
+        }
+
+        state.bytes(&self.vram);
+        state.bytes(&self.oam);
+
+        for pixel in self.display.iter() {
+            state.u32(*pixel);
+        }
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.mode = match state.u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::Oam,
+            3 => Mode::Transfer,
+            _ => return Err(StateError::Corrupt)
+        };
+        self.dot = state.u16()?;
+        self.line = state.u8()?;
+        let (compared, ly) = (state.bool()?, state.u8()?);
+        self.compare_ly = if compared { Some(ly) } else { None };
+        self.stat_line = state.bool()?;
+        self.skip_frame = state.bool()?;
+
+        self.lx = state.u8()?;
+        self.discard = state.u8()?;
+        self.bg_fifo.clear();
+        for _ in 0..state.u8()? {
+            self.bg_fifo.push_back(state.u8()? & 3);
+        }
+        self.sprite_fifo.clear();
+        for _ in 0..state.u8()? {
+            self.sprite_fifo.push_back(SpritePixel { color: state.u8()? & 3, palette: state.bool()?, priority: state.bool()? });
+        }
+        self.fetcher = Fetcher::load_state(state)?;
+        self.line_sprites.clear();
+        for _ in 0..state.u8()? {
+            self.line_sprites.push(((state.u8()? as usize).min(39), state.bool()?));
+        }
+        let (index, dots) = ((state.u8()? as usize).min(39), state.u8()?);
+        self.sprite_fetch = if dots > 0 { Some((index, dots)) } else { None };
+
+        self.window_line = state.u8()?;
+        self.window_y_hit = state.bool()?;
+        self.window_wrap = state.bool()?;
+
+        if self.dot >= LINE_DOTS || self.line >= LINES || self.lx > 160 || self.fetcher.dot > FETCH_DOTS {
+            return Err(StateError::Corrupt);
+        }
+
+        for register in [&mut self.LCDC, &mut self.STAT, &mut self.scroll_y, &mut self.scroll_x, &mut self.lcd_y, &mut self.lycompare,
+                         &mut self.OAM_DMA, &mut self.window_y, &mut self.window_x, &mut self.bg_palette, &mut self.ob_palette0, &mut self.ob_palette1].iter_mut() {
+            **register = state.u8()?;
+        }
+
+        state.bytes(&mut self.vram)?;
+        state.bytes(&mut self.oam)?;
+
+        for pixel in self.display.iter_mut() {
+            *pixel = state.u32()?;
+        }
+
+        self.sprites = [Sprite::default(); 40];
+
+        Ok(())
+    }
+
     fn translate(addr: u16) -> Region {
         match addr {
             0x8000 ..= 0x9FFF => Region::VRAM( addr as usize - 0x8000 ),
@@ -662,4 +776,174 @@ impl GPU {
             _ => panic!("Error translating address in GPU module")
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //LCD, background and sprites on, the first frame is drawn
+    fn lcd_on() -> GPU {
+        let mut gpu = GPU::default();
+        gpu.write_lcdc(0x83);
+        gpu.skip_frame = false;
+        gpu
+    }
+
+    //run the rest of the current line, returns the dots it spent in mode 3 and the ones that requested a STAT interrupt
+    fn run_line(gpu: &mut GPU, interrupts: &mut InterruptHandler) -> (usize, Vec<u16>) {
+        let mut screen = Vec::new();
+        let mut transfer = 0;
+        let mut requests = Vec::new();
+        let line = gpu.line;
+
+        while gpu.line == line {
+            let dot = gpu.dot;
+            let was_transfer = gpu.mode == Mode::Transfer;
+
+            gpu.step_dot(interrupts, &mut screen);
+
+            if was_transfer || gpu.mode == Mode::Transfer {
+                transfer += 1;
+            }
+            if interrupts.requests.test_bit(1) {
+                interrupts.requests.reset_bit(1);
+                requests.push(dot);
+            }
+        }
+        (transfer, requests)
+    }
+
+    fn transfer_dots(gpu: &mut GPU) -> usize {
+        run_line(gpu, &mut InterruptHandler::default()).0
+    }
+
+    //sprite on line 0 with tile 0, x as stored in OAM (8 is the left edge of the screen)
+    fn sprite(gpu: &mut GPU, index: usize, x: u8, flags: u8) {
+        let addr = 0xFE00 + index as u16 * 4;
+        gpu.write_byte(addr, 16);
+        gpu.write_byte(addr + 1, x);
+        gpu.write_byte(addr + 3, flags);
+    }
+
+    #[test]
+    fn mode_3_grows_with_fine_scroll_and_sprites() {
+        for scx in 0..16 {
+            let mut gpu = lcd_on();
+            gpu.scroll_x = scx;
+            assert_eq!(transfer_dots(&mut gpu), 172 + (scx & 7) as usize);
+        }
+
+        //6 dots for the fetch, plus what the background fetcher has left of its tile
+        for scx in 0..8 {
+            for x in 8..24 {
+                let mut gpu = lcd_on();
+                gpu.scroll_x = scx;
+                sprite(&mut gpu, 0, x, 0);
+
+                let wait = 5 - 5.min((x - 8 + scx) % 8) as usize;
+                assert_eq!(transfer_dots(&mut gpu), 172 + scx as usize + 6 + wait, "SCX {} X {}", scx, x);
+            }
+
+            //X=0 always waits for a whole tile
+            let mut gpu = lcd_on();
+            gpu.scroll_x = scx;
+            sprite(&mut gpu, 0, 0, 0);
+            assert_eq!(transfer_dots(&mut gpu), 172 + scx as usize + 11, "SCX {}", scx);
+        }
+
+        //after the first sprite at an X the fetcher is ready, the others only take their own fetch
+        let mut gpu = lcd_on();
+        for index in 0..10 {
+            sprite(&mut gpu, index, 28, 0);
+        }
+        assert_eq!(transfer_dots(&mut gpu), 172 + 7 + 9 * 6);
+
+        //past the right edge
+        let mut gpu = lcd_on();
+        sprite(&mut gpu, 0, 168, 0);
+        assert_eq!(transfer_dots(&mut gpu), 172);
+    }
+
+    #[test]
+    fn window_line_only_counts_drawn_lines() {
+        let mut interrupts = InterruptHandler::default();
+        let mut gpu = lcd_on();
+        gpu.write_lcdc(0xA3);
+        gpu.window_x = 7;
+
+        run_line(&mut gpu, &mut interrupts);
+        assert_eq!(gpu.window_line, 1);
+
+        //past WX=166 the window never starts
+        gpu.window_x = 200;
+        run_line(&mut gpu, &mut interrupts);
+        run_line(&mut gpu, &mut interrupts);
+        assert_eq!(gpu.window_line, 1);
+
+        gpu.window_x = 7;
+        gpu.write_lcdc(0x83);
+        run_line(&mut gpu, &mut interrupts);
+        assert_eq!(gpu.window_line, 1);
+
+        //it resumes from where it stopped, not from LY-WY
+        gpu.write_lcdc(0xA3);
+        run_line(&mut gpu, &mut interrupts);
+        assert_eq!(gpu.window_line, 2);
+        assert_eq!(gpu.line, 5);
+    }
+
+    #[test]
+    fn ten_sprites_per_line_by_oam_order() {
+        let mut gpu = lcd_on();
+        gpu.ob_palette0 = 0xC0;
+        gpu.ob_palette1 = 0x40;
+        //every row of tile 0 is color 3
+        for byte in 0..16 {
+            gpu.write_byte(0x8000 + byte, 0xFF);
+        }
+
+        //sprite 0 is off the left of the screen but takes a slot, 10 and 11 are one too many
+        sprite(&mut gpu, 0, 0, 0);
+        for index in 1..10 {
+            sprite(&mut gpu, index, 8 + index as u8 * 12, 0);
+        }
+        sprite(&mut gpu, 10, 140, 0);
+        sprite(&mut gpu, 11, 150, 0);
+        //sprite 3 (X 44) is under sprite 9 (X 40 with palette 1), the lower X wins
+        sprite(&mut gpu, 9, 40, 0x10);
+
+        transfer_dots(&mut gpu);
+        let indexes: Vec<usize> = gpu.line_sprites.iter().map(|&(index, _)| index).collect();
+        assert_eq!(indexes, (0..10).collect::<Vec<_>>());
+
+        let black = gpu.to_rgb(3, 0xC0);
+        let light = gpu.to_rgb(3, 0x40);
+        let white = gpu.to_rgb(0, 0);
+        assert_eq!(gpu.display[..8], [white; 8]);
+        assert_eq!(gpu.display[32..40], [light; 8]);
+        assert_eq!(gpu.display[40], black);
+        assert_eq!(gpu.display[132..148], [white; 16]);
+    }
+
+    #[test]
+    fn stat_interrupts_need_the_line_to_go_low() {
+        let mut interrupts = InterruptHandler::default();
+
+        //mode 0 and mode 2: mode 2 is already on when STAT is written and every mode 0 runs straight into the next
+        //mode 2, so only mode 0 fires
+        let mut gpu = lcd_on();
+        gpu.write_stat(0x28, &mut interrupts);
+        interrupts.requests = 0;
+        assert_eq!(run_line(&mut gpu, &mut interrupts).1, [251]);
+        assert_eq!(run_line(&mut gpu, &mut interrupts).1, [251]);
+
+        //LY=LYC and mode 0: line 1 matches from dot 4 and stays high through mode 0
+        let mut gpu = lcd_on();
+        gpu.lycompare = 1;
+        gpu.write_stat(0x48, &mut interrupts);
+        interrupts.requests = 0;
+        assert_eq!(run_line(&mut gpu, &mut interrupts).1, [251]);
+        assert_eq!(run_line(&mut gpu, &mut interrupts).1, [4]);
+        assert_eq!(run_line(&mut gpu, &mut interrupts).1, [251]);
+    }
+}
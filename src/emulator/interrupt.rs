@@ -1,22 +1,19 @@
 #![allow(non_snake_case)]
 
 use super::bit_utils::BitUtils;
+use super::state::{StateWriter, StateReader, StateError};
 use std::fmt::{Formatter, Result, Display};
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Default)]
 pub enum EI {
     Requested,
     Active,
+    #[default]
     Disabled,
 }
 
-impl Default for EI {
-    fn default() -> Self { EI::Disabled }
-}
-
 
 #[derive(Copy, Clone, PartialEq)]
-#[allow(dead_code)] //Serial will be used when we implement it
 pub enum Interrupt {
     VBlank,
     LCDC,
@@ -52,6 +49,31 @@ impl Display for InterruptHandler {
 }
 
 impl InterruptHandler {
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bool(self.master);
+        state.bool(self.halt_bug);
+        state.u8(match self.ei_key {
+            EI::Requested => 0,
+            EI::Active => 1,
+            EI::Disabled => 2,
+        });
+        state.u8(self.enable);
+        state.u8(self.requests);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> std::result::Result<(), StateError> {
+        self.master = state.bool()?;
+        self.halt_bug = state.bool()?;
+        self.ei_key = match state.u8()? {
+            0 => EI::Requested,
+            1 => EI::Active,
+            2 => EI::Disabled,
+            _ => return Err(StateError::Corrupt)
+        };
+        self.enable = state.u8()?;
+        self.requests = state.u8()?;
+        Ok(())
+    }
 
     pub fn get_vec(&self) -> InterruptVector {
 
@@ -1,4 +1,4 @@
-#![allow(dead_code)] //unused constants will be used when sound is implemented
+#![allow(dead_code)] //not every register constant is referenced by name
 
 pub const JOYP: u16   = 0xFF00; // Joypad info
 
@@ -34,7 +34,8 @@ pub const NR50: u16 = 0xFF24; // Channel Control/Volume
 pub const NR51: u16 = 0xFF25; // Sound output terminal
 pub const NR52: u16 = 0xFF26; // Sound on/off
 
-// FF30 - FF3F Wave pattern RAM
+pub const WAVE_RAM: u16     = 0xFF30; // Wave pattern RAM start
+pub const WAVE_RAM_END: u16 = 0xFF3F; // Wave pattern RAM end
 
 pub const LCDC: u16    = 0xFF40; // LCD Control
 pub const STAT: u16    = 0xFF41; // LCD Status
@@ -2,6 +2,7 @@
 
 use super::interrupt::{Interrupt};
 use super::bit_utils::{*};
+use super::state::{StateWriter, StateReader, StateError};
 
 pub struct Joypad {
     JOYP: u8
@@ -142,10 +143,19 @@ impl Joypad {
     pub fn write(&mut self, byte: u8) {
         //clears bits 7,6,3,2,1 and 0 (only bits 4 and 5 are W)
         let data = byte & 0x30;
-        self.JOYP = self.JOYP & !0x30;
+        self.JOYP &= !0x30;
         self.JOYP |= data;
     }
     pub fn read(&self) -> u8 {
         self.JOYP
     }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.JOYP);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.JOYP = state.u8()?;
+        Ok(())
+    }
 }
\ No newline at end of file
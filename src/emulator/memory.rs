@@ -1,6 +1,7 @@
 #![allow(non_snake_case)]
 
 use super::cpu::registers::Response;
+use super::state::{StateWriter, StateReader, StateError};
 
 pub struct Memory {
     wram: [u8; 0x2000], //Internal RAM
@@ -60,6 +61,17 @@ impl Memory {
         
     }
 
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(&self.wram);
+        state.bytes(&self.hram);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes(&mut self.wram)?;
+        state.bytes(&mut self.hram)?;
+        Ok(())
+    }
+
     fn translate(addr: u16) -> Region {
         match addr {
             0xC000 ..= 0xDFFF => Region::WRAM(addr as usize - 0xC000 ),
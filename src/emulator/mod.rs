@@ -1,5 +1,6 @@
 mod cpu;
 mod gpu;
+mod apu;
 mod memory;
 mod io_constants;
 mod cartridge;
@@ -8,15 +9,92 @@ mod bit_utils;
 mod bus;
 mod timer;
 mod joypad;
+mod state;
+mod watchpoint;
+mod trace;
+mod serial;
 use cpu::{*};
 use cpu::registers::{*};
 use bus::{*};
+use std::io;
+use std::path::Path;
+use crate::symbols::Symbols;
+pub use cartridge::{Cartridge, CartridgeError};
+pub use cpu::registers::CpuRegister;
+pub use state::StateError;
+pub use watchpoint::{Watchpoint, WatchAccess, WatchHit};
+pub use trace::TraceOptions;
+pub use serial::{SerialDevice, Disconnected, SerialCapture};
+use trace::Trace;
+use state::{StateWriter, StateReader};
+pub(crate) use cpu::disassembler;
+pub(crate) use state::crc32;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+//number of cycles the LCD takes to draw a full frame (154 lines of 456 cycles)
+pub const FRAME_CYCLES: u32 = 70224;
+
+//gets the address, symbol and disassembly of each instruction
+type InstructionCallback = Box<dyn FnMut(u16, Option<&str>, &str)>;
 
 #[derive(Default)]
 pub struct Gameboy {
     cpu: CPU,
     bus: Bus,
-    pub screen: Vec<u32>
+    rumble: bool,
+    rumble_callback: Option<Box<dyn FnMut(bool)>>,
+    instruction_callback: Option<InstructionCallback>,
+    symbols: Symbols,
+    trace: Option<Trace>
+}
+
+//builds a Gameboy with a cartridge already inserted
+pub struct GameboyBuilder {
+    rom: Vec<u8>,
+    sample_rate: u32,
+    symbols: Symbols,
+    lenient: bool
+}
+
+impl GameboyBuilder {
+    pub fn new(rom: Vec<u8>) -> Self {
+        GameboyBuilder { rom, sample_rate: apu::DEFAULT_SAMPLE_RATE, symbols: Symbols::new(), lenient: false }
+    }
+
+    //rate (in Hz) of the audio samples returned by Gameboy::drain_samples
+    pub fn sample_rate(mut self, rate: u32) -> Self {
+        self.sample_rate = rate;
+        self
+    }
+
+    //labels shown in the disassembly and given to the on_instruction callback
+    pub fn symbols(mut self, symbols: Symbols) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    //let the CPU use VRAM and OAM in any PPU mode instead of reading 0xFF and dropping writes while they're locked
+    pub fn lenient(mut self, lenient: bool) -> Self {
+        self.lenient = lenient;
+        self
+    }
+
+    pub fn build(self) -> Result<Gameboy, CartridgeError> {
+        let cartridge = Cartridge::from_bytes(&self.rom)?;
+
+        let mut system = Gameboy {
+            symbols: self.symbols,
+            ..Gameboy::default()
+        };
+
+        system.bus.screen = vec![0; SCREEN_WIDTH*SCREEN_HEIGHT];
+        system.bus.insert_cartrigbe(cartridge);
+        system.bus.apu.set_sample_rate(self.sample_rate);
+        system.bus.lenient = self.lenient;
+
+        Ok(system)
+    }
 }
 
 macro_rules! jp_input {
@@ -37,17 +115,356 @@ macro_rules! jp_input {
 }
 
 impl Gameboy {
-    //main loop
-    pub fn run(&mut self, debug: bool) -> u8{
+    pub fn builder(rom: Vec<u8>) -> GameboyBuilder {
+        GameboyBuilder::new(rom)
+    }
+
+    //execute a single instruction (or interrupt dispatch) and run the rest of the system, returns the cycles it took
+    pub fn step_instruction(&mut self) -> u8 {
+        let pc = self.pc();
+
         //execute the instruction pointed by PC
-        let cycles = self.cpu_inst(debug);
+        let cycles = self.cpu_inst();
+
+        if self.bus.watchpoints.has_hits() {
+            let (instruction, _) = self.disassemble(pc);
+            self.bus.watchpoints.describe_hits(pc, &instruction);
+        }
         
-        //run the rest of the system
-        self.bus.run_system(cycles, &mut self.screen);
+        //run the rest of the system for the cycles the memory accesses didn't already
+        self.bus.finish_instruction(cycles);
+
+        self.update_rumble();
 
         return cycles;
     }
 
+    //run instructions until a frame worth of cycles has passed, returns the cycles made.
+    //Stops early after an instruction that triggered a watchpoint, see take_watch_hits
+    pub fn run_frame(&mut self) -> u32 {
+        let mut cycles = 0;
+
+        while cycles < FRAME_CYCLES {
+            cycles += self.step_instruction() as u32;
+
+            if self.bus.watchpoints.has_hits() { break; }
+        }
+
+        cycles
+    }
+
+    //last complete frame, SCREEN_WIDTH*SCREEN_HEIGHT pixels as 0RGB
+    pub fn framebuffer(&self) -> &[u32] {
+        &self.bus.screen[..SCREEN_WIDTH*SCREEN_HEIGHT]
+    }
+
+    //interleaved stereo (left, right) samples made since the last call. At most one second is kept, so drain it regularly
+    pub fn drain_samples(&mut self) -> Vec<i16> {
+        self.bus.apu.drain_samples()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.bus.apu.sample_rate()
+    }
+
+    //plug something into the link port, replacing what was there
+    pub fn connect_serial(&mut self, device: Box<dyn SerialDevice>) {
+        self.bus.serial.connect(device);
+    }
+
+    pub fn disconnect_serial(&mut self) {
+        self.bus.serial.connect(Box::new(Disconnected));
+    }
+
+    //keep every byte the game sends through the serial port for take_serial_output, off by default so the log
+    //doesn't grow forever when nobody reads it
+    pub fn capture_serial(&mut self, capture: bool) {
+        self.bus.serial.capture(capture);
+    }
+
+    //bytes the game sent through the serial port since the last call. Empty unless capture_serial is on
+    pub fn take_serial_output(&mut self) -> Vec<u8> {
+        self.bus.serial.take_output()
+    }
+
+    //bytes sent through the serial port that weren't taken yet
+    pub fn serial_output(&self) -> &[u8] {
+        self.bus.serial.output()
+    }
+
+    //CRC-32 of the framebuffer, to recognise a screen without storing it
+    pub fn screen_hash(&self) -> u32 {
+        let bytes: Vec<u8> = self.framebuffer().iter().flat_map(|pixel| pixel.to_le_bytes()).collect();
+        state::crc32(&bytes)
+    }
+
+    //true while the cartridge's rumble motor is on
+    pub fn rumble_active(&self) -> bool {
+        self.rumble
+    }
+
+    //called with the new state every time the rumble motor turns on or off
+    pub fn on_rumble<F: FnMut(bool) + 'static>(&mut self, callback: F) {
+        self.rumble_callback = Some(Box::new(callback));
+    }
+
+    //called before every instruction the CPU executes with its address, the symbol there and its disassembly
+    pub fn on_instruction<F: FnMut(u16, Option<&str>, &str) + 'static>(&mut self, callback: F) {
+        self.instruction_callback = Some(Box::new(callback));
+    }
+
+    fn report_instruction(&mut self, pc: u16) {
+        let (text, _) = self.disassemble(pc);
+        let symbol = self.symbol(pc);
+
+        if let Some(callback) = &mut self.instruction_callback {
+            callback(pc, symbol.as_deref(), &text);
+        }
+    }
+
+    fn update_rumble(&mut self) {
+        let rumble = self.bus.rumble();
+
+        if rumble != self.rumble {
+            self.rumble = rumble;
+
+            if let Some(callback) = &mut self.rumble_callback {
+                callback(rumble);
+            }
+        }
+    }
+
+    //address of the next instruction
+    pub fn pc(&self) -> u16 {
+        self.cpu.registers.get(CpuRegister::PC)
+    }
+
+    pub fn register(&self, register: CpuRegister) -> u16 {
+        self.cpu.registers.get(register)
+    }
+
+    pub fn set_register(&mut self, register: CpuRegister, value: u16) {
+        self.cpu.registers.set(register, value);
+    }
+
+    //registers, flags and interrupt state as text
+    pub fn cpu_status(&self) -> String {
+        format!("{}Flags: {}\n{}", self.cpu.registers, self.cpu.registers.flags(), self.bus.interrupts)
+    }
+
+    //read memory the way the CPU sees it
+    pub fn peek(&mut self, addr: u16) -> u8 {
+        self.bus.peek(addr).value()
+    }
+
+    //write memory the way the CPU does, so writes to ROM go to the cartridge controller
+    pub fn poke(&mut self, addr: u16, byte: u8) {
+        self.bus.write_untimed(addr, byte);
+    }
+
+    //the instruction at addr with its operands, and its length in bytes. Addresses with a symbol show its name
+    pub fn disassemble(&mut self, addr: u16) -> (String, u16) {
+        let bytes = [self.peek(addr), self.peek(addr.wrapping_add(1)), self.peek(addr.wrapping_add(2))];
+        let (text, length) = cpu::disassembler::disassemble(bytes, addr);
+
+        let bank = self.rom_bank();
+        let text = cpu::disassembler::with_label(text, bytes, addr, |target| self.symbols.name(bank, target).map(str::to_string));
+
+        (text, length)
+    }
+
+    //ROM bank the cartridge has mapped at 0x4000-0x7FFF
+    pub fn rom_bank(&self) -> u16 {
+        self.bus.rom_bank()
+    }
+
+    //game title from the cartridge header
+    pub fn title(&self) -> &str {
+        self.bus.rom_title()
+    }
+
+    //contents of the battery backed cartridge RAM to keep between sessions, None if the cartridge has no battery.
+    //MBC3 timer carts append the RTC state
+    pub fn battery_ram(&self) -> Option<Vec<u8>> {
+        self.bus.battery_ram()
+    }
+
+    //restore battery RAM saved by battery_ram, usually right after building the Gameboy
+    pub fn load_battery_ram(&mut self, data: &[u8]) -> Result<(), CartridgeError> {
+        self.bus.load_battery_ram(data)
+    }
+
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    //addr as the closest symbol before it in the current ROM bank, like Main.loop+3
+    pub fn symbol(&self, addr: u16) -> Option<String> {
+        let (name, offset) = self.symbols.nearest(self.rom_bank(), addr)?;
+
+        if offset == 0 { Some(name.to_string()) } else { Some(format!("{}+{}", name, offset)) }
+    }
+
+    //pause emulation whenever the watchpoint triggers, returns its id
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.bus.watchpoints.add(watchpoint)
+    }
+
+    //false if there was no watchpoint with that id
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        self.bus.watchpoints.remove(id)
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.bus.watchpoints.clear();
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.bus.watchpoints.iter()
+    }
+
+    //accesses that triggered a watchpoint since the last call, in the order they happened
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        self.bus.watchpoints.take_hits()
+    }
+
+    //log every executed instruction to out, see TraceOptions for limiting it. Replaces the trace running before
+    pub fn start_trace<W: std::io::Write + 'static>(&mut self, out: W, options: TraceOptions) {
+        self.trace = Some(Trace::new(Box::new(out), options));
+    }
+
+    //same as start_trace, into a new file
+    pub fn trace_to_file(&mut self, path: &Path, options: TraceOptions) -> io::Result<()> {
+        let file = std::fs::File::create(path)?;
+        self.start_trace(io::BufWriter::new(file), options);
+        Ok(())
+    }
+
+    //true until the trace reaches its stop address or limit
+    pub fn tracing(&self) -> bool {
+        self.trace.as_ref().is_some_and(|trace| trace.active())
+    }
+
+    //end the trace, returns how many instructions were logged or the error that stopped it
+    pub fn finish_trace(&mut self) -> io::Result<u64> {
+        match self.trace.take() {
+            Some(trace) => trace.close(),
+            None => Ok(0)
+        }
+    }
+
+    fn trace_instruction(&mut self, pc: u16) {
+        let wants = match &mut self.trace {
+            Some(trace) => trace.wants(pc),
+            None => false
+        };
+        if !wants { return; }
+
+        let registers = [
+            CpuRegister::A, CpuRegister::F, CpuRegister::B, CpuRegister::C, CpuRegister::D,
+            CpuRegister::E, CpuRegister::H, CpuRegister::L, CpuRegister::SP, CpuRegister::PC
+        ].map(|register| self.register(register));
+
+        let mut memory = [0; 4];
+        for (offset, byte) in memory.iter_mut().enumerate() {
+            *byte = self.peek(pc.wrapping_add(offset as u16));
+        }
+
+        if let Some(trace) = &mut self.trace {
+            trace.log(registers, memory);
+        }
+    }
+
+    //snapshot of the whole machine, restore it with load_state
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut state = StateWriter::new(self.bus.rom_checksum());
+
+        self.cpu.registers.save_state(&mut state);
+
+        for pixel in self.framebuffer() {
+            state.u32(*pixel);
+        }
+
+        self.bus.save_state(&mut state);
+
+        state.finish()
+    }
+
+    //restore a snapshot made by save_state with the same ROM, nothing changes if it's refused
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let (mut state, checksum) = StateReader::new(data)?;
+
+        let expected = self.bus.rom_checksum();
+        if checksum != expected {
+            return Err(StateError::RomMismatch { expected, found: checksum });
+        }
+
+        let mut registers = Registers::default();
+        registers.load_state(&mut state)?;
+
+        let mut screen = vec![0; SCREEN_WIDTH*SCREEN_HEIGHT];
+        for pixel in screen.iter_mut() {
+            *pixel = state.u32()?;
+        }
+
+        //the bus goes last since it can't be undone once it succeeds. It ends the state, so it also refuses one
+        //with bytes left over before changing anything
+        self.bus.load_state(&mut state)?;
+
+        self.cpu.registers = registers;
+        self.bus.screen = screen;
+
+        Ok(())
+    }
+
+    //also keep the output of each channel separately, see drain_channel_samples
+    pub fn record_channels(&mut self, record: bool) {
+        self.bus.apu.record_channels(record);
+    }
+
+    //same as drain_samples, one buffer per channel. Empty unless record_channels is on
+    pub fn drain_channel_samples(&mut self) -> [Vec<i16>; 4] {
+        self.bus.apu.drain_channel_samples()
+    }
+
+    //run for the given number of frames and write the audio to a 16 bit PCM WAV file.
+    //With split_channels each channel is also written to its own file (name_ch1.wav .. name_ch4.wav)
+    pub fn record_audio(&mut self, frames: u32, path: &Path, split_channels: bool) -> io::Result<()> {
+        let mut mixed = vec![];
+        let mut channels: [Vec<i16>; 4] = Default::default();
+
+        self.drain_samples();
+        self.record_channels(split_channels);
+
+        for _ in 0..frames {
+            self.run_frame();
+
+            mixed.extend(self.drain_samples());
+            for (channel, samples) in channels.iter_mut().zip(self.drain_channel_samples().iter()) {
+                channel.extend(samples);
+            }
+        }
+
+        self.record_channels(false);
+
+        crate::wav::write_wav(path, &mixed, self.sample_rate())?;
+
+        if split_channels {
+            let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+
+            for (i, samples) in channels.iter().enumerate() {
+                let name = path.with_file_name(format!("{}_ch{}.wav", stem, i + 1));
+                crate::wav::write_wav(&name, samples, self.sample_rate())?;
+            }
+        }
+
+        Ok(())
+    }
+
     jp_input!(up, down, left, right, btn_a, btn_b, start, select);
 
     //get an opcode byte and convert it into an Instruction object
@@ -72,13 +489,28 @@ impl Gameboy {
     }
 
     //execute instruction pointed by PC, increment it as needed, return number of cycles it took and if an IO write was made
-    fn cpu_inst(&mut self, debug_flag: bool) -> u8 {
+    fn cpu_inst(&mut self) -> u8 {
         let int_cycles = self.cpu.interrupts(&mut self.bus);
 
-        if int_cycles != 0 { return int_cycles; }
+        if int_cycles != 0 {
+            //the return address pushed on the stack can trigger watchpoints
+            if self.bus.watchpoints.has_hits() {
+                let pc = self.pc();
+                self.bus.watchpoints.describe_hits(pc, "interrupt dispatch");
+            }
+
+            return int_cycles;
+        }
         
         if !self.bus.halt_cpu {
             let pc = self.cpu.PC();
+
+            if self.trace.is_some() {
+                self.trace_instruction(pc);
+            }
+            if self.instruction_callback.is_some() {
+                self.report_instruction(pc);
+            }
             let opcode = self.bus.read_byte(pc).value();
     
             let instruction = self.decode(opcode, pc);
@@ -120,11 +552,6 @@ impl Gameboy {
                     panic!("Instruction has wrong number of args \"{}\"", instruction);
                 },
             }
-    
-            if debug_flag && pc > 256 {
-                let oprnds = Bus::to_short(operands);
-                println!("{:#10x}: {}\r\t\t\t{:#10x}", pc, instruction.disassembly, oprnds);
-            }   
 
 
             
@@ -138,8 +565,244 @@ impl Gameboy {
             return 4;
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //32KB ROM-only cartridge, empty apart from a valid header checksum
+    fn rom() -> Vec<u8> {
+        cartridge(0, 0)
+    }
+
+    //32KB cartridge of the given type and RAM size code
+    fn cartridge(cartridge_type: u8, ram_size: u8) -> Vec<u8> {
+        banked_cartridge(cartridge_type, 0, ram_size)
+    }
+
+    //cartridge with the given ROM size code, the first byte of every bank past 0 holds its number
+    fn banked_cartridge(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000 << rom_size]; //>
+        for bank in 1..rom.len() / 0x4000 {
+            rom[bank * 0x4000] = bank as u8;
+        }
+        rom[0x147] = cartridge_type;
+        rom[0x148] = rom_size;
+        rom[0x149] = ram_size;
+        rom[0x14D] = rom[0x134..0x14D].iter().fold(0u8, |x, byte| x.wrapping_sub(*byte).wrapping_sub(1));
+        rom
+    }
+
+    //run a program from WRAM with the boot ROM unmapped, the flags clear, HL and SP on TIMA and the timer
+    //counting every 16 cycles. It starts by resetting DIV so TIMA counts from there
+    fn run(program: &[u8], instructions: usize) -> Gameboy {
+        let mut gameboy = Gameboy::builder(rom()).build().unwrap();
+        gameboy.poke(0xFF50, 1);
+        gameboy.poke(0xFF07, 0x05);
+
+        for (offset, byte) in [0xE0, 0x04].iter().chain(program).enumerate() {
+            gameboy.poke(0xC000 + offset as u16, *byte);
+        }
+        gameboy.set_register(CpuRegister::F, 0);
+        gameboy.set_register(CpuRegister::HL, 0xFF05);
+        gameboy.set_register(CpuRegister::SP, 0xFF05);
+        gameboy.set_register(CpuRegister::PC, 0xC000);
+
+        for _ in 0..=instructions {
+            gameboy.step_instruction();
+        }
+        gameboy
+    }
+
+    //TIMA as read by LD A,(HL) in its 2nd M-cycle, after some NOPs
+    fn read_tima(nops: usize) -> u16 {
+        let mut program = vec![0x00; nops];
+        program.push(0x7E);
+
+        run(&program, nops + 1).register(CpuRegister::A) & 0xFF
+    }
+
+    #[test]
+    fn reads_happen_in_their_m_cycle() {
+        let early: Vec<u16> = (0..10).map(read_tima).collect();
+
+        //LD A,(a16) reads in its 4th M-cycle, 2 after LD A,(HL)
+        let late: Vec<u16> = (0..8).map(|nops| {
+            let mut program = vec![0x00; nops];
+            program.extend_from_slice(&[0xFA, 0x05, 0xFF]);
+
+            run(&program, nops + 1).register(CpuRegister::A) & 0xFF
+        }).collect();
+
+        //TIMA moves every 4 M-cycles
+        assert_ne!(early[0], early[9]);
+        assert_eq!(late[..], early[2..]);
+    }
+
+    #[test]
+    fn taken_conditional_return_checks_the_condition_first() {
+        //with SP on TIMA, the return address is what TIMA was when the stack was read
+        let returned = |opcode: u8, nops: usize| {
+            let mut program = vec![0x00; nops];
+            program.push(opcode);
+
+            run(&program, nops + 1).pc() & 0xFF
+        };
+
+        for nops in 0..8 {
+            //RET reads the stack in M2, RET NZ a cycle later
+            assert_eq!(returned(0xC9, nops), read_tima(nops));
+            assert_eq!(returned(0xC0, nops), read_tima(nops + 1));
+        }
+    }
+
+    #[test]
+    fn battery_ram_round_trips_with_the_rtc() {
+        let mut gameboy = Gameboy::builder(rom()).build().unwrap();
+        assert_eq!(gameboy.battery_ram(), None);
+        assert_eq!(gameboy.load_battery_ram(&[0; 0x2000]), Err(CartridgeError::NoBattery));
+
+        //MBC3+TIMER+RAM+BATTERY with 8KB of SRAM
+        let mut gameboy = Gameboy::builder(cartridge(0x10, 2)).build().unwrap();
+        gameboy.poke(0x0000, 0x0A);
+        gameboy.poke(0xA000, 0x42);
+
+        let save = gameboy.battery_ram().unwrap();
+        assert_eq!(save.len(), 0x2000 + 48);
+
+        let mut gameboy = Gameboy::builder(cartridge(0x10, 2)).build().unwrap();
+        assert_eq!(gameboy.load_battery_ram(&save[1..]), Err(CartridgeError::SaveSizeMismatch { expected: 0x2000, found: 0x2000 + 47 }));
+        gameboy.load_battery_ram(&save).unwrap();
+        gameboy.poke(0x0000, 0x0A);
+        assert_eq!(gameboy.peek(0xA000), 0x42);
+    }
+
+    #[test]
+    fn oam_dma_reads_echo_ram_and_open_bus() {
+        let mut gameboy = Gameboy::builder(rom()).build().unwrap();
+        gameboy.poke(0xC000, 0x12);
+
+        gameboy.poke(0xFF46, 0xE0);
+        assert_eq!(gameboy.peek(0xFE00), 0x12);
+
+        gameboy.poke(0xFF46, 0xFE);
+        assert_eq!(gameboy.peek(0xFE00), 0xFF);
+        gameboy.poke(0xFF46, 0xFF);
+        assert_eq!(gameboy.peek(0xFE00), 0xFF);
+    }
+
+    #[test]
+    fn blocked_accesses_hit_watchpoints() {
+        let mut gameboy = Gameboy::builder(rom()).build().unwrap();
+        gameboy.add_watchpoint(Watchpoint::new(WatchAccess::Write, 0xFE00..=0xFE00));
+        gameboy.poke(0xFF50, 1);
+
+        //LD A,$42; LD ($FE00),A while a DMA fills OAM
+        for (offset, byte) in [0x3E, 0x42, 0xEA, 0x00, 0xFE].iter().enumerate() {
+            gameboy.poke(0xC000 + offset as u16, *byte);
+        }
+        gameboy.set_register(CpuRegister::PC, 0xC000);
+        gameboy.poke(0xFF46, 0xC0);
+        gameboy.take_watch_hits();
+
+        gameboy.step_instruction();
+        gameboy.step_instruction();
+
+        let hits = gameboy.take_watch_hits();
+        assert_eq!(hits.len(), 1);
+        assert!(hits[0].blocked);
+        assert_eq!(hits[0].new, 0x42);
+        assert_ne!(gameboy.peek(0xFE00), 0x42);
+    }
+
+    #[test]
+    fn states_with_bytes_left_over_are_refused() {
+        //MBC1+RAM+BATTERY, so the cartridge has SRAM to restore
+        let mut gameboy = Gameboy::builder(cartridge(0x03, 2)).build().unwrap();
+        gameboy.poke(0x0000, 0x0A);
+        gameboy.poke(0xA000, 0x11);
+        gameboy.poke(0xC000, 0x11);
+        let mut state = gameboy.save_state();
+
+        gameboy.poke(0xA000, 0x22);
+        gameboy.poke(0xC000, 0x22);
+        state.push(0);
+
+        assert_eq!(gameboy.load_state(&state), Err(StateError::TrailingData(1)));
+        assert_eq!(gameboy.peek(0xA000), 0x22);
+        assert_eq!(gameboy.peek(0xC000), 0x22);
+
+        state.pop();
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.peek(0xA000), 0x11);
+        assert_eq!(gameboy.peek(0xC000), 0x11);
+    }
+
+    #[test]
+    fn out_of_range_accesses_are_harmless() {
+        //64KB MBC1 without RAM, bank 31 mirrors bank 3
+        let mut gameboy = Gameboy::builder(banked_cartridge(0x01, 1, 0)).build().unwrap();
+        gameboy.poke(0x2000, 0x1F);
+        assert_eq!(gameboy.peek(0x4000), 3);
+
+        gameboy.poke(0x0000, 0x0A);
+        gameboy.poke(0x6000, 0x01);
+        gameboy.poke(0x4000, 0xFF);
+        gameboy.poke(0xA000, 0x42);
+        assert_eq!(gameboy.peek(0xA000), 0xFF);
+
+        gameboy.poke(0xFEA0, 0x42);
+        assert_eq!(gameboy.peek(0xFEA0), 0xFF);
+
+        //LD ($FFFE),SP writes HRAM and IE one byte at a time
+        gameboy.poke(0xFF50, 1);
+        for (offset, byte) in [0x08, 0xFE, 0xFF].iter().enumerate() {
+            gameboy.poke(0xC000 + offset as u16, *byte);
+        }
+        gameboy.set_register(CpuRegister::SP, 0x1234);
+        gameboy.set_register(CpuRegister::PC, 0xC000);
+        gameboy.step_instruction();
+        assert_eq!(gameboy.peek(0xFFFE), 0x34);
+        assert_eq!(gameboy.peek(0xFFFF) & 0x1F, 0x12);
+    }
+
+    #[test]
+    fn states_with_banks_the_cartridge_lacks_are_refused() {
+        //64KB MBC1 without RAM, its state ends with the ROM bank, the RAM bank and an empty SRAM
+        let mut gameboy = Gameboy::builder(banked_cartridge(0x01, 1, 0)).build().unwrap();
+        gameboy.poke(0x2000, 0x1F);
+        let state = gameboy.save_state();
+        let end = state.len() - 4;
+        assert_eq!(state[end - 2 .. end], [0x1F, 0]);
+
+        let mut bad = state.clone();
+        bad[end - 2] = 0x20;
+        assert_eq!(gameboy.load_state(&bad), Err(StateError::Corrupt));
+
+        let mut bad = state.clone();
+        bad[end - 1] = 1;
+        assert_eq!(gameboy.load_state(&bad), Err(StateError::Corrupt));
+
+        gameboy.load_state(&state).unwrap();
+        assert_eq!(gameboy.peek(0x4000), 3);
+    }
+
+    #[test]
+    fn instructions_go_to_the_callback() {
+        let mut gameboy = Gameboy::builder(rom()).build().unwrap();
+        gameboy.poke(0xFF50, 1);
+        for (offset, byte) in [0x00, 0x3E, 0x42].iter().enumerate() {
+            gameboy.poke(0xC000 + offset as u16, *byte);
+        }
+        gameboy.set_register(CpuRegister::PC, 0xC000);
+
+        let executed = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let log = executed.clone();
+        gameboy.on_instruction(move |pc, symbol, text| log.borrow_mut().push((pc, symbol.is_some(), text.to_string())));
+        gameboy.step_instruction();
+        gameboy.step_instruction();
 
-    pub fn insert(&mut self, file_name: String){
-        self.bus.insert_cartrigbe(file_name);
+        assert_eq!(executed.borrow()[..], [(0xC000, false, "NOP".to_string()), (0xC001, false, "LD A,$42".to_string())]);
     }
 }
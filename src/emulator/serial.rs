@@ -0,0 +1,214 @@
+use super::io_constants::{*};
+use super::cpu::registers::Response;
+use super::bit_utils::BitUtils;
+use super::interrupt::{*};
+use super::state::{StateWriter, StateReader, StateError};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+//with the internal clock a bit is shifted every 512 cycles (8192Hz)
+const CYCLES_PER_BIT: u32 = 4194304 / 8192;
+
+//Whatever is on the other end of the link cable
+pub trait SerialDevice {
+    //The Game Boy started a transfer with its own clock and `byte` is going to shift out.
+    //Return the byte the other side has ready, it shifts in at the same time
+    fn transfer(&mut self, byte: u8) -> u8;
+
+    //The Game Boy waits for the other side to clock a transfer, with `byte` ready to go out.
+    //Called every step until it returns the byte the other side sent, which completes the transfer
+    fn external_clock(&mut self, _byte: u8) -> Option<u8> { None }
+
+    //Called every step, before external_clock, with the cycles that passed. Lets a device keep time with the Game Boy
+    fn step(&mut self, _cycles: u8) {}
+}
+
+//nothing plugged in: the data line stays high so 0xFF is read, and nobody ever drives the external clock
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn transfer(&mut self, _byte: u8) -> u8 {
+        0xFF
+    }
+}
+
+//keeps every byte the Game Boy sends in a buffer shared with whoever created it, and answers like a disconnected cable
+pub struct SerialCapture {
+    bytes: Rc<RefCell<Vec<u8>>>,
+}
+
+impl SerialCapture {
+    //the device and a handle to read what it captured
+    pub fn new() -> (Self, Rc<RefCell<Vec<u8>>>) {
+        let bytes = Rc::new(RefCell::new(vec![]));
+        (SerialCapture { bytes: bytes.clone() }, bytes)
+    }
+}
+
+impl SerialDevice for SerialCapture {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        self.bytes.borrow_mut().push(byte);
+        0xFF
+    }
+}
+
+//SB/SC shift register. With capture on, every byte sent is also kept in a log so test ROMs that report through
+//serial can be read
+pub struct Serial {
+    data: u8,
+    control: u8,
+    bits_left: u8,                          //of the transfer in progress
+    bit_cycles: u32,
+    incoming: u8,                           //byte from the other side, shifting in
+    device: Box<dyn SerialDevice>,
+    capture: bool,
+    output: Vec<u8>,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial {
+            data: 0,
+            control: 0,
+            bits_left: 0,
+            bit_cycles: 0,
+            incoming: 0xFF,
+            device: Box::new(Disconnected),
+            capture: false,
+            output: vec![],
+        }
+    }
+}
+
+impl Serial {
+    pub fn write_byte(&mut self, addr: u16, byte: u8) -> Response {
+        match addr {
+            SB => self.data = byte,
+            SC => {
+                self.control = byte;
+
+                if self.control.test_bit(7) {
+                    self.start_transfer();
+                } else {
+                    self.bits_left = 0;
+                }
+            },
+            _ => {}
+        }
+
+        Response::None
+    }
+
+    pub fn read_byte(&self, addr: u16) -> Response {
+        match addr {
+            SB => Response::Byte( self.data ),
+            //unused bits read as 1
+            SC => Response::Byte( self.control | 0x7E ),
+            _ => unreachable!()
+        }
+    }
+
+    fn start_transfer(&mut self) {
+        self.bits_left = 8;
+        self.bit_cycles = 0;
+
+        if self.internal_clock() {
+            self.log(self.data);
+            self.incoming = self.device.transfer(self.data);
+        }
+    }
+
+    fn internal_clock(&self) -> bool {
+        self.control.test_bit(0)
+    }
+
+    pub fn step(&mut self, cycles: u8, interrupts: &mut InterruptHandler) {
+        self.device.step(cycles);
+
+        if self.bits_left == 0 { return; }
+
+        if !self.internal_clock() {
+            if let Some(incoming) = self.device.external_clock(self.data) {
+                self.log(self.data);
+                self.data = incoming;
+                self.finish_transfer(interrupts);
+            }
+            return;
+        }
+
+        self.bit_cycles += cycles as u32;
+
+        while self.bit_cycles >= CYCLES_PER_BIT && self.bits_left > 0 {
+            self.bit_cycles -= CYCLES_PER_BIT;
+            self.bits_left -= 1;
+
+            //the top bit goes out and the next bit of the incoming byte comes in at the bottom
+            self.data = (self.data << 1) | ((self.incoming >> self.bits_left) & 1); //>
+
+            if self.bits_left == 0 {
+                self.finish_transfer(interrupts);
+            }
+        }
+    }
+
+    fn finish_transfer(&mut self, interrupts: &mut InterruptHandler) {
+        self.bits_left = 0;
+        self.control.reset_bit(7);
+        interrupts.request(Interrupt::Serial);
+    }
+
+    fn log(&mut self, byte: u8) {
+        if self.capture {
+            self.output.push(byte);
+        }
+    }
+
+    pub fn connect(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    //the cable and the log (and whether it is kept) belong to the host, they survive loading a state into a new bus
+    pub fn take_connection(&mut self, old: &mut Serial) {
+        self.device = std::mem::replace(&mut old.device, Box::new(Disconnected));
+        self.capture = old.capture;
+        self.output = std::mem::take(&mut old.output);
+    }
+
+    //the log is dropped when capture is turned off
+    pub fn capture(&mut self, capture: bool) {
+        self.capture = capture;
+
+        if !capture {
+            self.output.clear();
+        }
+    }
+
+    //bytes sent since the last call
+    pub fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.data);
+        state.u8(self.control);
+        state.u8(self.bits_left);
+        state.u32(self.bit_cycles);
+        state.u8(self.incoming);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.data = state.u8()?;
+        self.control = state.u8()?;
+        self.bits_left = state.u8()?;
+        self.bit_cycles = state.u32()?;
+        self.incoming = state.u8()?;
+
+        if self.bits_left > 8 { return Err(StateError::Corrupt); }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+use std::fmt::{Formatter, Display};
+
+//every save state starts with this
+const MAGIC: &[u8; 8] = b"RUSTYGBS";
+//bump this whenever the layout of any component changes
+pub const STATE_VERSION: u16 = 8;
+
+//reasons a save state can be refused
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u16),
+    RomMismatch { expected: u32, found: u32 },
+    Truncated,
+    TrailingData(usize),                    //bytes left over after the whole state was read
+    Corrupt,                                //a value is out of range for the register it belongs to
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "Data is not a save state"),
+            StateError::UnsupportedVersion(version) => write!(f, "Save state version {} is not supported (expected {})", version, STATE_VERSION),
+            StateError::RomMismatch { expected, found } => write!(f, "Save state was made with another ROM (expected checksum {:#010x}, found {:#010x})", expected, found),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::TrailingData(size) => write!(f, "Save state has {} bytes left over", size),
+            StateError::Corrupt => write!(f, "Save state is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+//little endian writer for the save state format
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    //starts the state with the header: magic, format version and ROM checksum
+    pub fn new(rom_checksum: u32) -> Self {
+        let mut state = StateWriter { data: MAGIC.to_vec() };
+        state.u16(STATE_VERSION);
+        state.u32(rom_checksum);
+        state
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+//reads back what StateWriter wrote
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    //checks the header, the ROM checksum is returned so it can be compared with the inserted cartridge
+    pub fn new(data: &'a [u8]) -> Result<(Self, u32), StateError> {
+        if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+            return Err(StateError::NotAState);
+        }
+
+        let mut state = StateReader { data, position: MAGIC.len() };
+
+        let version = state.u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let checksum = state.u32()?;
+
+        Ok((state, checksum))
+    }
+
+    fn take(&mut self, size: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.position < size {
+            return Err(StateError::Truncated);
+        }
+
+        let taken = &self.data[self.position .. self.position + size];
+        self.position += size;
+
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt)
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, StateError> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, StateError> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    //fills the whole buffer
+    pub fn bytes(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        buffer.copy_from_slice(self.take(buffer.len())?);
+        Ok(())
+    }
+
+    //a state is only valid if it was read to the end
+    pub fn finish(&self) -> Result<(), StateError> {
+        match self.data.len() - self.position {
+            0 => Ok(()),
+            left => Err(StateError::TrailingData(left))
+        }
+    }
+}
+
+//CRC-32 (IEEE), identifies the ROM a state was made with
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+
+    !crc
+}
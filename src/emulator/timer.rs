@@ -4,48 +4,49 @@ use super::io_constants::{*};
 use super::cpu::registers::Response;
 use super::bit_utils::BitUtils;
 use super::interrupt::{*};
+use super::state::{StateWriter, StateReader, StateError};
 
-#[derive(Eq, PartialEq, Copy, Clone)]
-enum Frequency {
-    Mode0 = 4096,
-    Mode1 = 262144,
-    Mode2 = 65536,
-    Mode3 = 16384,
-}
-
-impl Default for Frequency {
-    fn default() -> Self { Frequency::Mode0 }
-}
+//bit of SYS_CLK that drives TIMA for each TAC frequency (4096, 262144, 65536 and 16384Hz)
+const CLOCK_BITS: [u8; 4] = [9, 3, 5, 7];
 
+//TIMA counts the falling edges of (selected SYS_CLK bit AND timer enabled), so resetting DIV or changing TAC
+//can make it tick too. When it overflows it reads 0 for one M-cycle before TMA is loaded and the interrupt fires
+#[derive(Default)]
 pub struct Timer {
     TIMA: u8,
     TMA: u8,
     TAC: u8,
     SYS_CLK: u16,
-    timer_count: i32,
-    frequency: Frequency
-}
-
-impl Default for Timer {
-    fn default() -> Self {
-        Timer{
-            TIMA: 0,
-            TMA: 0,
-            TAC: 0,
-            SYS_CLK: 0,
-            timer_count: 4194304 / 16384,
-            frequency: Frequency::Mode0,
-        } 
-    }
+    overflow: bool,                         //TIMA overflowed last M-cycle, it gets reloaded in this one
+    reloading: bool,                        //TIMA was reloaded last M-cycle, writes to it are ignored in this one
 }
 
 impl Timer {
     pub fn write_byte(&mut self, addr: u16, byte: u8) -> Response {
         match addr {
-            DIV => self.SYS_CLK = 0,
-            TIMA => self.TIMA = byte,
-            TMA => self.TMA = byte,
-            TAC => self.TAC = byte,
+            DIV => {
+                let input = self.input();
+                self.SYS_CLK = 0;
+                self.falling_edge(input);
+            },
+            //a write while TMA is being loaded loses to it, one right after the overflow cancels the reload and the interrupt
+            TIMA if self.reloading => {},
+            TIMA => {
+                self.TIMA = byte;
+                self.overflow = false;
+            },
+            TMA => {
+                self.TMA = byte;
+
+                if self.reloading {
+                    self.TIMA = byte;
+                }
+            },
+            TAC => {
+                let input = self.input();
+                self.TAC = byte;
+                self.falling_edge(input);
+            },
             _ => {}
         }
 
@@ -57,59 +58,118 @@ impl Timer {
             DIV => Response::Byte( (self.SYS_CLK >> 8) as u8 ),
             TIMA => Response::Byte( self.TIMA ),
             TMA => Response::Byte( self.TMA ),
-            TAC => Response::Byte( self.TAC ),
+            //unused bits read as 1
+            TAC => Response::Byte( self.TAC | 0xF8 ),
             _ => unreachable!()
         }
     }
 
+    //one M-cycle at a time, SYS_CLK always moves 4 cycles at once so edges never fall in between
     pub fn step(&mut self, cycles: u8, interrupts: &mut InterruptHandler) {
-        self.update_div(cycles);
-        
-        
-        if self.enabled() {
+        for _ in 0 .. cycles / 4 {
+            self.reloading = false;
+
+            if self.overflow {
+                self.overflow = false;
+                self.reloading = true;
+                self.TIMA = self.TMA;
+                interrupts.request(Interrupt::Timer);
+            }
 
-            let freq = self.get_freq();
+            let input = self.input();
+            self.SYS_CLK = self.SYS_CLK.wrapping_add(4);
+            self.falling_edge(input);
+        }
+    }
 
-            if freq != self.frequency {
-                self.set_frequency(freq);
-            }
-            self.timer_count -= cycles as i32;
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(self.TIMA);
+        state.u8(self.TMA);
+        state.u8(self.TAC);
+        state.u16(self.SYS_CLK);
+        state.bool(self.overflow);
+        state.bool(self.reloading);
+    }
 
-            if self.timer_count <= 0{
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.TIMA = state.u8()?;
+        self.TMA = state.u8()?;
+        self.TAC = state.u8()?;
+        self.SYS_CLK = state.u16()?;
+        self.overflow = state.bool()?;
+        self.reloading = state.bool()?;
+        Ok(())
+    }
 
-                self.set_frequency(freq);
+    //the signal TIMA counts the falling edges of
+    fn input(&self) -> bool {
+        self.TAC.test_bit(2) && self.SYS_CLK.test_bit(CLOCK_BITS[(self.TAC & 3) as usize])
+    }
 
-                if self.TIMA == 255 {
-                    self.TIMA = self.TMA;
-                    interrupts.request(Interrupt::Timer);
-                } else {
-                    self.TIMA += 1;
-                }
-            }
-        }
+    //increment TIMA if the input went from high (`before`) to low
+    fn falling_edge(&mut self, before: bool) {
+        if !before || self.input() { return; }
 
+        if self.TIMA == 0xFF {
+            self.TIMA = 0;
+            self.overflow = true;
+        } else {
+            self.TIMA += 1;
+        }
     }
+}
 
-    fn enabled(&self) -> bool {
-        self.TAC.test_bit(2)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::cpu::registers::Value;
+
+    //timer at 262144Hz (SYS_CLK bit 3) that has run for `cycles`
+    fn timer(cycles: u8) -> Timer {
+        let mut timer = Timer::default();
+        timer.write_byte(TAC, 0x05);
+        timer.step(cycles, &mut InterruptHandler::default());
+        timer
     }
 
-    fn update_div(&mut self, cycles: u8) {
-        self.SYS_CLK = self.SYS_CLK.wrapping_add(cycles as u16);
+    fn tima(timer: &Timer) -> u8 {
+        timer.read_byte(TIMA).value()
     }
 
-    fn set_frequency(&mut self, frequency: Frequency) {
-        self.frequency = frequency;
-        self.timer_count = 4194304 / (frequency as i32);
+    #[test]
+    fn div_write_ticks_tima_when_the_bit_is_high() {
+        //SYS_CLK is 8, bit 3 is high and resetting it is a falling edge
+        let mut high = timer(8);
+        high.write_byte(DIV, 0);
+        assert_eq!(tima(&high), 1);
+
+        //SYS_CLK is 4, nothing to fall
+        let mut low = timer(4);
+        low.write_byte(DIV, 0);
+        assert_eq!(tima(&low), 0);
     }
 
-    fn get_freq(&self) -> Frequency {
-        match self.TAC & 3 {
-            0 => Frequency::Mode0,
-            1 => Frequency::Mode1,
-            2 => Frequency::Mode2,
-            3 => Frequency::Mode3,
-            _ => unreachable!()
-        }
+    #[test]
+    fn tac_change_ticks_tima_when_the_input_falls() {
+        //disabling the timer while the bit is high
+        let mut disabled = timer(8);
+        disabled.write_byte(TAC, 0x01);
+        assert_eq!(tima(&disabled), 1);
+
+        //switching to a bit that is low (bit 5 for 65536Hz)
+        let mut slower = timer(8);
+        slower.write_byte(TAC, 0x06);
+        assert_eq!(tima(&slower), 1);
+
+        //enabling it while the bit is high is a rising edge
+        let mut enabled = timer(8);
+        enabled.write_byte(TAC, 0x01);
+        enabled.write_byte(TAC, 0x05);
+        assert_eq!(tima(&enabled), 1);
+
+        //the bit is low already
+        let mut low = timer(4);
+        low.write_byte(TAC, 0x01);
+        assert_eq!(tima(&low), 0);
     }
-}
\ No newline at end of file
+}
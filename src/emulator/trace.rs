@@ -0,0 +1,85 @@
+use std::io::{self, Write};
+
+//when a trace starts and ends, everything is traced by default
+#[derive(Clone, Default, Debug)]
+pub struct TraceOptions {
+    pub start: Option<u16>,                 //start logging the first time PC gets here
+    pub stop: Option<u16>,                  //stop logging (and don't log it) when PC gets here
+    pub limit: Option<u64>,                 //stop after this many instructions
+}
+
+//One line per executed instruction with the state before it runs, in the format other emulators and
+//Gameboy Doctor use, so logs can be diffed: A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+pub struct Trace {
+    out: Box<dyn Write>,
+    options: TraceOptions,
+    started: bool,
+    finished: bool,
+    lines: u64,
+    error: Option<io::Error>,
+}
+
+impl Trace {
+    pub fn new(out: Box<dyn Write>, options: TraceOptions) -> Self {
+        let started = options.start.is_none();
+        Trace { out, options, started, finished: false, lines: 0, error: None }
+    }
+
+    //false once the stop address or the limit was reached, or writing failed
+    pub fn active(&self) -> bool {
+        !self.finished
+    }
+
+    //whether the instruction at pc has to be logged, updating the triggers
+    pub fn wants(&mut self, pc: u16) -> bool {
+        if self.finished { return false; }
+
+        if !self.started && self.options.start == Some(pc) {
+            self.started = true;
+        }
+        if self.started && self.options.stop == Some(pc) {
+            self.finish();
+        }
+
+        self.started && !self.finished
+    }
+
+    //registers are A, F, B, C, D, E, H, L, SP and PC, memory the 4 bytes at PC
+    pub fn log(&mut self, registers: [u16; 10], memory: [u8; 4]) {
+        let [a, f, b, c, d, e, h, l, sp, pc] = registers;
+
+        let written = writeln!(self.out,
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            a, f, b, c, d, e, h, l, sp, pc, memory[0], memory[1], memory[2], memory[3]);
+
+        if let Err(er) = written {
+            self.error = Some(er);
+            self.finished = true;
+            return;
+        }
+
+        self.lines += 1;
+
+        if self.options.limit == Some(self.lines) {
+            self.finish();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.finished = true;
+
+        if let Err(er) = self.out.flush() {
+            self.error.get_or_insert(er);
+        }
+    }
+
+    //flush and return the number of lines written, or the first write error
+    pub fn close(mut self) -> io::Result<u64> {
+        self.finish();
+
+        match self.error {
+            Some(er) => Err(er),
+            None => Ok(self.lines)
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use std::fmt::{Formatter, Display};
+use std::ops::RangeInclusive;
+
+//which bus accesses a watchpoint reacts to
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum WatchAccess {
+    Read,
+    Write,
+    Any,
+}
+
+impl Display for WatchAccess {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WatchAccess::Read => write!(f, "read"),
+            WatchAccess::Write => write!(f, "write"),
+            WatchAccess::Any => write!(f, "access"),
+        }
+    }
+}
+
+//Stops the emulation when the CPU (or an OAM DMA) touches an address range,
+//e.g. Watchpoint::new(WatchAccess::Write, 0xC100..=0xC100).value(0x00) for "a write of 0x00 to 0xC100"
+#[derive(Clone, PartialEq, Debug)]
+pub struct Watchpoint {
+    pub access: WatchAccess,
+    pub start: u16,
+    pub end: u16,
+    pub value: Option<u8>,                  //only trigger when the byte read or written is this
+}
+
+impl Watchpoint {
+    pub fn new(access: WatchAccess, range: RangeInclusive<u16>) -> Self {
+        Watchpoint { access, start: *range.start(), end: *range.end(), value: None }
+    }
+
+    pub fn value(mut self, value: u8) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    fn matches(&self, access: WatchAccess, addr: u16, value: u8) -> bool {
+        (self.access == WatchAccess::Any || self.access == access)
+            && (self.start ..= self.end).contains(&addr)
+            && self.value.is_none_or(|expected| expected == value)
+    }
+}
+
+impl Display for Watchpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ${:04X}", self.access, self.start)?;
+
+        if self.end != self.start {
+            write!(f, "-${:04X}", self.end)?;
+        }
+        if let Some(value) = self.value {
+            write!(f, " == ${:02X}", value)?;
+        }
+
+        Ok(())
+    }
+}
+
+//a triggered watchpoint. For reads old and new are both the byte that was read
+#[derive(Clone, Debug)]
+pub struct WatchHit {
+    pub id: usize,
+    pub access: WatchAccess,
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+    pub pc: u16,                            //address of the instruction that made the access
+    pub instruction: String,
+    pub blocked: bool,                      //VRAM or OAM was locked, so the read got 0xFF or the write was dropped
+}
+
+impl Display for WatchHit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.access {
+            WatchAccess::Write => write!(f, "Watchpoint {}: write ${:04X} ${:02X} -> ${:02X}", self.id, self.addr, self.old, self.new)?,
+            _ => write!(f, "Watchpoint {}: read ${:04X} = ${:02X}", self.id, self.addr, self.new)?,
+        }
+
+        if self.blocked {
+            write!(f, " (blocked)")?;
+        }
+
+        write!(f, " by ${:04X}: {}", self.pc, self.instruction)
+    }
+}
+
+//the watchpoints set on the bus and the hits nobody has collected yet
+#[derive(Default)]
+pub struct Watchpoints {
+    list: Vec<(usize, Watchpoint)>,
+    next_id: usize,
+    hits: Vec<WatchHit>,
+}
+
+impl Watchpoints {
+    pub fn add(&mut self, watchpoint: Watchpoint) -> usize {
+        self.next_id += 1;
+        self.list.push((self.next_id, watchpoint));
+        self.next_id
+    }
+
+    pub fn remove(&mut self, id: usize) -> bool {
+        let count = self.list.len();
+        self.list.retain(|(watch_id, _)| *watch_id != id);
+        self.list.len() != count
+    }
+
+    pub fn clear(&mut self) {
+        self.list.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &Watchpoint)> {
+        self.list.iter().map(|(id, watchpoint)| (*id, watchpoint))
+    }
+
+    //checked before touching the bus so the common case costs nothing
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    pub fn check(&mut self, access: WatchAccess, addr: u16, old: u8, new: u8) {
+        self.record(access, addr, old, new, false);
+    }
+
+    //an access the CPU made while the memory was locked, for writes new is the byte that was dropped
+    pub fn check_blocked(&mut self, access: WatchAccess, addr: u16, old: u8, new: u8) {
+        self.record(access, addr, old, new, true);
+    }
+
+    fn record(&mut self, access: WatchAccess, addr: u16, old: u8, new: u8, blocked: bool) {
+        for (id, watchpoint) in &self.list {
+            if watchpoint.matches(access, addr, new) {
+                self.hits.push(WatchHit { id: *id, access, addr, old, new, pc: 0, instruction: String::new(), blocked });
+            }
+        }
+    }
+
+    pub fn has_hits(&self) -> bool {
+        !self.hits.is_empty()
+    }
+
+    //fill in who made the accesses recorded since the last call
+    pub fn describe_hits(&mut self, pc: u16, instruction: &str) {
+        for hit in self.hits.iter_mut().filter(|hit| hit.instruction.is_empty()) {
+            hit.pc = pc;
+            hit.instruction = instruction.to_string();
+        }
+    }
+
+    pub fn take_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.hits)
+    }
+}
@@ -0,0 +1,530 @@
+use crate::{Gameboy, CpuRegister, Watchpoint, WatchAccess, WatchHit};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, SocketAddr};
+
+//the register file as GDB sees it, each one 16 bit little endian in g/G/p/P packets
+const REGISTERS: [CpuRegister; 6] = [CpuRegister::AF, CpuRegister::BC, CpuRegister::DE, CpuRegister::HL, CpuRegister::SP, CpuRegister::PC];
+type AddressCallback = Box<dyn FnMut(SocketAddr)>;
+//instructions executed between checks for the client asking to stop (ctrl-c), about a frame
+const INTERRUPT_POLL: u32 = 20000;
+//largest packet the client may send or get, framing included. Advertised in qSupported
+const PACKET_SIZE: usize = 0x4000;
+
+//why the target stopped, as a stop reply packet
+enum Stop {
+    Trap,
+    Interrupted,
+    Watchpoint(WatchHit),
+}
+
+impl Stop {
+    fn reply(&self) -> String {
+        match self {
+            Stop::Trap => "S05".to_string(),
+            Stop::Interrupted => "S02".to_string(),
+            Stop::Watchpoint(hit) => {
+                let kind = match hit.access {
+                    WatchAccess::Write => "watch",
+                    WatchAccess::Read => "rwatch",
+                    WatchAccess::Any => "awatch",
+                };
+                format!("T05{}:{:04x};", kind, hit.addr)
+            },
+        }
+    }
+}
+
+//packet framing over the socket: $data#checksum, acknowledged with + or -
+struct Connection {
+    stream: TcpStream,
+    pending: VecDeque<u8>,
+}
+
+impl Connection {
+    fn byte(&mut self) -> io::Result<u8> {
+        if let Some(byte) = self.pending.pop_front() {
+            return Ok(byte);
+        }
+
+        let mut buffer = [0; 1024];
+        let read = self.stream.read(&mut buffer)?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "GDB disconnected"));
+        }
+
+        self.pending.extend(&buffer[..read]);
+        Ok(self.pending.pop_front().unwrap_or(0))
+    }
+
+    //next valid packet, acks are skipped and corrupted packets are asked again
+    fn receive(&mut self) -> io::Result<String> {
+        loop {
+            while self.byte()? != b'$' {}
+
+            let mut data = vec![];
+            loop {
+                match self.byte()? {
+                    b'#' => break,
+                    byte => data.push(byte)
+                }
+            }
+
+            let checksum = [self.byte()?, self.byte()?];
+            let expected = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+
+            if std::str::from_utf8(&checksum).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) == Some(expected) {
+                self.stream.write_all(b"+")?;
+                return Ok(String::from_utf8_lossy(&data).into_owned());
+            }
+
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        self.stream.write_all(format!("${}#{:02x}", data, checksum).as_bytes())?;
+        self.stream.flush()
+    }
+
+    //true if the client sent a break (0x03) while the target runs, anything else is kept for later
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+
+        let mut buffer = [0; 1024];
+        let result = self.stream.read(&mut buffer);
+
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(0) => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "GDB disconnected")),
+            Ok(read) => {
+                self.pending.extend(&buffer[..read]);
+
+                match self.pending.iter().position(|&byte| byte == 0x03) {
+                    Some(i) => {
+                        self.pending.remove(i);
+                        Ok(true)
+                    },
+                    None => Ok(false)
+                }
+            },
+            Err(er) if er.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(er) => Err(er)
+        }
+    }
+}
+
+//GDB remote serial protocol server, so GDB or any other RSP client can drive the emulator.
+//Supports g/G, p/P, m/M, Z0/Z1 breakpoints, Z2-Z4 watchpoints, s and c
+#[derive(Default)]
+pub struct GdbStub {
+    breakpoints: Vec<u16>,
+    watchpoints: Vec<(char, u16, u16, usize)>,    //Z type, address and length as given by the client, and the watchpoint id
+    on_listen: Option<AddressCallback>,
+    on_connect: Option<AddressCallback>,
+}
+
+impl GdbStub {
+    pub fn new() -> Self {
+        GdbStub::default()
+    }
+
+    //called with the address listen is bound to, before it waits for a client
+    pub fn on_listen<F: FnMut(SocketAddr) + 'static>(mut self, callback: F) -> Self {
+        self.on_listen = Some(Box::new(callback));
+        self
+    }
+
+    //called with the client's address when it connects
+    pub fn on_connect<F: FnMut(SocketAddr) + 'static>(mut self, callback: F) -> Self {
+        self.on_connect = Some(Box::new(callback));
+        self
+    }
+
+    //wait for a client on localhost:port and serve it until it detaches, kills the target or disconnects
+    pub fn listen(&mut self, gameboy: &mut Gameboy, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        if let Some(callback) = &mut self.on_listen {
+            callback(listener.local_addr()?);
+        }
+
+        let (stream, addr) = listener.accept()?;
+        if let Some(callback) = &mut self.on_connect {
+            callback(addr);
+        }
+
+        self.serve(gameboy, stream)
+    }
+
+    pub fn serve(&mut self, gameboy: &mut Gameboy, stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let mut connection = Connection { stream, pending: VecDeque::new() };
+
+        let result = self.session(gameboy, &mut connection);
+
+        //leave no watchpoints behind for whoever uses the Gameboy next
+        for (_, _, _, id) in self.watchpoints.drain(..) {
+            gameboy.remove_watchpoint(id);
+        }
+        self.breakpoints.clear();
+
+        match result {
+            Err(er) if er.kind() == io::ErrorKind::UnexpectedEof => Ok(()),
+            result => result
+        }
+    }
+
+    fn session(&mut self, gameboy: &mut Gameboy, connection: &mut Connection) -> io::Result<()> {
+        loop {
+            let packet = connection.receive()?;
+
+            match packet.as_bytes().first() {
+                Some(b'k') => return Ok(()),
+                Some(b'D') => {
+                    connection.send("OK")?;
+                    return Ok(());
+                },
+                Some(b's') => {
+                    resume_at(gameboy, &packet[1..]);
+                    let stop = step(gameboy);
+                    connection.send(&stop.reply())?;
+                },
+                Some(b'c') => {
+                    resume_at(gameboy, &packet[1..]);
+                    let stop = self.continue_running(gameboy, connection)?;
+                    connection.send(&stop.reply())?;
+                },
+                _ => {
+                    let reply = self.handle(gameboy, &packet);
+                    connection.send(&reply)?;
+                }
+            }
+        }
+    }
+
+    //reply to every packet that doesn't run the target. Unsupported packets get the empty reply
+    fn handle(&mut self, gameboy: &mut Gameboy, packet: &str) -> String {
+        let (command, args) = match packet.char_indices().nth(1) {
+            Some((i, _)) => packet.split_at(i),
+            None => (packet, "")
+        };
+
+        let reply = match command {
+            "?" => Some(Stop::Trap.reply()),
+            "g" => Some(REGISTERS.iter().map(|&register| hex_short(gameboy.register(register))).collect()),
+            "G" => write_registers(gameboy, args),
+            "p" => parse_hex(args)
+                .and_then(|n| REGISTERS.get(n as usize))
+                .map(|&register| hex_short(gameboy.register(register))),
+            "P" => write_register(gameboy, args),
+            "m" => read_memory(gameboy, args),
+            "M" => write_memory(gameboy, args),
+            "Z" => self.insert_point(gameboy, args),
+            "z" => self.remove_point(gameboy, args),
+            "H" => Some("OK".to_string()),
+            "q" if args.starts_with("Supported") => Some(format!("PacketSize={:x}", PACKET_SIZE)),
+            "q" if args == "Attached" => Some("1".to_string()),
+            "q" if args == "C" => Some("QC1".to_string()),
+            "q" if args == "fThreadInfo" => Some("m1".to_string()),
+            "q" if args == "sThreadInfo" => Some("l".to_string()),
+            _ => Some(String::new())
+        };
+
+        reply.unwrap_or_else(|| "E01".to_string())
+    }
+
+    //Z0 and Z1 are breakpoints, Z2 Z3 and Z4 write, read and access watchpoints
+    fn insert_point(&mut self, gameboy: &mut Gameboy, args: &str) -> Option<String> {
+        let (kind, addr, length) = parse_point(args)?;
+
+        match kind {
+            '0' | '1' => {
+                if !self.breakpoints.contains(&addr) {
+                    self.breakpoints.push(addr);
+                }
+            },
+            '2' | '3' | '4' => {
+                let access = match kind {
+                    '2' => WatchAccess::Write,
+                    '3' => WatchAccess::Read,
+                    _ => WatchAccess::Any
+                };
+                let end = addr.saturating_add(length.max(1) - 1);
+                let id = gameboy.add_watchpoint(Watchpoint::new(access, addr ..= end));
+
+                self.watchpoints.push((kind, addr, length, id));
+            },
+            _ => return Some(String::new())
+        }
+
+        Some("OK".to_string())
+    }
+
+    fn remove_point(&mut self, gameboy: &mut Gameboy, args: &str) -> Option<String> {
+        let (kind, addr, length) = parse_point(args)?;
+
+        match kind {
+            '0' | '1' => self.breakpoints.retain(|&breakpoint| breakpoint != addr),
+            '2' | '3' | '4' => {
+                if let Some(i) = self.watchpoints.iter().position(|&(k, a, l, _)| (k, a, l) == (kind, addr, length)) {
+                    let (_, _, _, id) = self.watchpoints.remove(i);
+                    gameboy.remove_watchpoint(id);
+                }
+            },
+            _ => return Some(String::new())
+        }
+
+        Some("OK".to_string())
+    }
+
+    //run until a breakpoint or watchpoint is hit, or the client sends a break
+    fn continue_running(&mut self, gameboy: &mut Gameboy, connection: &mut Connection) -> io::Result<Stop> {
+        //the instruction under the current breakpoint runs first, otherwise continue would never move
+        if let Some(stop) = execute_instruction(gameboy) { return Ok(stop); }
+
+        let mut count = 0;
+
+        loop {
+            if self.breakpoints.contains(&gameboy.pc()) { return Ok(Stop::Trap); }
+
+            if let Some(stop) = execute_instruction(gameboy) { return Ok(stop); }
+
+            count += 1;
+            if count == INTERRUPT_POLL {
+                count = 0;
+                if connection.interrupted()? { return Ok(Stop::Interrupted); }
+            }
+        }
+    }
+}
+
+//s and c can carry the address to resume from
+fn resume_at(gameboy: &mut Gameboy, args: &str) {
+    if let Some(addr) = parse_hex(args) {
+        gameboy.set_register(CpuRegister::PC, addr as u16);
+    }
+}
+
+fn step(gameboy: &mut Gameboy) -> Stop {
+    execute_instruction(gameboy).unwrap_or(Stop::Trap)
+}
+
+fn execute_instruction(gameboy: &mut Gameboy) -> Option<Stop> {
+    gameboy.step_instruction();
+
+    gameboy.take_watch_hits().into_iter().next().map(Stop::Watchpoint)
+}
+
+fn write_registers(gameboy: &mut Gameboy, args: &str) -> Option<String> {
+    let bytes = parse_bytes(args)?;
+    if bytes.len() < REGISTERS.len() * 2 { return None; }
+
+    for (register, value) in REGISTERS.iter().zip(bytes.chunks(2)) {
+        gameboy.set_register(*register, u16::from_le_bytes([value[0], value[1]]));
+    }
+
+    Some("OK".to_string())
+}
+
+fn write_register(gameboy: &mut Gameboy, args: &str) -> Option<String> {
+    let (number, value) = args.split_once('=')?;
+    let register = REGISTERS.get(parse_hex(number)? as usize)?;
+
+    let bytes = parse_bytes(value)?;
+    let value = match bytes.as_slice() {
+        [low] => *low as u16,
+        [low, high, ..] => u16::from_le_bytes([*low, *high]),
+        [] => return None
+    };
+
+    gameboy.set_register(*register, value);
+
+    Some("OK".to_string())
+}
+
+//longer reads are cut to what fits in a packet, GDB asks for the rest
+fn read_memory(gameboy: &mut Gameboy, args: &str) -> Option<String> {
+    let (addr, length) = args.split_once(',')?;
+    let addr = parse_hex(addr)? as u16;
+    //2 hex digits a byte, minus the $ and #checksum around them
+    let length = parse_hex(length)?.min(((PACKET_SIZE - 4) / 2) as u32);
+
+    Some((0..length).map(|offset| format!("{:02x}", gameboy.peek(addr.wrapping_add(offset as u16)))).collect())
+}
+
+fn write_memory(gameboy: &mut Gameboy, args: &str) -> Option<String> {
+    let (location, data) = args.split_once(':')?;
+    let (addr, length) = location.split_once(',')?;
+    let addr = parse_hex(addr)? as u16;
+    let length = parse_hex(length)? as usize;
+
+    let bytes = parse_bytes(data)?;
+    if bytes.len() != length { return None; }
+
+    for (offset, byte) in bytes.iter().enumerate() {
+        gameboy.poke(addr.wrapping_add(offset as u16), *byte);
+    }
+
+    Some("OK".to_string())
+}
+
+//type,addr,kind of a Z or z packet. For watchpoints kind is the length in bytes
+fn parse_point(args: &str) -> Option<(char, u16, u16)> {
+    let mut fields = args.split(',');
+
+    let kind = fields.next()?.chars().next()?;
+    let addr = parse_hex(fields.next()?)? as u16;
+    let length = fields.next().and_then(|length| parse_hex(length.split(';').next()?)).unwrap_or(1) as u16;
+
+    Some((kind, addr, length))
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn parse_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) { return None; }
+
+    (0..text.len()).step_by(2)
+        .map(|i| text.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
+
+//16 bit value as GDB expects it, little endian
+fn hex_short(value: u16) -> String {
+    let [low, high] = value.to_le_bytes();
+    format!("{:02x}{:02x}", low, high)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::{self, JoinHandle};
+
+    //a stub serving an empty ROM on a thread with the boot ROM unmapped, and the client's end of the socket
+    fn serving() -> (TcpStream, JoinHandle<()>) {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let mut rom = vec![0; 0x8000];
+            rom[0x14D] = rom[0x134..0x14D].iter().fold(0u8, |x, byte| x.wrapping_sub(*byte).wrapping_sub(1));
+            let mut gameboy = Gameboy::builder(rom).build().unwrap();
+            gameboy.poke(0xFF50, 1);
+
+            let (stream, _) = listener.accept().unwrap();
+            GdbStub::new().serve(&mut gameboy, stream).unwrap();
+        });
+
+        //a test that goes wrong fails instead of waiting forever for a reply
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+        (stream, server)
+    }
+
+    fn read_byte(stream: &mut TcpStream) -> u8 {
+        let mut byte = [0];
+        stream.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+
+    //send raw bytes, returns the ack
+    fn send_raw(stream: &mut TcpStream, bytes: &[u8]) -> u8 {
+        stream.write_all(bytes).unwrap();
+        read_byte(stream)
+    }
+
+    fn send(stream: &mut TcpStream, data: &str) {
+        let checksum = data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte));
+        assert_eq!(send_raw(stream, format!("${}#{:02x}", data, checksum).as_bytes()), b'+');
+    }
+
+    //the next packet, checking its framing and checksum
+    fn reply(stream: &mut TcpStream) -> String {
+        assert_eq!(read_byte(stream), b'$');
+        let mut reply = vec![];
+        loop {
+            match read_byte(stream) {
+                b'#' => break,
+                byte => reply.push(byte)
+            }
+        }
+        let hex = [read_byte(stream), read_byte(stream)];
+        let checksum = reply.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        assert_eq!(std::str::from_utf8(&hex).unwrap(), format!("{:02x}", checksum));
+
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    fn request(stream: &mut TcpStream, data: &str) -> String {
+        send(stream, data);
+        reply(stream)
+    }
+
+    #[test]
+    fn packets_are_framed_and_checked() {
+        let (mut stream, server) = serving();
+
+        //a bad checksum is asked again, garbage and acks before a packet are skipped
+        assert_eq!(send_raw(&mut stream, b"$g#00"), b'-');
+        stream.write_all(b"++xx").unwrap();
+        assert_eq!(request(&mut stream, "qSupported:multiprocess+"), "PacketSize=4000");
+        assert_eq!(request(&mut stream, "vMustReplyEmpty"), "");
+        assert_eq!(request(&mut stream, "g").len(), REGISTERS.len() * 4);
+
+        request(&mut stream, "D");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn memory_reads_and_writes() {
+        let (mut stream, server) = serving();
+
+        assert_eq!(request(&mut stream, "Mc000,3:0102ab"), "OK");
+        assert_eq!(request(&mut stream, "mc000,3"), "0102ab");
+        assert_eq!(request(&mut stream, "mbfff,2"), "ff01");
+
+        //the length has to match the data
+        assert_eq!(request(&mut stream, "Mc000,2:010203"), "E01");
+        assert_eq!(request(&mut stream, "Mc000,1:0"), "E01");
+        assert_eq!(request(&mut stream, "mc000"), "E01");
+
+        //the reply fits in PacketSize with its framing
+        let reply = request(&mut stream, "m0,10000");
+        assert_eq!(reply.len(), PACKET_SIZE - 4);
+
+        send(&mut stream, "k");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn breakpoints_and_watchpoints_stop_continue() {
+        let (mut stream, server) = serving();
+
+        //LD A,$05; LD ($C000),A; NOP; JR @
+        assert_eq!(request(&mut stream, "Mc100,8:3e05ea00c00018fe"), "OK");
+        assert_eq!(request(&mut stream, "Z2,c000,1"), "OK");
+        assert_eq!(request(&mut stream, "Z0,c106,1"), "OK");
+
+        assert_eq!(request(&mut stream, "cc100"), "T05watch:c000;");
+        assert_eq!(request(&mut stream, "mc000,1"), "05");
+
+        //PC is register 5
+        assert_eq!(request(&mut stream, "c"), "S05");
+        assert_eq!(request(&mut stream, "p5"), "06c1");
+
+        //without them it runs until ctrl-c
+        assert_eq!(request(&mut stream, "z2,c000,1"), "OK");
+        assert_eq!(request(&mut stream, "z0,c106,1"), "OK");
+        send(&mut stream, "cc100");
+        stream.write_all(&[0x03]).unwrap();
+        assert_eq!(reply(&mut stream), "S02");
+
+        send(&mut stream, "k");
+        server.join().unwrap();
+    }
+}
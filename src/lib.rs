@@ -0,0 +1,15 @@
+//the emulator core favours explicit returns and hardware names (CPU, GPU, MBC1...) as identifiers
+#![allow(clippy::needless_return, clippy::upper_case_acronyms)]
+
+mod emulator;
+pub mod wav;
+pub mod png;
+pub mod rewind;
+pub mod debugger;
+pub mod gdb;
+pub mod disasm;
+pub mod symbols;
+pub mod test_runner;
+pub mod link;
+pub mod printer;
+pub use emulator::{Gameboy, GameboyBuilder, Cartridge, CartridgeError, StateError, CpuRegister, Watchpoint, WatchAccess, WatchHit, TraceOptions, SerialDevice, Disconnected, SerialCapture, SCREEN_WIDTH, SCREEN_HEIGHT, FRAME_CYCLES};
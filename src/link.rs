@@ -0,0 +1,358 @@
+use crate::{Gameboy, SerialDevice, FRAME_CYCLES};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::rc::Rc;
+
+//Both sides stop to swap a message every SYNC_CYCLES of their own cycles, so what one side sees of the other only
+//depends on how many cycles each ran and transfers complete the same way every time.
+//The message from one sync is used by the other side at the next one, so neither has to wait for the other to catch up.
+//A sync per bit of the internal clock lets a side that waits again right after a transfer be seen in time for the next one
+pub const SYNC_CYCLES: u32 = 512;
+//sent by both sides when they connect, followed by SYNC_CYCLES
+const MAGIC: &[u8; 4] = b"GBLK";
+
+type DisconnectCallback = Box<dyn FnMut(&io::Error)>;
+
+//what a side tells the other at a sync
+#[derive(Copy, Clone, Default, Debug)]
+struct Message {
+    waiting: Option<u8>,                    //SB, if it waits for the other side to clock a transfer
+    sent: Option<u8>,                       //byte it clocked out to the other side since the last sync
+    received: u8,                           //count of bytes the other side clocked in, wrapping
+}
+
+impl Message {
+    fn encode(&self) -> [u8; 4] {
+        let flags = self.waiting.is_some() as u8 | (self.sent.is_some() as u8) << 1; //>
+        [flags, self.waiting.unwrap_or(0xFF), self.sent.unwrap_or(0xFF), self.received]
+    }
+
+    fn decode(bytes: [u8; 4]) -> Self {
+        Message {
+            waiting: if bytes[0] & 1 != 0 { Some(bytes[1]) } else { None },
+            sent: if bytes[0] & 2 != 0 { Some(bytes[2]) } else { None },
+            received: bytes[3],
+        }
+    }
+}
+
+//how messages get to the other side
+trait Wire {
+    fn send(&mut self, message: [u8; 4]) -> io::Result<()>;
+    fn receive(&mut self) -> io::Result<[u8; 4]>;
+}
+
+//a socket to the other emulator
+struct StreamWire<S: Read + Write> {
+    stream: S,
+}
+
+impl<S: Read + Write> Wire for StreamWire<S> {
+    fn send(&mut self, message: [u8; 4]) -> io::Result<()> {
+        self.stream.write_all(&message)?;
+        self.stream.flush()
+    }
+
+    fn receive(&mut self) -> io::Result<[u8; 4]> {
+        let mut message = [0; 4];
+        self.stream.read_exact(&mut message)?;
+        Ok(message)
+    }
+}
+
+//messages between two Game Boys of the same process, queues[side] holds the ones side sent
+#[derive(Default)]
+struct Mailboxes {
+    queues: [VecDeque<[u8; 4]>; 2],
+    sent: [u64; 2],
+}
+
+struct LocalWire {
+    mailboxes: Rc<RefCell<Mailboxes>>,
+    side: usize,
+}
+
+impl Wire for LocalWire {
+    fn send(&mut self, message: [u8; 4]) -> io::Result<()> {
+        let mut mailboxes = self.mailboxes.borrow_mut();
+        mailboxes.queues[self.side].push_back(message);
+        mailboxes.sent[self.side] += 1;
+        Ok(())
+    }
+
+    fn receive(&mut self) -> io::Result<[u8; 4]> {
+        self.mailboxes.borrow_mut().queues[1 - self.side].pop_front().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::WouldBlock, "the other Game Boy fell behind, run both with LocalLink::run_frame")
+        })
+    }
+}
+
+//One end of a link cable, plugged into a Game Boy with Gameboy::connect_serial.
+//The other end is another emulator, in this process (LocalLink) or another one (listen/connect)
+pub struct LinkPort {
+    wire: Option<Box<dyn Wire>>,            //None once the cable is disconnected
+    cycles: u32,                            //since the last sync
+    synced: bool,                           //a message was sent already, so there's one to receive at the next sync
+    waiting: Option<u8>,                    //SB while the Game Boy waits for an external clock, as of the last step
+    sent: Option<u8>,
+    sent_count: u8,
+    received: u8,
+    peer_waiting: Option<u8>,
+    incoming: Option<u8>,                   //byte the other side clocked in, delivered when the Game Boy waits for it
+    on_disconnect: Option<DisconnectCallback>,
+}
+
+impl LinkPort {
+    fn new(wire: Box<dyn Wire>) -> Self {
+        LinkPort {
+            wire: Some(wire),
+            cycles: 0,
+            synced: false,
+            waiting: None,
+            sent: None,
+            sent_count: 0,
+            received: 0,
+            peer_waiting: None,
+            incoming: None,
+            on_disconnect: None,
+        }
+    }
+
+    //link over any stream to another LinkPort, checking the other side speaks the same protocol
+    pub fn over_stream<S: Read + Write + 'static>(mut stream: S) -> io::Result<Self> {
+        let mut hello = MAGIC.to_vec();
+        hello.extend_from_slice(&SYNC_CYCLES.to_le_bytes());
+        stream.write_all(&hello)?;
+        stream.flush()?;
+
+        let mut answer = [0; 8];
+        stream.read_exact(&mut answer)?;
+        if answer[..] != hello[..] {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "the other side isn't a compatible link cable"));
+        }
+
+        Ok(LinkPort::new(Box::new(StreamWire { stream })))
+    }
+
+    //wait for the other emulator to connect to localhost:port, on_listen gets the bound address before waiting
+    pub fn listen<F: FnOnce(SocketAddr)>(port: u16, on_listen: F) -> io::Result<Self> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        on_listen(listener.local_addr()?);
+
+        let (stream, _) = listener.accept()?;
+
+        stream.set_nodelay(true)?;
+        LinkPort::over_stream(stream)
+    }
+
+    //connect to the emulator listening on localhost:port
+    pub fn connect(port: u16) -> io::Result<Self> {
+        let stream = TcpStream::connect(("127.0.0.1", port))?;
+        stream.set_nodelay(true)?;
+        LinkPort::over_stream(stream)
+    }
+
+    //wait for the other emulator to connect to the Unix socket at path, on_listen is called once it's bound
+    #[cfg(unix)]
+    pub fn listen_unix<F: FnOnce(&std::path::Path)>(path: &std::path::Path, on_listen: F) -> io::Result<Self> {
+        let listener = std::os::unix::net::UnixListener::bind(path)?;
+        on_listen(path);
+
+        let (stream, _) = listener.accept()?;
+
+        //the socket file is only needed to connect
+        std::fs::remove_file(path)?;
+        LinkPort::over_stream(stream)
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix(path: &std::path::Path) -> io::Result<Self> {
+        LinkPort::over_stream(std::os::unix::net::UnixStream::connect(path)?)
+    }
+
+    //called once if the connection fails, the port then behaves like an unplugged cable
+    pub fn on_disconnect<F: FnMut(&io::Error) + 'static>(mut self, callback: F) -> Self {
+        self.on_disconnect = Some(Box::new(callback));
+        self
+    }
+
+    fn sync(&mut self, waiting: Option<u8>) {
+        let wire = match &mut self.wire {
+            Some(wire) => wire,
+            None => return
+        };
+
+        let message = Message { waiting, sent: self.sent.take(), received: self.received };
+        let synced = self.synced;
+
+        let result = wire.send(message.encode()).and_then(|_| {
+            if synced { wire.receive().map(Some) } else { Ok(None) }
+        });
+
+        match result {
+            Ok(Some(bytes)) => {
+                let peer = Message::decode(bytes);
+
+                //until the other side took every byte sent to it, its SB is the one from before
+                self.peer_waiting = if peer.received == self.sent_count { peer.waiting } else { None };
+
+                if peer.sent.is_some() {
+                    self.incoming = peer.sent;
+                }
+            },
+            Ok(None) => {},
+            Err(er) => {
+                self.wire = None;
+                self.peer_waiting = None;
+                self.incoming = None;
+
+                if let Some(callback) = &mut self.on_disconnect {
+                    callback(&er);
+                }
+            }
+        }
+
+        self.synced = true;
+    }
+}
+
+impl SerialDevice for LinkPort {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        match self.peer_waiting.take() {
+            Some(theirs) => {
+                self.sent = Some(byte);
+                self.sent_count = self.sent_count.wrapping_add(1);
+                theirs
+            },
+            //nobody is ready on the other side, the data line stays high
+            None => 0xFF
+        }
+    }
+
+    fn external_clock(&mut self, byte: u8) -> Option<u8> {
+        match self.incoming.take() {
+            Some(theirs) => {
+                self.received = self.received.wrapping_add(1);
+                Some(theirs)
+            },
+            None => {
+                self.waiting = Some(byte);
+                None
+            }
+        }
+    }
+
+    fn step(&mut self, cycles: u8) {
+        let waiting = self.waiting.take();
+        self.cycles += cycles as u32;
+
+        if self.cycles >= SYNC_CYCLES {
+            self.cycles -= SYNC_CYCLES;
+            self.sync(waiting);
+        }
+    }
+}
+
+//A link cable between two Game Boys of the same process, run in lockstep
+pub struct LocalLink {
+    mailboxes: Rc<RefCell<Mailboxes>>,
+}
+
+impl LocalLink {
+    //plug the cable into both, replacing whatever was connected
+    pub fn connect(a: &mut Gameboy, b: &mut Gameboy) -> Self {
+        let mailboxes = Rc::new(RefCell::new(Mailboxes::default()));
+
+        a.connect_serial(Box::new(LinkPort::new(Box::new(LocalWire { mailboxes: mailboxes.clone(), side: 0 }))));
+        b.connect_serial(Box::new(LinkPort::new(Box::new(LocalWire { mailboxes: mailboxes.clone(), side: 1 }))));
+
+        LocalLink { mailboxes }
+    }
+
+    //run both until each made a frame worth of cycles, returns the cycles of each.
+    //The one that synced less runs next so the message it needs is always there; either can overshoot by up to a sync
+    pub fn run_frame(&self, a: &mut Gameboy, b: &mut Gameboy) -> (u32, u32) {
+        let (mut cycles_a, mut cycles_b) = (0, 0);
+
+        while cycles_a < FRAME_CYCLES || cycles_b < FRAME_CYCLES {
+            let [sent_a, sent_b] = self.mailboxes.borrow().sent;
+
+            if sent_a <= sent_b {
+                cycles_a += a.step_instruction() as u32;
+            } else {
+                cycles_b += b.step_instruction() as u32;
+            }
+        }
+
+        (cycles_a, cycles_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CpuRegister;
+
+    //A Game Boy that waits `delay` loops of 16 cycles, puts sb in SB, starts a transfer with sc and counts in B how many
+    //times it polls SC until the transfer is done. The program runs from WRAM with the boot ROM unmapped
+    fn polling(delay: u8, sb: u8, sc: u8) -> Gameboy {
+        let mut rom = vec![0; 0x8000];
+        rom[0x14D] = rom[0x134..0x14D].iter().fold(0u8, |x, byte| x.wrapping_sub(*byte).wrapping_sub(1));
+        let mut gameboy = Gameboy::builder(rom).build().unwrap();
+        gameboy.poke(0xFF50, 1);
+
+        //LD B,0; LD C,delay; wait: DEC C; JR NZ,wait; LD A,sb; LDH (SB),A; LD A,sc; LDH (SC),A
+        //poll: INC B; LDH A,(SC); BIT 7,A; JR NZ,poll; JR @
+        let program = [0x06, 0x00, 0x0E, delay, 0x0D, 0x20, 0xFD, 0x3E, sb, 0xE0, 0x01, 0x3E, sc, 0xE0, 0x02,
+            0x04, 0xF0, 0x02, 0xCB, 0x7F, 0x20, 0xF9, 0x18, 0xFE];
+        for (offset, byte) in program.iter().enumerate() {
+            gameboy.poke(0xC000 + offset as u16, *byte);
+        }
+        gameboy.set_register(CpuRegister::PC, 0xC000);
+        gameboy
+    }
+
+    //SB, the serial interrupt request and the polls of a side once its transfer is done
+    fn result(gameboy: &mut Gameboy) -> (u8, bool, u16) {
+        (gameboy.peek(0xFF01), gameboy.peek(0xFF0F) & 0x08 != 0, gameboy.register(CpuRegister::B))
+    }
+
+    #[test]
+    fn local_link_swaps_a_byte() {
+        //b waits for a transfer, a clocks it once b had the time to be seen waiting
+        let mut a = polling(0, 0x42, 0x81);
+        let mut b = polling(1, 0x99, 0x80);
+        let link = LocalLink::connect(&mut a, &mut b);
+        link.run_frame(&mut a, &mut b);
+
+        //a polls for the 4096 cycles of its transfer, b until the byte reaches it at a sync after a started
+        assert_eq!(result(&mut a), (0x99, true, 115));
+        assert_eq!(result(&mut b), (0x42, true, 141));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn socket_link_matches_the_local_one() {
+        let (socket_a, socket_b) = std::os::unix::net::UnixStream::pair().unwrap();
+
+        //each side waits for the other's messages, so b runs on its own thread
+        let other = std::thread::spawn(move || {
+            let mut b = polling(1, 0x99, 0x80);
+            b.connect_serial(Box::new(LinkPort::over_stream(socket_b).unwrap()));
+            b.run_frame();
+            result(&mut b)
+        });
+
+        let mut a = polling(0, 0x42, 0x81);
+        a.connect_serial(Box::new(LinkPort::over_stream(socket_a).unwrap()));
+        a.run_frame();
+        let result_a = result(&mut a);
+
+        //b may be a sync ahead and wait for a message that never comes, closing the socket lets it finish
+        drop(a);
+        assert_eq!(result_a, (0x99, true, 115));
+        assert_eq!(other.join().unwrap(), (0x42, true, 141));
+    }
+}
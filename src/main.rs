@@ -1,15 +1,34 @@
-mod emulator;
-use emulator::Gameboy;
+use rusty_gb::{Gameboy, TraceOptions, SCREEN_WIDTH as WIDTH, SCREEN_HEIGHT as HEIGHT};
+use rusty_gb::rewind::Rewind;
+use rusty_gb::debugger::Debugger;
+use rusty_gb::gdb::GdbStub;
+use rusty_gb::disasm::{disassemble_rom, rom_banks};
+use rusty_gb::symbols::Symbols;
+use rusty_gb::test_runner::{TestRunner, TestResult};
+use rusty_gb::link::LinkPort;
+use rusty_gb::printer::Printer;
 use minifb::{Key, Window, WindowOptions};
 use std::time::{Duration, Instant};
 use std::thread;
+use std::path::Path;
+use std::sync::atomic::Ordering;
 
-const WIDTH: usize = 160;
-const HEIGHT: usize = 144;
-const MAXCYCLES: u32 = 66576;
+//frames recorded by --wav when --frames isn't given (10 seconds)
+const DEFAULT_WAV_FRAMES: u32 = 600;
+//rewind history, can be changed with --rewind-seconds and --rewind-mb
+const DEFAULT_REWIND_SECONDS: u32 = 60;
+const DEFAULT_REWIND_MB: usize = 64;
+//frames between rewind snapshots, the ones in between are emulated again when going back
+const REWIND_INTERVAL: u32 = 4;
+//frames --trace runs for at most when --frames isn't given (10 seconds)
+const DEFAULT_TRACE_FRAMES: u32 = 600;
+//cycles a test ROM gets by default before it times out (two minutes)
+const DEFAULT_TEST_CYCLES: u64 = 120 * 4194304;
+//port --gdb listens on when --gdb-port isn't given
+const DEFAULT_GDB_PORT: u16 = 2345;
 
 fn create_window() -> Window {
-    let win = Window::new(
+    Window::new(
         "Rusty GB",
         WIDTH,
         HEIGHT,
@@ -24,9 +43,7 @@ fn create_window() -> Window {
     )
     .unwrap_or_else(|e| {
         panic!("{}", e);
-    });
-
-    return win;
+    })
 }
 
 fn get_input(window: &Window, system: &mut Gameboy) {
@@ -53,29 +70,324 @@ fn get_input(window: &Window, system: &mut Gameboy) {
     );
 }
 
-fn main(){
-    let mut system = Gameboy::default();
+//value following a flag, like the file in "--wav file"
+fn arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a String> {
+    args.iter().position(|arg| arg == flag).and_then(|i| args.get(i + 1))
+}
 
-    //panics if a char is not valid unicode
-    let args: Vec<_> = std::env::args().collect();
+//numeric value of a flag, or the default if it's not there
+fn arg_number<T: std::str::FromStr>(args: &[String], flag: &str, default: T) -> T {
+    match arg_value(args, flag) {
+        Some(value) => value.parse().unwrap_or_else(|_| {
+            println!("Invalid value \"{}\" for {}. Exiting", value, flag);
+            std::process::exit(1);
+        }),
+        None => default
+    }
+}
 
-    system.insert(args[1].to_string());
-    
-    let debug = args.contains(&"-d".to_string());
+//render the audio without opening a window
+fn record_wav(system: &mut Gameboy, args: &[String], file: &str) {
+    let frames = arg_number(args, "--frames", DEFAULT_WAV_FRAMES);
+
+    let split = args.contains(&"--split-channels".to_string());
+
+    if let Err(er) = system.record_audio(frames, Path::new(file), split) {
+        println!("{}. Exiting", er);
+        std::process::exit(1);
+    }
+}
+
+//address given in hexadecimal, with or without a $ or 0x prefix
+fn parse_address(text: &str) -> Option<u16> {
+    let hex = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")).unwrap_or(text);
+    u16::from_str_radix(hex, 16).ok()
+}
+
+//hexadecimal address of a flag
+fn arg_address(args: &[String], flag: &str) -> Option<u16> {
+    arg_value(args, flag).map(|value| parse_address(value).unwrap_or_else(|| {
+        println!("Invalid address \"{}\" for {}. Exiting", value, flag);
+        std::process::exit(1);
+    }))
+}
+
+//--link-listen or --link-connect with a localhost port, or a Unix socket path, plugs a link cable to another rusty_gb
+fn connect_link(system: &mut Gameboy, args: &[String]) {
+    let port = match (arg_value(args, "--link-listen"), arg_value(args, "--link-connect")) {
+        (Some(target), _) => match target.parse() {
+            Ok(port) => LinkPort::listen(port, |addr| println!("Waiting for the link cable on {}", addr)),
+            Err(_) => listen_unix(target),
+        },
+        (None, Some(target)) => match target.parse() {
+            Ok(port) => LinkPort::connect(port),
+            Err(_) => connect_unix(target),
+        },
+        (None, None) => return
+    };
+
+    match port {
+        Ok(port) => {
+            println!("Link cable connected");
+            system.connect_serial(Box::new(port.on_disconnect(|er| println!("Link cable disconnected: {}", er))));
+        },
+        Err(er) => {
+            println!("{}. Exiting", er);
+            std::process::exit(1);
+        }
+    }
+}
+
+//--printer dir plugs a Game Boy Printer instead, every page it prints is saved in dir
+fn connect_printer(system: &mut Gameboy, args: &[String]) {
+    if let Some(directory) = arg_value(args, "--printer") {
+        let printer = Printer::new(Path::new(directory)).on_print(|result| match result {
+            Ok(path) => println!("Printed {}", path.display()),
+            Err(er) => println!("Couldn't save the printed page: {}", er)
+        });
+
+        system.connect_serial(Box::new(printer));
+    }
+}
+
+#[cfg(unix)]
+fn listen_unix(path: &str) -> std::io::Result<LinkPort> {
+    LinkPort::listen_unix(Path::new(path), |path| println!("Waiting for the link cable on {}", path.display()))
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> std::io::Result<LinkPort> {
+    LinkPort::connect_unix(Path::new(path))
+}
+
+#[cfg(not(unix))]
+fn listen_unix(target: &str) -> std::io::Result<LinkPort> {
+    Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("Invalid port \"{}\"", target)))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(target: &str) -> std::io::Result<LinkPort> {
+    listen_unix(target)
+}
+
+//log instructions to a file without opening a window, until the trace ends or --frames frames have passed
+fn record_trace(system: &mut Gameboy, args: &[String], file: &str) {
+    let options = TraceOptions {
+        start: arg_address(args, "--trace-start"),
+        stop: arg_address(args, "--trace-stop"),
+        limit: arg_value(args, "--trace-limit").map(|_| arg_number(args, "--trace-limit", 0)),
+    };
+
+    let frames = arg_number(args, "--frames", DEFAULT_TRACE_FRAMES);
+
+    if let Err(er) = system.trace_to_file(Path::new(file), options) {
+        println!("{}: {}. Exiting", file, er);
+        std::process::exit(1);
+    }
+
+    let mut frame = 0;
+    while system.tracing() && frame < frames {
+        system.run_frame();
+        frame += 1;
+    }
+
+    match system.finish_trace() {
+        Ok(lines) => println!("{} instructions written to {}", lines, file),
+        Err(er) => {
+            println!("{}: {}. Exiting", file, er);
+            std::process::exit(1);
+        }
+    }
+}
+
+//run one test ROM, the expected screen hash comes from --screen-hash or name.hash next to the ROM
+fn run_test(path: &Path, args: &[String]) -> bool {
+    let name = path.display();
+
+    let rom = match std::fs::read(path) {
+        Ok(rom) => rom,
+        Err(er) => {
+            println!("ERROR {}: {}", name, er);
+            return false;
+        }
+    };
+
+    let mut system = match Gameboy::builder(rom).build() {
+        Ok(system) => system,
+        Err(er) => {
+            println!("ERROR {}: {}", name, er);
+            return false;
+        }
+    };
+
+    let hash = match arg_value(args, "--screen-hash") {
+        Some(hash) => Some(hash.clone()),
+        None => std::fs::read_to_string(path.with_extension("hash")).ok()
+    };
+    let hash = match hash.map(|hash| u32::from_str_radix(hash.trim(), 16)) {
+        Some(Ok(hash)) => Some(hash),
+        Some(Err(_)) => {
+            println!("ERROR {}: invalid screen hash", name);
+            return false;
+        },
+        None => None
+    };
+
+    let cycles = arg_number(args, "--cycles", DEFAULT_TEST_CYCLES);
+    let report = TestRunner::new(cycles).screen_hash(hash).run(&mut system);
+
+    println!("{} {}", name, report);
+
+    //the last line printed is usually the reason of a failure
+    if report.result != TestResult::Passed {
+        if let Some(line) = report.serial.lines().rev().find(|line| !line.trim().is_empty()) {
+            println!("    {}", line.trim());
+        }
+    }
+
+    report.result == TestResult::Passed
+}
+
+//rusty_gb test <rom or directory> [--cycles N] [--screen-hash hash], exits with 1 if any test didn't pass
+fn run_tests(args: &[String]) {
+    let path = match args.get(2) {
+        Some(path) => Path::new(path),
+        None => {
+            println!("Usage: rusty_gb test <rom or directory> [--cycles N] [--screen-hash hash]");
+            std::process::exit(1);
+        }
+    };
+
+    let roms = if path.is_dir() {
+        let entries = std::fs::read_dir(path).unwrap_or_else(|er| {
+            println!("{}. Exiting", er);
+            std::process::exit(1);
+        });
+
+        let mut roms: Vec<_> = entries.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|file| matches!(file.extension().and_then(|ext| ext.to_str()), Some("gb") | Some("gbc")))
+            .collect();
+        roms.sort();
+        roms
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let passed = roms.iter().filter(|rom| run_test(rom, args)).count();
+
+    println!("{}/{} passed", passed, roms.len());
+
+    if passed != roms.len() {
+        std::process::exit(1);
+    }
+}
+
+//symbols from --sym, or the .sym file next to the ROM (RGBDS writes game.sym next to game.gb)
+fn load_symbols(args: &[String], rom: &Path) -> Symbols {
+    let sym = match arg_value(args, "--sym") {
+        Some(sym) => Some(Path::new(sym).to_path_buf()),
+        None => Some(rom.with_extension("sym")).filter(|sym| sym.exists())
+    };
+
+    match sym {
+        Some(sym) => Symbols::load(&sym).unwrap_or_else(|er| {
+            println!("{}: {}. Exiting", sym.display(), er);
+            std::process::exit(1);
+        }),
+        None => Symbols::new()
+    }
+}
+
+//rusty_gb disasm <rom> [--bank N] [--range start-end] [--sym file]
+fn disasm(args: &[String]) {
+    let path = match args.get(2) {
+        Some(path) => Path::new(path),
+        None => {
+            println!("Usage: rusty_gb disasm <rom> [--bank N] [--range start-end] [--sym file]");
+            std::process::exit(1);
+        }
+    };
+
+    let rom = std::fs::read(path).unwrap_or_else(|er| {
+        println!("{}. Exiting", er);
+        std::process::exit(1);
+    });
+
+    let symbols = load_symbols(args, path);
+
+    let range = arg_value(args, "--range").map(|range| {
+        let parsed = range.split_once('-').and_then(|(start, end)| Some(parse_address(start)? ..= parse_address(end)?));
+
+        parsed.unwrap_or_else(|| {
+            println!("Invalid range \"{}\", expected start-end in hexadecimal. Exiting", range);
+            std::process::exit(1);
+        })
+    });
+
+    //without --bank, a range in the switchable area is read from bank 1 and no range means the whole ROM
+    let banks: Vec<usize> = match (args.iter().any(|arg| arg == "--bank"), &range) {
+        (true, _) => vec![arg_number(args, "--bank", 0)],
+        (false, Some(range)) => vec![if *range.start() >= 0x4000 { 1 } else { 0 }],
+        (false, None) => (0..rom_banks(&rom)).collect()
+    };
+
+    for bank in banks {
+        match disassemble_rom(&rom, bank, range.clone(), &symbols) {
+            Ok(text) => println!("; ROM bank {}\n{}\n", bank, text),
+            Err(er) => {
+                println!("{}. Exiting", er);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+//battery RAM is kept as <title>.sav in the working directory
+fn save_file(system: &Gameboy) -> String {
+    format!("{}.sav", system.title())
+}
+
+fn load_battery_ram(system: &mut Gameboy) {
+    if system.battery_ram().is_none() { return; }
+
+    //no .sav yet the first time a game is played
+    if let Ok(data) = std::fs::read(save_file(system)) {
+        match system.load_battery_ram(&data) {
+            Ok(_) => println!("SRAM data loaded"),
+            Err(er) => println!("WARNING: Loading SRAM data failed -> {}", er)
+        }
+    }
+}
+
+fn save_battery_ram(system: &Gameboy) {
+    if let Some(data) = system.battery_ram() {
+        match std::fs::write(save_file(system), data) {
+            Ok(_) => println!("SRAM data saved"),
+            Err(er) => println!("WARNING: Saving SRAM data failed -> {}", er)
+        }
+    }
+}
+
+fn run_window(system: &mut Gameboy, args: &[String]) {
+    let seconds = arg_number(args, "--rewind-seconds", DEFAULT_REWIND_SECONDS);
+    let megabytes = arg_number(args, "--rewind-mb", DEFAULT_REWIND_MB);
+    let mut rewind = Rewind::new(seconds, REWIND_INTERVAL, megabytes * 1024 * 1024);
 
     let mut window = create_window();
 
     let frame = Duration::new(0, 16600000); // 16.6 ms as nanoseconds
 
-    system.screen = vec![0; WIDTH*HEIGHT];
-
     while window.is_open() && !window.is_key_down(Key::Escape) {
         
         let start = Instant::now();
-        let mut cycles_now = 0;
-        while cycles_now < MAXCYCLES { 
-            cycles_now += system.run(debug) as u32;
-            get_input(&window, &mut system);
+
+        //holding backspace runs the game backwards
+        if window.is_key_down(Key::Backspace) {
+            rewind.step_back(system);
+        } else {
+            get_input(&window, system);
+            system.run_frame();
+            rewind.record(system);
         }
 
         let elapsed = start.elapsed();
@@ -83,7 +395,79 @@ fn main(){
             thread::sleep(frame - elapsed);
         }
 
-        window.update_with_buffer(&system.screen, WIDTH, HEIGHT).unwrap();
+        window.update_with_buffer(system.framebuffer(), WIDTH, HEIGHT).unwrap();
+    }
+}
+
+fn main(){
+    //panics if a char is not valid unicode
+    let args: Vec<_> = std::env::args().collect();
+
+    match args.get(1).map(String::as_str) {
+        Some("disasm") => return disasm(&args),
+        Some("test") => return run_tests(&args),
+        _ => {}
+    }
+
+    let rom = match std::fs::read(&args[1]) {
+        Ok(rom) => rom,
+        Err(er) => {
+            println!("{}. Exiting", er);
+            std::process::exit(1);
+        }
+    };
+    
+    let lenient = args.contains(&"--lenient".to_string());
+    let symbols = load_symbols(&args, Path::new(&args[1]));
+
+    let mut system = match Gameboy::builder(rom).lenient(lenient).symbols(symbols).build() {
+        Ok(system) => system,
+        Err(er) => {
+            println!("{}. Exiting", er);
+            std::process::exit(1);
+        }
+    };
+
+    //print every executed instruction, the boot ROM's aren't worth it
+    if args.contains(&"-d".to_string()) {
+        system.on_instruction(|pc, symbol, text| {
+            if pc > 256 {
+                println!("{:#06x} {:<24} {}", pc, symbol.unwrap_or_default(), text);
+            }
+        });
     }
+    connect_link(&mut system, &args);
+    connect_printer(&mut system, &args);
 
+    if let Some(file) = arg_value(&args, "--wav") {
+        record_wav(&mut system, &args, file);
+    } else if let Some(file) = arg_value(&args, "--trace") {
+        record_trace(&mut system, &args, file);
+    } else if args.contains(&"--debugger".to_string()) {
+        let mut debugger = Debugger::new();
+
+        //ctrl-c stops the emulation and goes back to the prompt instead of killing the process
+        let interrupt = debugger.interrupt_flag();
+        if let Err(er) = ctrlc::set_handler(move || interrupt.store(true, Ordering::Relaxed)) {
+            println!("WARNING: ctrl-c won't stop the debugger -> {}", er);
+        }
+
+        debugger.run(&mut system);
+    } else if args.contains(&"--gdb".to_string()) {
+        let port = arg_number(&args, "--gdb-port", DEFAULT_GDB_PORT);
+
+        let mut stub = GdbStub::new()
+            .on_listen(|addr| println!("Waiting for GDB on {}", addr))
+            .on_connect(|addr| println!("GDB connected from {}", addr));
+
+        if let Err(er) = stub.listen(&mut system, port) {
+            println!("{}. Exiting", er);
+            std::process::exit(1);
+        }
+    } else {
+        //only the windowed run plays the game for real, the other modes leave the .sav alone
+        load_battery_ram(&mut system);
+        run_window(&mut system, &args);
+        save_battery_ram(&system);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,95 @@
+use crate::emulator::crc32;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+//most bytes a stored (uncompressed) deflate block can hold
+const STORED_BLOCK: usize = 0xFFFF;
+
+//write 8 bit grayscale pixels, row after row, as a PNG file
+pub fn write_png(path: &Path, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write_to(&mut file, width, height, pixels)?;
+    file.flush()
+}
+
+pub fn write_to<W: Write>(writer: &mut W, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+    if pixels.len() != (width * height) as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "pixel count doesn't match the image size"));
+    }
+
+    writer.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    //header: size, 8 bit depth, grayscale, deflate, no filter method extensions, not interlaced
+    let mut header = vec![];
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    header.extend_from_slice(&[8, 0, 0, 0, 0]);
+    chunk(writer, b"IHDR", &header)?;
+
+    //every row starts with its filter type, 0 is none
+    let mut raw = Vec::with_capacity(pixels.len() + height as usize);
+    for row in pixels.chunks(width.max(1) as usize) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    chunk(writer, b"IDAT", &zlib_stored(&raw))?;
+    chunk(writer, b"IEND", &[])
+}
+
+fn chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut checked = kind.to_vec();
+    checked.extend_from_slice(data);
+
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(&checked)?;
+    writer.write_all(&crc32(&checked).to_be_bytes())
+}
+
+//zlib stream of uncompressed blocks, a printed page is small enough not to bother compressing
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+
+    let mut blocks = data.chunks(STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let length = block.len() as u16;
+
+        stream.push(last as u8);
+        stream.extend_from_slice(&length.to_le_bytes());
+        stream.extend_from_slice(&(!length).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    b << 16 | a //>
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adler32_matches_known_values() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+        //big enough for both sums to wrap around the modulus
+        assert_eq!(adler32(&[0xFF; 6000]), 0xA49759EA);
+    }
+}
@@ -0,0 +1,359 @@
+use crate::SerialDevice;
+use crate::png::write_png;
+use std::io;
+use std::path::{Path, PathBuf};
+
+//paper width in pixels, 20 tiles
+pub const PRINTER_WIDTH: usize = 160;
+//tile data of a 160x8 strip, 20 tiles of 16 bytes
+const TILE_ROW_BYTES: usize = 20 * 16;
+//a whole 160x144 picture, the most a game sends before printing
+const IMAGE_BYTES: usize = TILE_ROW_BYTES * 18;
+//size of the printer's RAM, anything past it is dropped
+const BUFFER_SIZE: usize = 0x2000;
+//how long the printer reports it's busy after each print, half a second
+const PRINT_CYCLES: u32 = 4194304 / 2;
+//a packet that stops arriving for this long (a tenth of a second) is dropped
+const PACKET_TIMEOUT: u32 = 4194304 / 10;
+//shade of each color once the palette is applied, from white to black
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+
+type PrintCallback = Box<dyn FnMut(io::Result<PathBuf>)>;
+
+//where the next byte goes in a packet: 0x88 0x33, command, compression, length (LE), data, checksum (LE),
+//then two more bytes the printer answers with 0x81 (it's there) and its status
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Receive {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+//Game Boy Printer on the serial port. Printed strips are joined until the game feeds the paper out (a print with
+//a margin after it, or one before the next print). The page is written to the directory as print_NNNN.png after
+//every print, so it's rewritten as it grows and the next page goes to a new file
+pub struct Printer {
+    directory: PathBuf,
+    next_page: u32,
+    page: Option<PathBuf>,                  //file of the page being printed, picked by its first print
+    state: Receive,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16,
+    expected_checksum: u16,
+    buffer: Vec<u8>,                        //tile data received since the last print
+    ended: bool,                            //the game sent an empty data packet, the image is complete
+    checksum_error: bool,
+    busy: u32,                              //cycles until printing ends
+    idle: u32,                              //cycles since the last byte of a packet in progress
+    paper: Vec<u8>,                         //printed rows of the page, 8 bit grayscale
+    on_print: Option<PrintCallback>,
+}
+
+impl Printer {
+    pub fn new(directory: &Path) -> Self {
+        Printer {
+            directory: directory.to_path_buf(),
+            next_page: 1,
+            page: None,
+            state: Receive::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: vec![],
+            checksum: 0,
+            expected_checksum: 0,
+            buffer: vec![],
+            ended: false,
+            checksum_error: false,
+            busy: 0,
+            idle: 0,
+            paper: vec![],
+            on_print: None,
+        }
+    }
+
+    //called with the path every time a page is written, or the error writing it
+    pub fn on_print<F: FnMut(io::Result<PathBuf>) + 'static>(mut self, callback: F) -> Self {
+        self.on_print = Some(Box::new(callback));
+        self
+    }
+
+    fn status(&self) -> u8 {
+        let full = self.ended || self.buffer.len() >= IMAGE_BYTES;
+
+        self.checksum_error as u8
+            | ((self.busy > 0) as u8) << 1          //>
+            | (full as u8) << 2                     //>
+            | (!self.buffer.is_empty() as u8) << 3  //>
+    }
+
+    fn receive(&mut self, byte: u8) {
+        if !matches!(self.state, Receive::ChecksumLow | Receive::ChecksumHigh) {
+            self.checksum = self.checksum.wrapping_add(byte as u16);
+        }
+
+        self.state = match self.state {
+            Receive::Magic1 if byte == 0x88 => Receive::Magic2,
+            Receive::Magic1 => Receive::Magic1,
+            Receive::Magic2 if byte == 0x33 => {
+                self.checksum = 0;
+                self.data.clear();
+                Receive::Command
+            },
+            Receive::Magic2 => Receive::Magic1,
+            Receive::Command => {
+                self.command = byte;
+                Receive::Compression
+            },
+            Receive::Compression => {
+                self.compressed = byte & 1 != 0;
+                Receive::LengthLow
+            },
+            Receive::LengthLow => {
+                self.length = byte as u16;
+                Receive::LengthHigh
+            },
+            Receive::LengthHigh => {
+                self.length |= (byte as u16) << 8; //>
+                if self.length == 0 { Receive::ChecksumLow } else { Receive::Data }
+            },
+            Receive::Data => {
+                self.data.push(byte);
+                if self.data.len() == self.length as usize { Receive::ChecksumLow } else { Receive::Data }
+            },
+            Receive::ChecksumLow => {
+                self.expected_checksum = byte as u16;
+                Receive::ChecksumHigh
+            },
+            Receive::ChecksumHigh => {
+                self.expected_checksum |= (byte as u16) << 8; //>
+                self.checksum_error = self.checksum != self.expected_checksum;
+
+                if !self.checksum_error {
+                    self.execute();
+                }
+                Receive::Alive
+            },
+            Receive::Alive => Receive::Status,
+            Receive::Status => Receive::Magic1,
+        };
+    }
+
+    fn execute(&mut self) {
+        match self.command {
+            INIT => {
+                self.buffer.clear();
+                self.ended = false;
+                self.busy = 0;
+            },
+            DATA if self.data.is_empty() => self.ended = true,
+            DATA => {
+                let data = if self.compressed { decompress(&self.data) } else { std::mem::take(&mut self.data) };
+                let room = BUFFER_SIZE - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..data.len().min(room)]);
+            },
+            PRINT if self.data.len() >= 4 => {
+                let (margins, palette) = (self.data[1], self.data[2]);
+                self.print(margins >> 4, margins & 0x0F, palette); //>
+            },
+            //status requests and anything unknown only get the status back
+            _ => {}
+        }
+    }
+
+    fn print(&mut self, margin_before: u8, margin_after: u8, palette: u8) {
+        if margin_before > 0 {
+            self.feed();
+        }
+
+        //games that don't care about the palette send 0, which prints like the usual 0xE4
+        let palette = if palette == 0 { 0xE4 } else { palette };
+
+        for tile_row in self.buffer.chunks_exact(TILE_ROW_BYTES) {
+            for line in 0..8 {
+                for x in 0..PRINTER_WIDTH {
+                    let tile = &tile_row[(x / 8) * 16 ..];
+                    let (low, high) = (tile[line * 2], tile[line * 2 + 1]);
+                    let bit = 7 - (x % 8);
+                    let color = (low >> bit) & 1 | ((high >> bit) & 1) << 1; //>
+
+                    self.paper.push(SHADES[((palette >> (color * 2)) & 3) as usize]); //>
+                }
+            }
+        }
+
+        self.buffer.clear();
+        self.ended = false;
+        self.busy = PRINT_CYCLES;
+
+        if !self.paper.is_empty() {
+            let result = self.write_page();
+
+            if let Some(callback) = &mut self.on_print {
+                callback(result);
+            }
+        }
+
+        if margin_after > 0 {
+            self.feed();
+        }
+    }
+
+    //the page is done, whatever is printed next goes on a new one
+    fn feed(&mut self) {
+        self.paper.clear();
+        self.page = None;
+    }
+
+    fn write_page(&mut self) -> io::Result<PathBuf> {
+        std::fs::create_dir_all(&self.directory)?;
+
+        let path = match &self.page {
+            Some(path) => path.clone(),
+            None => {
+                //never overwrite pages from an earlier session
+                let mut path = self.directory.join(format!("print_{:04}.png", self.next_page));
+                while path.exists() {
+                    self.next_page += 1;
+                    path = self.directory.join(format!("print_{:04}.png", self.next_page));
+                }
+                self.next_page += 1;
+                self.page = Some(path.clone());
+                path
+            }
+        };
+
+        let height = self.paper.len() / PRINTER_WIDTH;
+        write_png(&path, PRINTER_WIDTH as u32, height as u32, &self.paper)?;
+
+        Ok(path)
+    }
+}
+
+impl SerialDevice for Printer {
+    fn transfer(&mut self, byte: u8) -> u8 {
+        let reply = match self.state {
+            Receive::Alive => 0x81,
+            Receive::Status => self.status(),
+            _ => 0x00
+        };
+
+        self.idle = 0;
+        self.receive(byte);
+
+        reply
+    }
+
+    fn step(&mut self, cycles: u8) {
+        self.busy = self.busy.saturating_sub(cycles as u32);
+
+        if self.state != Receive::Magic1 {
+            self.idle += cycles as u32;
+
+            if self.idle >= PACKET_TIMEOUT {
+                self.state = Receive::Magic1;
+            }
+        }
+    }
+}
+
+//data packets may be run length encoded: a control byte with bit 7 set repeats the next byte (control & 0x7F) + 2
+//times, otherwise control + 1 bytes follow as they are
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = vec![];
+    let mut i = 0;
+
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+
+        if control & 0x80 != 0 {
+            if let Some(&byte) = data.get(i) {
+                output.resize(output.len() + (control & 0x7F) as usize + 2, byte);
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            output.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    //send a whole packet, checksum included
+    fn send(printer: &mut Printer, command: u8, data: &[u8]) {
+        let mut packet = vec![command, 0, data.len() as u8, (data.len() >> 8) as u8]; //>
+        packet.extend_from_slice(data);
+        let checksum = packet.iter().fold(0u16, |sum, byte| sum.wrapping_add(*byte as u16));
+
+        for byte in [0x88, 0x33].iter().chain(&packet).chain(&checksum.to_le_bytes()).chain(&[0, 0]) {
+            printer.transfer(*byte);
+        }
+    }
+
+    //height of a PNG from its header
+    fn png_height(path: &Path) -> u32 {
+        let png = std::fs::read(path).unwrap();
+        u32::from_be_bytes([png[20], png[21], png[22], png[23]])
+    }
+
+    #[test]
+    fn decompress_expands_runs_and_literals() {
+        //3 literal bytes, 0xAA repeated 5 times, 1 literal byte
+        assert_eq!(decompress(&[0x02, 1, 2, 3, 0x83, 0xAA, 0x00, 4]), [1, 2, 3, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 4]);
+        //a run of 0x7F + 2
+        assert_eq!(decompress(&[0xFF, 0x11]), [0x11; 129]);
+        //packets cut short keep what they have
+        assert_eq!(decompress(&[0x05, 1, 2]), [1, 2]);
+        assert_eq!(decompress(&[0x85]), []);
+    }
+
+    #[test]
+    fn every_print_writes_the_page() {
+        let directory = std::env::temp_dir().join(format!("rusty_gb_printer_test_{}", std::process::id()));
+        let printed = Rc::new(RefCell::new(vec![]));
+        let log = printed.clone();
+        let mut printer = Printer::new(&directory).on_print(move |result| log.borrow_mut().push(result.unwrap()));
+
+        //two strips without margins go on the same page, the margin after the second feeds it out
+        send(&mut printer, INIT, &[]);
+        send(&mut printer, DATA, &[0xFF; TILE_ROW_BYTES]);
+        send(&mut printer, PRINT, &[1, 0x00, 0xE4, 0x40]);
+        assert_eq!(png_height(&printed.borrow()[0]), 8);
+
+        send(&mut printer, DATA, &[0xFF; TILE_ROW_BYTES * 2]);
+        send(&mut printer, PRINT, &[1, 0x03, 0xE4, 0x40]);
+        assert_eq!(printed.borrow()[1], printed.borrow()[0]);
+        assert_eq!(png_height(&printed.borrow()[1]), 24);
+
+        send(&mut printer, DATA, &[0xFF; TILE_ROW_BYTES]);
+        send(&mut printer, PRINT, &[1, 0x00, 0xE4, 0x40]);
+        assert_ne!(printed.borrow()[2], printed.borrow()[1]);
+        assert_eq!(png_height(&printed.borrow()[2]), 8);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,258 @@
+use crate::Gameboy;
+use std::collections::VecDeque;
+
+//the LCD refreshes at ~59.7Hz, close enough for sizing the buffer
+const FRAMES_PER_SECOND: u32 = 60;
+
+//an older snapshot, stored as the difference to the one after it
+struct Delta {
+    frame: u64,
+    data: Vec<u8>,
+}
+
+//Ring buffer of save states taken every few frames so the game can be run backwards.
+//Only the newest state is kept whole, every older one is the run-length encoded XOR against its successor,
+//so dropping the oldest never breaks the chain and consecutive states that barely differ take almost no memory
+pub struct Rewind {
+    interval: u32,
+    max_snapshots: usize,
+    memory_cap: usize,
+
+    frame: u64,
+    newest: Option<(u64, Vec<u8>)>,
+    older: VecDeque<Delta>,
+    memory: usize,
+}
+
+impl Rewind {
+    //keep up to `seconds` of history, capturing a state every `interval` frames and using at most `memory_cap` bytes
+    pub fn new(seconds: u32, interval: u32, memory_cap: usize) -> Self {
+        let interval = interval.max(1);
+        let frames = seconds as usize * FRAMES_PER_SECOND as usize;
+
+        Rewind {
+            interval,
+            max_snapshots: (frames / interval as usize).max(1),
+            memory_cap,
+            frame: 0,
+            newest: None,
+            older: VecDeque::new(),
+            memory: 0,
+        }
+    }
+
+    //call once after every emulated frame
+    pub fn record(&mut self, gameboy: &Gameboy) {
+        self.frame += 1;
+
+        if !self.frame.is_multiple_of(self.interval as u64) { return; }
+
+        let state = gameboy.save_state();
+
+        if let Some((frame, previous)) = self.newest.take() {
+            let data = encode(&previous, &state);
+            self.memory += data.len();
+            self.older.push_back(Delta { frame, data });
+            self.memory -= previous.len();
+        }
+
+        self.memory += state.len();
+        self.newest = Some((self.frame, state));
+
+        while self.older.len() + 1 > self.max_snapshots || (self.memory > self.memory_cap && !self.older.is_empty()) {
+            if let Some(dropped) = self.older.pop_front() {
+                self.memory -= dropped.data.len();
+            }
+        }
+    }
+
+    //go back one frame, false if there is no history left.
+    //Frames between snapshots are emulated again, so audio and rumble will be produced for them
+    pub fn step_back(&mut self, gameboy: &mut Gameboy) -> bool {
+        if self.frame == 0 { return false; }
+
+        let target = self.frame - 1;
+
+        //throw away every snapshot newer than the target
+        while let Some((frame, _)) = &self.newest {
+            if *frame <= target { break; }
+
+            if !self.pop() {
+                return false;
+            }
+        }
+
+        let (frame, state) = match &self.newest {
+            Some(newest) => newest,
+            None => return false
+        };
+
+        if gameboy.load_state(state).is_err() {
+            self.clear();
+            return false;
+        }
+
+        for _ in *frame..target {
+            gameboy.run_frame();
+        }
+
+        self.frame = target;
+
+        true
+    }
+
+    //rebuild the state before the newest one, false if it was the last
+    fn pop(&mut self) -> bool {
+        let delta = match self.older.pop_back() {
+            Some(delta) => delta,
+            None => return false
+        };
+
+        if let Some((_, newest)) = self.newest.take() {
+            let state = decode(&newest, &delta.data);
+
+            self.memory -= newest.len() + delta.data.len();
+            self.memory += state.len();
+            self.newest = Some((delta.frame, state));
+        }
+
+        true
+    }
+
+    pub fn clear(&mut self) {
+        self.newest = None;
+        self.older.clear();
+        self.memory = 0;
+    }
+
+    //seconds of history currently stored
+    pub fn seconds(&self) -> f32 {
+        match &self.newest {
+            Some(_) => (self.older.len() as u32 * self.interval) as f32 / FRAMES_PER_SECOND as f32,
+            None => 0.0
+        }
+    }
+
+    //bytes used by the stored snapshots
+    pub fn memory_used(&self) -> usize {
+        self.memory
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_length(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    while *position < data.len() {
+        let byte = data[*position];
+        *position += 1;
+
+        value |= ((byte & 0x7F) as usize) << shift; //>
+        shift += 7;
+
+        if byte & 0x80 == 0 { break; }
+    }
+
+    value
+}
+
+//XOR of `older` against `base`, as pairs of (zero run, literal bytes)
+fn encode(older: &[u8], base: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| older[i] ^ base.get(i).copied().unwrap_or(0);
+
+    let mut out = vec![];
+    write_length(&mut out, older.len());
+
+    let mut i = 0;
+    while i < older.len() {
+        let start = i;
+        while i < older.len() && xor(i) == 0 { i += 1; }
+        write_length(&mut out, i - start);
+
+        //a literal only ends at two zeros in a row, a single one is cheaper to keep
+        let start = i;
+        while i < older.len() && !(xor(i) == 0 && (i + 1 == older.len() || xor(i + 1) == 0)) { i += 1; }
+        write_length(&mut out, i - start);
+
+        for j in start..i {
+            out.push(xor(j));
+        }
+    }
+
+    out
+}
+
+fn decode(base: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let size = read_length(data, &mut position);
+
+    let mut state: Vec<u8> = (0..size).map(|i| base.get(i).copied().unwrap_or(0)).collect();
+
+    let mut i = 0;
+    while position < data.len() && i < size {
+        i += read_length(data, &mut position);
+
+        let literal = read_length(data, &mut position);
+        for _ in 0..literal {
+            if i >= size || position >= data.len() { break; }
+
+            state[i] ^= data[position];
+            i += 1;
+            position += 1;
+        }
+    }
+
+    state
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deltas_round_trip() {
+        let base: Vec<u8> = (0..300).map(|i| (i * 7) as u8).collect();
+
+        //same length with scattered changes, a longer older state and a shorter one
+        let mut changed = base.clone();
+        changed[0] ^= 1;
+        changed[150] = 0;
+        changed[151] = 0;
+        changed[299] ^= 0xFF;
+        let longer: Vec<u8> = base.iter().copied().chain(0..200).collect();
+        let shorter = base[..100].to_vec();
+
+        for older in [base.clone(), changed, longer, shorter, vec![]] {
+            assert_eq!(decode(&base, &encode(&older, &base)), older);
+        }
+        assert_eq!(decode(&[], &encode(&base, &[])), base);
+    }
+
+    #[test]
+    fn lengths_round_trip() {
+        let mut out = vec![];
+        for value in [0, 0x7F, 0x80, 0x3FFF, 0x4000, 1 << 30] {
+            write_length(&mut out, value);
+        }
+
+        let mut position = 0;
+        for value in [0, 0x7F, 0x80, 0x3FFF, 0x4000, 1 << 30] {
+            assert_eq!(read_length(&out, &mut position), value);
+        }
+        assert_eq!(position, out.len());
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::path::Path;
+
+//Labels from an RGBDS or no$gmb .sym file, lines of "bank:address name" with ; starting a comment.
+//The bank only tells apart addresses in the switchable ROM area (0x4000-0x7FFF), everywhere else the first name wins
+#[derive(Default)]
+pub struct Symbols {
+    names: BTreeMap<u16, Vec<(u16, String)>>,
+    addresses: HashMap<String, (u16, u16)>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Symbols::default()
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(Symbols::parse(&std::fs::read_to_string(path)?))
+    }
+
+    //lines that don't look like a symbol (like no$gmb's [labels] header) are skipped
+    pub fn parse(text: &str) -> Self {
+        let mut symbols = Symbols::new();
+
+        for line in text.lines() {
+            let line = line.split(';').next().unwrap_or("");
+
+            let mut fields = line.split_whitespace();
+            let (location, name) = match (fields.next(), fields.next()) {
+                (Some(location), Some(name)) => (location, name),
+                _ => continue
+            };
+
+            let parsed = location.split_once(':').and_then(|(bank, addr)| {
+                Some((u16::from_str_radix(bank, 16).ok()?, u16::from_str_radix(addr, 16).ok()?))
+            });
+
+            if let Some((bank, addr)) = parsed {
+                symbols.insert(bank, addr, name);
+            }
+        }
+
+        symbols
+    }
+
+    pub fn insert(&mut self, bank: u16, addr: u16, name: &str) {
+        self.names.entry(addr).or_default().push((bank, name.to_string()));
+        self.addresses.entry(name.to_string()).or_insert((bank, addr));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    //bank and address of a label
+    pub fn address(&self, name: &str) -> Option<(u16, u16)> {
+        self.addresses.get(name).copied()
+    }
+
+    //name of addr while `bank` is the ROM bank mapped at 0x4000-0x7FFF
+    pub fn name(&self, bank: u16, addr: u16) -> Option<&str> {
+        self.names.get(&addr).and_then(|names| pick(names, bank, addr))
+    }
+
+    //closest label at or before addr in the same memory area and how far addr is from it, like Main.loop+3
+    pub fn nearest(&self, bank: u16, addr: u16) -> Option<(&str, u16)> {
+        self.names.range(area_start(addr) ..= addr).rev()
+            .find_map(|(symbol_addr, names)| pick(names, bank, *symbol_addr).map(|name| (name, addr - symbol_addr)))
+    }
+}
+
+fn pick(names: &[(u16, String)], bank: u16, addr: u16) -> Option<&str> {
+    let name = match addr {
+        0x4000 ..= 0x7FFF => names.iter().find(|(symbol_bank, _)| *symbol_bank == bank),
+        _ => names.first()
+    };
+
+    name.map(|(_, name)| name.as_str())
+}
+
+//labels don't carry over from one area of the memory map to the next
+fn area_start(addr: u16) -> u16 {
+    match addr {
+        0x0000 ..= 0x3FFF => 0x0000,
+        0x4000 ..= 0x7FFF => 0x4000,
+        0x8000 ..= 0x9FFF => 0x8000,
+        0xA000 ..= 0xBFFF => 0xA000,
+        0xC000 ..= 0xFDFF => 0xC000,
+        0xFE00 ..= 0xFF7F => 0xFE00,
+        _ => 0xFF80
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYM: &str = "\
+; File generated by rgblink
+[labels]
+00:0150 Main
+00:0158 Main.loop
+01:4000 BankOneStart
+02:4000 BankTwoStart
+02:4010 BankTwoFunc
+00:C000 wBuffer ; work RAM
+not a symbol
+";
+
+    #[test]
+    fn parse_skips_headers_and_comments() {
+        let symbols = Symbols::parse(SYM);
+
+        assert_eq!(symbols.address("Main.loop"), Some((0, 0x0158)));
+        assert_eq!(symbols.address("wBuffer"), Some((0, 0xC000)));
+        assert_eq!(symbols.address("[labels]"), None);
+        assert_eq!(symbols.name(0, 0x0150), Some("Main"));
+    }
+
+    #[test]
+    fn switchable_bank_labels_need_their_bank() {
+        let symbols = Symbols::parse(SYM);
+
+        assert_eq!(symbols.name(1, 0x4000), Some("BankOneStart"));
+        assert_eq!(symbols.name(2, 0x4000), Some("BankTwoStart"));
+        assert_eq!(symbols.name(3, 0x4000), None);
+        //bank 0 and RAM don't depend on the mapped bank
+        assert_eq!(symbols.name(5, 0x0158), Some("Main.loop"));
+        assert_eq!(symbols.name(5, 0xC000), Some("wBuffer"));
+    }
+
+    #[test]
+    fn nearest_stays_in_the_bank_and_area() {
+        let symbols = Symbols::parse(SYM);
+
+        assert_eq!(symbols.nearest(1, 0x015A), Some(("Main.loop", 2)));
+        assert_eq!(symbols.nearest(1, 0x4020), Some(("BankOneStart", 0x20)));
+        assert_eq!(symbols.nearest(2, 0x4020), Some(("BankTwoFunc", 0x10)));
+        assert_eq!(symbols.nearest(3, 0x4020), None);
+        //labels in ROM don't reach into VRAM or past the end of bank 0
+        assert_eq!(symbols.nearest(1, 0x8000), None);
+        assert_eq!(symbols.nearest(3, 0x3FFF), Some(("Main.loop", 0x3FFF - 0x0158)));
+        assert_eq!(symbols.nearest(0, 0xC005), Some(("wBuffer", 5)));
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{Gameboy, CpuRegister, FRAME_CYCLES};
+use std::fmt::{Formatter, Display};
+
+//opcode of LD B,B, which Mooneye test ROMs execute when they are done
+const LD_B_B: u8 = 0x40;
+//B, C, D, E, H and L of a passed Mooneye test, a failed one sets them all to 0x42
+const FIBONACCI: [u16; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_REGISTERS: [CpuRegister; 6] = [CpuRegister::B, CpuRegister::C, CpuRegister::D, CpuRegister::E, CpuRegister::H, CpuRegister::L];
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TestResult {
+    Passed,
+    Failed,
+    TimedOut,
+}
+
+//how the result was found
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Detection {
+    Serial,                                 //"Passed" or "Failed" sent through the serial port, like blargg's tests
+    Mooneye,                                //LD B,B with the Fibonacci numbers (or 0x42s) in the registers
+    ScreenHash,                             //the screen matched the expected hash, or didn't by the end
+}
+
+pub struct TestReport {
+    pub result: TestResult,
+    pub detection: Option<Detection>,
+    pub cycles: u64,
+    pub serial: String,                     //everything the ROM sent through the serial port
+    pub screen_hash: u32,                   //of the last frame, to record it for a screen hash test
+}
+
+impl Display for TestReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let result = match self.result {
+            TestResult::Passed => "PASS",
+            TestResult::Failed => "FAIL",
+            TestResult::TimedOut => "TIMEOUT",
+        };
+        write!(f, "{} after {} cycles", result, self.cycles)?;
+
+        match self.detection {
+            Some(Detection::Serial) => write!(f, " (serial)")?,
+            Some(Detection::Mooneye) => write!(f, " (LD B,B)")?,
+            Some(Detection::ScreenHash) => write!(f, " (screen hash)")?,
+            None => {}
+        }
+
+        write!(f, ", screen {:08x}", self.screen_hash)
+    }
+}
+
+//Runs a test ROM without a window until it reports a result or max_cycles pass
+pub struct TestRunner {
+    max_cycles: u64,
+    screen_hash: Option<u32>,
+}
+
+impl TestRunner {
+    pub fn new(max_cycles: u64) -> Self {
+        TestRunner { max_cycles, screen_hash: None }
+    }
+
+    //pass as soon as a frame has this hash (see Gameboy::screen_hash), fail if it never does
+    pub fn screen_hash(mut self, hash: Option<u32>) -> Self {
+        self.screen_hash = hash;
+        self
+    }
+
+    pub fn run(&self, gameboy: &mut Gameboy) -> TestReport {
+        gameboy.capture_serial(true);
+        let mut serial = String::from_utf8_lossy(&gameboy.take_serial_output()).into_owned();
+        let mut cycles: u64 = 0;
+        let mut frame_cycles: u32 = 0;
+        //a serial result is reported once its line is complete, or after a frame if it never is
+        let mut serial_result: Option<(TestResult, u64)> = None;
+
+        let report = |result, detection, cycles, serial: String, gameboy: &Gameboy| TestReport {
+            result, detection, cycles, serial, screen_hash: gameboy.screen_hash()
+        };
+
+        while cycles < self.max_cycles {
+            if gameboy.peek(gameboy.pc()) == LD_B_B {
+                let registers: Vec<u16> = MOONEYE_REGISTERS.iter().map(|&register| gameboy.register(register)).collect();
+
+                if registers == FIBONACCI {
+                    return report(TestResult::Passed, Some(Detection::Mooneye), cycles, serial, gameboy);
+                }
+                if registers.iter().all(|&register| register == 0x42) {
+                    return report(TestResult::Failed, Some(Detection::Mooneye), cycles, serial, gameboy);
+                }
+            }
+
+            let step = gameboy.step_instruction() as u32;
+            cycles += step as u64;
+            frame_cycles += step;
+
+            let output = gameboy.take_serial_output();
+            if !output.is_empty() {
+                serial.push_str(&String::from_utf8_lossy(&output));
+
+                if serial_result.is_none() {
+                    if serial.contains("Passed") {
+                        serial_result = Some((TestResult::Passed, cycles + FRAME_CYCLES as u64));
+                    } else if serial.contains("Failed") {
+                        serial_result = Some((TestResult::Failed, cycles + FRAME_CYCLES as u64));
+                    }
+                }
+            }
+
+            if let Some((result, deadline)) = serial_result {
+                if serial.ends_with('\n') || cycles >= deadline {
+                    return report(result, Some(Detection::Serial), cycles, serial, gameboy);
+                }
+            }
+
+            if frame_cycles >= FRAME_CYCLES {
+                frame_cycles -= FRAME_CYCLES;
+
+                if self.screen_hash == Some(gameboy.screen_hash()) {
+                    return report(TestResult::Passed, Some(Detection::ScreenHash), cycles, serial, gameboy);
+                }
+            }
+        }
+
+        if let Some((result, _)) = serial_result {
+            return report(result, Some(Detection::Serial), cycles, serial, gameboy);
+        }
+
+        match self.screen_hash {
+            Some(_) => report(TestResult::Failed, Some(Detection::ScreenHash), cycles, serial, gameboy),
+            None => report(TestResult::TimedOut, None, cycles, serial, gameboy)
+        }
+    }
+}